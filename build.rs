@@ -13,7 +13,6 @@ fn build_theta() {
         dst.join("lib").display()
     );
     println!("cargo:rustc-link-lib=static=uvc");
-    println!("cargo:rustc-link-lib=usb-1.0");
 
     let bindings = bindgen::Builder::default()
         .header(
@@ -41,25 +40,69 @@ fn build_theta() {
         .expect("Couldn't write bindings!");
 }
 
-#[cfg(feature = "k4a")]
-fn build_k4a() {
-    println!("cargo:rustc-link-lib=dylib=k4a");
+/// libuvc and libk4a do not expose hotplug, so the device providers use libusb directly.
+#[cfg(any(feature = "k4a", feature = "theta"))]
+fn build_libusb() {
+    println!("cargo:rustc-link-lib=usb-1.0");
 
-    let bindings = bindgen::Builder::default()
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+    bindgen::Builder::default()
+        .header_contents("usb_wrapper.h", "#include <libusb-1.0/libusb.h>")
+        .allowlist_function("libusb_(init|exit|has_capability|handle_events_timeout_completed)")
+        .allowlist_function("libusb_hotplug_(register|deregister)_callback")
+        .allowlist_type("libusb_(capability|hotplug_event|hotplug_flag)")
+        .allowlist_var("LIBUSB_HOTPLUG_MATCH_ANY")
+        .default_enum_style(bindgen::EnumVariation::Consts)
+        .prepend_enum_name(false)
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks))
+        .generate()
+        .expect("Unable to generate bindings")
+        .write_to_file(out_path.join("usb.rs"))
+        .expect("Couldn't write bindings!");
+}
+
+#[cfg(feature = "k4a")]
+fn k4a_builder() -> bindgen::Builder {
+    bindgen::Builder::default()
         .header("k4a_wrapper.h")
         .default_enum_style(bindgen::EnumVariation::Rust {
             non_exhaustive: true,
         })
         .derive_default(true)
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
-        .generate()
-        .expect("Unable to generate bindings");
+}
 
-    // Write the bindings to the $OUT_DIR/bindings.rs file.
+#[cfg(feature = "k4a")]
+fn build_k4a() {
+    // libk4a and libk4arecord are opened at runtime, so only the types are bound statically and
+    // each library gets a symbol table to load
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
-    bindings
+    k4a_builder()
+        .ignore_functions()
+        .generate()
+        .expect("Unable to generate bindings")
         .write_to_file(out_path.join("k4a.rs"))
         .expect("Couldn't write bindings!");
+
+    for (name, headers, file) in [
+        ("K4a", ".*/k4a/k4a\\.h", "k4a_functions.rs"),
+        (
+            "K4aRecord",
+            ".*/k4arecord/(playback|record)\\.h",
+            "k4arecord_functions.rs",
+        ),
+    ] {
+        k4a_builder()
+            .allowlist_file(headers)
+            .allowlist_recursively(false)
+            .blocklist_type(".*")
+            .dynamic_library_name(name)
+            .dynamic_link_require_all(true)
+            .generate()
+            .expect("Unable to generate bindings")
+            .write_to_file(out_path.join(file))
+            .expect("Couldn't write bindings!");
+    }
 }
 
 fn main() {
@@ -67,4 +110,6 @@ fn main() {
     build_theta();
     #[cfg(feature = "k4a")]
     build_k4a();
+    #[cfg(any(feature = "k4a", feature = "theta"))]
+    build_libusb();
 }
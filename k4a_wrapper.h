@@ -1 +1,3 @@
-#include <k4a/k4a.h>
\ No newline at end of file
+#include <k4a/k4a.h>
+#include <k4arecord/playback.h>
+#include <k4arecord/record.h>
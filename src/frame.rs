@@ -1,32 +1,25 @@
 use std::{
-    sync::{atomic::AtomicUsize, Condvar, Mutex},
-    time::Duration,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use crossbeam::{atomic::AtomicCell, queue::ArrayQueue};
+use crossbeam::atomic::AtomicCell;
+use gstreamer::glib;
 
-pub struct FrameData<T> {
-    buffer: ArrayQueue<T>,
-    start_timestamp: AtomicCell<Option<Duration>>,
+/// Exponential moving average of the latency measured on recent frames.
+#[derive(Default)]
+pub struct LatencyTracker {
     latency: AtomicCell<Option<f64>>,
-    park: (Mutex<()>, Condvar),
-    frame_counter: AtomicUsize,
 }
 
-impl<T> FrameData<T> {
-    const BUFFER_SZ: usize = 1;
+impl LatencyTracker {
     const EXP_MOVING_AVG_COEF: f64 = 0.8f64;
 
-    #[allow(unused)]
-    pub fn start_timestamp(&self, timestamp: Duration) -> Duration {
-        match self.start_timestamp.compare_exchange(None, Some(timestamp)) {
-            Ok(None) => timestamp,
-            Err(Some(prev)) => prev,
-            _ => unreachable!(),
-        }
-    }
-
-    pub fn update_latency(&self, latency: Duration) {
+    pub fn update(&self, latency: Duration) {
         if let Some(past_sample) = self.latency.load() {
             self.latency.store(Some(
                 past_sample * (1.0f64 - Self::EXP_MOVING_AVG_COEF)
@@ -37,45 +30,253 @@ impl<T> FrameData<T> {
         }
     }
 
-    pub fn get_latency(&self) -> Option<Duration> {
+    pub fn latency(&self) -> Option<Duration> {
         self.latency
             .load()
             .map(|timestamp| Duration::from_nanos(timestamp as u64))
     }
 
-    pub fn add_frame(&self, frame: T) -> usize {
-        self.buffer.force_push(frame);
-        let (_lock, cv) = &self.park;
-        cv.notify_one();
-        self.frame_counter
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    pub fn reset(&self) {
+        self.latency.store(None);
+    }
+}
+
+/// What to do when a frame arrives and the queue is already full.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstCArLeaky")]
+pub enum Leaky {
+    /// Block the producer until there is space.
+    No,
+    /// Drop the incoming frame.
+    Upstream,
+    /// Drop the oldest queued frame.
+    Downstream,
+}
+
+pub enum PopResult<T> {
+    Frame(T),
+    Timeout,
+    Flushing,
+}
+
+struct QueueState<T> {
+    frames: VecDeque<T>,
+    capacity: usize,
+    leaky: Leaky,
+    flushing: bool,
+}
+
+/// Bounded handoff between a capture thread and the streaming thread that can be woken up for
+/// flushing.
+pub struct FrameQueue<T> {
+    state: Mutex<QueueState<T>>,
+    cv: Condvar,
+}
+
+impl<T> FrameQueue<T> {
+    pub fn new(capacity: usize, leaky: Leaky) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                frames: VecDeque::with_capacity(capacity),
+                capacity: capacity.max(1),
+                leaky,
+                flushing: false,
+            }),
+            cv: Condvar::new(),
+        }
+    }
+
+    pub fn configure(&self, capacity: usize, leaky: Leaky) {
+        let mut state = self.state.lock().unwrap();
+        state.capacity = capacity.max(1);
+        state.leaky = leaky;
+        self.cv.notify_all();
+    }
+
+    /// Queues a frame, returning the frame that had to be dropped to make room, if any. A
+    /// producer waiting for space gives up and gets its frame back once it is cancelled through
+    /// [`FrameQueue::cancel_push`].
+    pub fn push(&self, frame: T, cancelled: &AtomicBool) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = None;
+        while !state.flushing && state.frames.len() >= state.capacity {
+            if cancelled.load(Ordering::Acquire) {
+                return Some(frame);
+            }
+            match state.leaky {
+                Leaky::No => state = self.cv.wait(state).unwrap(),
+                Leaky::Upstream => return Some(frame),
+                Leaky::Downstream => {
+                    dropped = state.frames.pop_front();
+                }
+            }
+        }
+        if state.flushing {
+            return Some(frame);
+        }
+        state.frames.push_back(frame);
+        self.cv.notify_all();
+        dropped
+    }
+
+    /// Waits for the next frame, giving up after `timeout` or once the queue starts flushing.
+    pub fn pop(&self, timeout: Option<Duration>) -> PopResult<T> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.flushing {
+                return PopResult::Flushing;
+            }
+            if let Some(frame) = state.frames.pop_front() {
+                self.cv.notify_all();
+                return PopResult::Frame(frame);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return PopResult::Timeout;
+                    }
+                    self.cv.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.cv.wait(state).unwrap(),
+            };
+        }
     }
 
-    pub fn pop_frame(&self) -> Option<T> {
-        self.buffer.pop()
+    /// Sets `cancelled` and wakes up the producer pushing with it. Unlike flushing this leaves
+    /// the consumer side alone.
+    pub fn cancel_push(&self, cancelled: &AtomicBool) {
+        let _state = self.state.lock().unwrap();
+        cancelled.store(true, Ordering::Release);
+        self.cv.notify_all();
+    }
+
+    /// While flushing, pending and future pops return immediately and pushes are discarded.
+    pub fn set_flushing(&self, flushing: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.flushing = flushing;
+        if flushing {
+            state.frames.clear();
+        }
+        self.cv.notify_all();
     }
 
-    pub fn wait(&self) {
-        let (lock, cv) = &self.park;
-        std::mem::drop(cv.wait(lock.lock().unwrap()).unwrap());
+    pub fn clear(&self) {
+        self.state.lock().unwrap().frames.clear();
+        self.cv.notify_all();
+    }
+
+    pub fn is_flushing(&self) -> bool {
+        self.state.lock().unwrap().flushing
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().frames.is_empty()
+    }
+
+    /// Drops frames off the front of the queue for as long as `predicate` holds, returning how
+    /// many were dropped.
+    pub fn drop_while(&self, mut predicate: impl FnMut(&T) -> bool) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = 0;
+        while state.frames.front().map_or(false, &mut predicate) {
+            state.frames.pop_front();
+            dropped += 1;
+        }
+        if dropped > 0 {
+            self.cv.notify_all();
+        }
+        dropped
     }
 }
 
-impl<T> Default for FrameData<T> {
-    fn default() -> Self {
-        Self {
-            buffer: ArrayQueue::new(Self::BUFFER_SZ),
-            start_timestamp: AtomicCell::new(None),
-            park: (Mutex::new(()), Condvar::new()),
-            latency: AtomicCell::new(None),
-            frame_counter: AtomicUsize::new(0),
+/// The host's monotonic clock, which capture libraries stamp frames with.
+pub fn monotonic_time() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time as *mut _);
+    }
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+/// A line fitted by least squares through recent pairs of readings of two clocks, mapping times
+/// on the first clock onto the second.
+#[derive(Default)]
+pub struct ClockFit {
+    observations: VecDeque<(u64, u64)>,
+}
+
+impl ClockFit {
+    const WINDOW: usize = 32;
+    const MIN_OBSERVATIONS: usize = 4;
+
+    pub fn reset(&mut self) {
+        self.observations.clear();
+    }
+
+    /// Records that the clocks read `from` and `to` at the same moment.
+    pub fn add(&mut self, from: u64, to: u64) {
+        if self.observations.len() == Self::WINDOW {
+            self.observations.pop_front();
         }
+        self.observations.push_back((from, to));
+    }
+
+    /// The time on the second clock at `from`, once an observation was added. Until there are
+    /// enough observations for a fit, the clocks are taken to run at the same rate.
+    pub fn map(&mut self, from: u64) -> Option<u64> {
+        let &(last_from, last_to) = self.observations.back()?;
+        let (num, denom, intercept, base) = if self.observations.len() < Self::MIN_OBSERVATIONS {
+            (1, 1, last_to, last_from)
+        } else {
+            match gstreamer::calculate_linear_regression(self.observations.make_contiguous(), None)
+            {
+                Some((num, denom, intercept, base, _)) => (num, denom.max(1), intercept, base),
+                None => (1, 1, last_to, last_from),
+            }
+        };
+        let (num, denom) = (num as u128, denom as u128);
+        Some(if from >= base {
+            intercept.saturating_add(((from - base) as u128 * num / denom) as u64)
+        } else {
+            intercept.saturating_sub(((base - from) as u128 * num / denom) as u64)
+        })
     }
 }
 
-impl<T> Drop for FrameData<T> {
-    fn drop(&mut self) {
-        let (_lock, cv) = &self.park;
-        cv.notify_all();
+/// Maps times on the host's monotonic clock onto the pipeline clock. Both clocks are sampled
+/// together, so the offset between them is exact and the line fitted through recent samples only
+/// follows their drift, of which the default monotonic system clock has none.
+#[derive(Default)]
+pub struct ClockMapper {
+    fit: ClockFit,
+}
+
+impl ClockMapper {
+    pub fn reset(&mut self) {
+        self.fit.reset();
+    }
+
+    /// Records the current time of `clock` against the monotonic clock.
+    pub fn sample(&mut self, clock: &gstreamer::Clock) {
+        let before = monotonic_time();
+        let Some(time) = clock.time() else {
+            return;
+        };
+        let after = monotonic_time();
+        let monotonic = before + (after - before) / 2;
+        self.fit.add(monotonic.as_nanos() as u64, time.nseconds());
+    }
+
+    /// The pipeline clock time of `monotonic`, once the clocks were sampled.
+    pub fn map(&mut self, monotonic: Duration) -> Option<gstreamer::ClockTime> {
+        self.fit
+            .map(monotonic.as_nanos() as u64)
+            .map(gstreamer::ClockTime::from_nseconds)
     }
 }
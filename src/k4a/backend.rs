@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use super::libk4a::{
+    self, sys, Calibration, Capture, ColorControlCommand, ColorControlMode, DeviceConfiguration,
+};
+
+/// A source of Azure Kinect devices: libk4a, or a stand-in for it.
+pub trait Backend: Send + Sync {
+    /// Opens the default device, or the first free one with the given serial number.
+    fn open(&self, serial_number: Option<&str>) -> Result<Box<dyn Device>, sys::k4a_result_t>;
+}
+
+pub trait Device: Send + Sync {
+    fn serial_number(&self) -> Result<String, sys::k4a_buffer_result_t>;
+
+    /// Starts the cameras. On failure the device is handed back so it can be retried or closed.
+    fn start_cameras(
+        self: Box<Self>,
+        config: DeviceConfiguration,
+    ) -> Result<Box<dyn Stream>, (Box<dyn Device>, sys::k4a_result_t)>;
+
+    fn get_calibration(
+        &self,
+        depth_mode: sys::k4a_depth_mode_t,
+        color_resolution: sys::k4a_color_resolution_t,
+    ) -> Result<Calibration, sys::k4a_result_t>;
+
+    /// The factory calibration as the JSON blob stored on the device.
+    fn get_raw_calibration(&self) -> Result<Vec<u8>, sys::k4a_buffer_result_t>;
+
+    fn set_color_control(
+        &self,
+        command: ColorControlCommand,
+        mode: ColorControlMode,
+        value: i32,
+    ) -> Result<(), sys::k4a_result_t>;
+
+    fn get_color_control(
+        &self,
+        command: ColorControlCommand,
+    ) -> Result<(ColorControlMode, i32), sys::k4a_result_t>;
+}
+
+/// A device with its cameras running. The cameras stop when it is dropped.
+pub trait Stream: Send + Sync {
+    fn device(&self) -> &dyn Device;
+
+    /// A handle that can wait for captures from another thread while the stream is running.
+    fn capturer(&self) -> Box<dyn Capturer>;
+
+    fn stop_cameras(self: Box<Self>) -> Box<dyn Device>;
+}
+
+pub trait Capturer: Send {
+    fn get_capture(&self, timeout: Duration) -> Result<Capture, sys::k4a_wait_result_t>;
+}
+
+/// The devices attached to this machine, through libk4a.
+pub struct Sdk;
+
+impl Backend for Sdk {
+    fn open(&self, serial_number: Option<&str>) -> Result<Box<dyn Device>, sys::k4a_result_t> {
+        let device = match serial_number {
+            Some(serial_number) => libk4a::Device::open_serial(serial_number)?,
+            None => libk4a::Device::new()?,
+        };
+        Ok(Box::new(device))
+    }
+}
+
+impl Device for libk4a::Device {
+    fn serial_number(&self) -> Result<String, sys::k4a_buffer_result_t> {
+        libk4a::Device::serial_number(self)
+    }
+
+    fn start_cameras(
+        self: Box<Self>,
+        config: DeviceConfiguration,
+    ) -> Result<Box<dyn Stream>, (Box<dyn Device>, sys::k4a_result_t)> {
+        match libk4a::Device::start_cameras(*self, config) {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err((device, err)) => Err((Box::new(device), err)),
+        }
+    }
+
+    fn get_calibration(
+        &self,
+        depth_mode: sys::k4a_depth_mode_t,
+        color_resolution: sys::k4a_color_resolution_t,
+    ) -> Result<Calibration, sys::k4a_result_t> {
+        libk4a::Device::get_calibration(self, depth_mode, color_resolution)
+    }
+
+    fn get_raw_calibration(&self) -> Result<Vec<u8>, sys::k4a_buffer_result_t> {
+        libk4a::Device::get_raw_calibration(self)
+    }
+
+    fn set_color_control(
+        &self,
+        command: ColorControlCommand,
+        mode: ColorControlMode,
+        value: i32,
+    ) -> Result<(), sys::k4a_result_t> {
+        libk4a::Device::set_color_control(self, command, mode, value)
+    }
+
+    fn get_color_control(
+        &self,
+        command: ColorControlCommand,
+    ) -> Result<(ColorControlMode, i32), sys::k4a_result_t> {
+        libk4a::Device::get_color_control(self, command)
+    }
+}
+
+impl Stream for libk4a::Stream {
+    fn device(&self) -> &dyn Device {
+        libk4a::Stream::device(self)
+    }
+
+    fn capturer(&self) -> Box<dyn Capturer> {
+        Box::new(libk4a::Stream::capturer(self))
+    }
+
+    fn stop_cameras(self: Box<Self>) -> Box<dyn Device> {
+        Box::new(libk4a::Stream::stop_cameras(*self))
+    }
+}
+
+impl Capturer for libk4a::Capturer {
+    fn get_capture(&self, timeout: Duration) -> Result<Capture, sys::k4a_wait_result_t> {
+        libk4a::Capturer::get_capture(self, timeout)
+    }
+}
@@ -0,0 +1,483 @@
+use gstreamer::glib;
+use gstreamer_video::VideoFormat;
+
+use super::libk4a;
+
+/// One legal combination of device settings, which maps onto exactly one caps structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamConfig {
+    pub mode: Mode,
+    pub format: ColorFormat,
+    pub fps_mode: FpsMode,
+    pub color_resolution: Option<ColorResolution>,
+    /// `None` in IR mode means passive IR.
+    pub depth_mode: Option<DepthMode>,
+}
+
+impl StreamConfig {
+    pub const IR_PASSIVE_RESOLUTION: (i32, i32) = (1024, 1024);
+    const FPS_MODES: [FpsMode; 3] = [FpsMode::Fps5, FpsMode::Fps15, FpsMode::Fps30];
+    const COLOR_FORMATS: [ColorFormat; 4] = [
+        ColorFormat::Bgra32,
+        ColorFormat::Yuy2,
+        ColorFormat::Nv12,
+        ColorFormat::Mjpg,
+    ];
+    const COLOR_RESOLUTIONS: [ColorResolution; 6] = [
+        ColorResolution::Res720P,
+        ColorResolution::Res1080P,
+        ColorResolution::Res1440P,
+        ColorResolution::Res1536P,
+        ColorResolution::Res2160P,
+        ColorResolution::Res3072P,
+    ];
+    const DEPTH_MODES: [DepthMode; 4] = [
+        DepthMode::NormalFov2x2Binned,
+        DepthMode::NormalFovUnbinned,
+        DepthMode::WideFov2x2Binned,
+        DepthMode::WideFovUnbinned,
+    ];
+
+    /// Enumerates every configuration the device accepts for the given stream.
+    pub fn all(mode: Mode) -> Vec<Self> {
+        let mut configs = Vec::new();
+        match mode {
+            Mode::Color => {
+                for format in Self::COLOR_FORMATS {
+                    for color_resolution in Self::COLOR_RESOLUTIONS {
+                        for fps_mode in Self::FPS_MODES {
+                            configs.push(Self {
+                                mode,
+                                format,
+                                fps_mode,
+                                color_resolution: Some(color_resolution),
+                                depth_mode: None,
+                            });
+                        }
+                    }
+                }
+            }
+            Mode::Ir => {
+                // passive IR first, then IR captured alongside each active depth mode
+                let depth_modes = std::iter::once(None).chain(Self::DEPTH_MODES.map(Some));
+                for depth_mode in depth_modes {
+                    for fps_mode in Self::FPS_MODES {
+                        configs.push(Self {
+                            mode,
+                            format: ColorFormat::Ir16,
+                            fps_mode,
+                            color_resolution: None,
+                            depth_mode,
+                        });
+                    }
+                }
+            }
+            Mode::Depth => {
+                for depth_mode in Self::DEPTH_MODES {
+                    for fps_mode in Self::FPS_MODES {
+                        configs.push(Self {
+                            mode,
+                            format: ColorFormat::Depth16,
+                            fps_mode,
+                            color_resolution: None,
+                            depth_mode: Some(depth_mode),
+                        });
+                    }
+                }
+            }
+        }
+        configs.retain(Self::is_valid);
+        configs
+    }
+
+    fn is_valid(&self) -> bool {
+        if let Some(color_resolution) = self.color_resolution {
+            // the device only produces NV12 and YUY2 natively at 720p
+            if matches!(self.format, ColorFormat::Nv12 | ColorFormat::Yuy2)
+                && color_resolution != ColorResolution::Res720P
+            {
+                return false;
+            }
+            if color_resolution == ColorResolution::Res3072P && self.fps_mode == FpsMode::Fps30 {
+                return false;
+            }
+        }
+        if self.depth_mode == Some(DepthMode::WideFovUnbinned) && self.fps_mode == FpsMode::Fps30 {
+            return false;
+        }
+        true
+    }
+
+    pub fn dimensions(&self) -> (i32, i32) {
+        match self.mode {
+            Mode::Color => self.color_resolution.unwrap().dimensions(),
+            Mode::Ir => self
+                .depth_mode
+                .map_or(Self::IR_PASSIVE_RESOLUTION, |depth_mode| {
+                    depth_mode.dimensions()
+                }),
+            Mode::Depth => self.depth_mode.unwrap().dimensions(),
+        }
+    }
+
+    pub fn framerate(&self) -> gstreamer::Fraction {
+        gstreamer::Fraction::new(self.fps_mode.fps() * 1000, 1001)
+    }
+
+    pub fn frame_duration(&self) -> gstreamer::ClockTime {
+        gstreamer::ClockTime::from_nseconds(
+            gstreamer::ClockTime::SECOND.nseconds() * 1001 / (self.fps_mode.fps() as u64 * 1000),
+        )
+    }
+
+    pub fn to_structure(self) -> gstreamer::Structure {
+        let (width, height) = self.dimensions();
+        let builder = match self.format.video_format() {
+            Some(format) => gstreamer::Structure::builder("video/x-raw")
+                .field("format", format.to_str().to_owned()),
+            None => gstreamer::Structure::builder("image/jpeg"),
+        };
+        builder
+            .field("width", width)
+            .field("height", height)
+            .field("framerate", self.framerate())
+            .build()
+    }
+
+    /// Finds the configuration described by a fixed caps structure.
+    pub fn from_structure(mode: Mode, structure: &gstreamer::StructureRef) -> Option<Self> {
+        Self::matching(mode, structure).into_iter().next()
+    }
+
+    /// Every configuration described by a fixed caps structure. Caps cannot tell passive IR
+    /// apart from IR in the wide unbinned depth mode since both are 1024x1024.
+    pub fn matching(mode: Mode, structure: &gstreamer::StructureRef) -> Vec<Self> {
+        let format = match structure.name() {
+            "image/jpeg" => Ok(None),
+            _ => structure
+                .get::<String>("format")
+                .map(|format| Some(VideoFormat::from_string(&format))),
+        };
+        let (Ok(format), Ok(width), Ok(height), Ok(framerate)) = (
+            format,
+            structure.get::<i32>("width"),
+            structure.get::<i32>("height"),
+            structure.get::<gstreamer::Fraction>("framerate"),
+        ) else {
+            return Vec::new();
+        };
+        Self::all(mode)
+            .into_iter()
+            .filter(|config| {
+                config.format.video_format() == format
+                    && config.dimensions() == (width, height)
+                    && config.framerate() == framerate
+            })
+            .collect()
+    }
+
+    /// The configuration of one track of a recording, or `None` if that track was not recorded.
+    pub fn from_record_configuration(
+        mode: Mode,
+        record_configuration: &libk4a::RecordConfiguration,
+    ) -> Option<Self> {
+        let fps_mode = FpsMode::from_sys(record_configuration.camera_fps)?;
+        let depth_mode = || match record_configuration.depth_mode {
+            libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_PASSIVE_IR => Some(None),
+            depth_mode => DepthMode::from_sys(depth_mode).map(Some),
+        };
+        match mode {
+            Mode::Color if record_configuration.color_track_enabled => Some(Self {
+                mode,
+                format: ColorFormat::from_sys(record_configuration.color_format)?,
+                fps_mode,
+                color_resolution: Some(ColorResolution::from_sys(
+                    record_configuration.color_resolution,
+                )?),
+                depth_mode: None,
+            }),
+            Mode::Ir if record_configuration.ir_track_enabled => Some(Self {
+                mode,
+                format: ColorFormat::Ir16,
+                fps_mode,
+                color_resolution: None,
+                depth_mode: depth_mode()?,
+            }),
+            Mode::Depth if record_configuration.depth_track_enabled => Some(Self {
+                mode,
+                format: ColorFormat::Depth16,
+                fps_mode,
+                color_resolution: None,
+                depth_mode: Some(depth_mode()??),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn device_configuration(&self) -> libk4a::DeviceConfiguration {
+        let color_format = match self.mode {
+            Mode::Color => self.format.to_sys(),
+            // default value for disabled
+            Mode::Ir | Mode::Depth => libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_MJPG,
+        };
+        let color_resolution = self.color_resolution.map_or(
+            libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_OFF,
+            |resolution| resolution.to_sys(),
+        );
+        let depth_mode = match (self.mode, self.depth_mode) {
+            (Mode::Ir, None) => libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_PASSIVE_IR,
+            (_, Some(depth_mode)) => depth_mode.to_sys(),
+            (_, None) => libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_OFF,
+        };
+        libk4a::DeviceConfiguration {
+            color_format,
+            color_resolution,
+            depth_mode,
+            camera_fps: self.fps_mode.to_sys(),
+            synchronized_images_only: Default::default(),
+            depth_delay_off_color_usec: Default::default(),
+            wired_sync_mode: libk4a::sys::k4a_wired_sync_mode_t::K4A_WIRED_SYNC_MODE_STANDALONE,
+            subordinate_delay_off_master_usec: Default::default(),
+            disable_streaming_indicator: Default::default(),
+        }
+    }
+
+    /// Everything k4a sources can produce, for pad templates.
+    pub fn template_caps() -> gstreamer::Caps {
+        let mut caps = Self::ir_depth_caps();
+        caps.merge(Self::color_caps());
+        caps
+    }
+
+    pub fn color_caps() -> gstreamer::Caps {
+        gstreamer::Caps::builder_full()
+            .structure(
+                gstreamer::Structure::builder("video/x-raw")
+                    .field("format", VideoFormat::Bgra.to_str().to_owned())
+                    .build(),
+            )
+            .structure(
+                gstreamer::Structure::builder("video/x-raw")
+                    .field("format", VideoFormat::Yuy2.to_str().to_owned())
+                    .build(),
+            )
+            .structure(
+                gstreamer::Structure::builder("video/x-raw")
+                    .field("format", VideoFormat::Nv12.to_str().to_owned())
+                    .build(),
+            )
+            .structure(gstreamer::Structure::builder("image/jpeg").build())
+            .build()
+    }
+
+    pub fn ir_depth_caps() -> gstreamer::Caps {
+        gstreamer::Caps::builder("video/x-raw")
+            .field("format", VideoFormat::Gray16Le.to_str().to_owned())
+            .build()
+    }
+
+    /// Wraps an image of this stream in a buffer without copying it, describing its layout with
+    /// a video meta for raw formats.
+    pub fn buffer(&self, image: libk4a::Image) -> Result<gstreamer::Buffer, glib::BoolError> {
+        let (width, height, stride) = (image.width(), image.height(), image.stride());
+
+        // the image (and through it the capture) is released once the last buffer referencing
+        // its memory is dropped
+        let mut buffer = gstreamer::Buffer::from_slice(image);
+
+        if let Some(format) = self.format.video_format() {
+            let (offset, stride) = match format {
+                VideoFormat::Nv12 => (
+                    vec![0, stride as usize * height as usize],
+                    vec![stride as i32, stride as i32],
+                ),
+                _ => (vec![0], vec![stride as i32]),
+            };
+            gstreamer_video::VideoMeta::add_full(
+                buffer.make_mut(),
+                gstreamer_video::VideoFrameFlags::empty(),
+                format,
+                width,
+                height,
+                &offset,
+                &stride,
+            )?;
+        }
+        Ok(buffer)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstK4aFpsMode")]
+pub enum FpsMode {
+    Fps5,
+    Fps15,
+    Fps30,
+}
+
+impl FpsMode {
+    pub fn fps(&self) -> i32 {
+        match self {
+            FpsMode::Fps5 => 5,
+            FpsMode::Fps15 => 15,
+            FpsMode::Fps30 => 30,
+        }
+    }
+
+    pub fn to_sys(self) -> libk4a::sys::k4a_fps_t {
+        match self {
+            FpsMode::Fps5 => libk4a::sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_5,
+            FpsMode::Fps15 => libk4a::sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_15,
+            FpsMode::Fps30 => libk4a::sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_30,
+        }
+    }
+
+    pub fn from_sys(fps: libk4a::sys::k4a_fps_t) -> Option<Self> {
+        StreamConfig::FPS_MODES
+            .into_iter()
+            .find(|candidate| candidate.to_sys() == fps)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstK4aColorResolution")]
+pub enum ColorResolution {
+    Res720P,
+    Res1080P,
+    Res1440P,
+    Res1536P,
+    Res2160P,
+    Res3072P,
+}
+
+impl ColorResolution {
+    pub fn dimensions(&self) -> (i32, i32) {
+        match self {
+            Self::Res720P => (1280, 720),
+            Self::Res1080P => (1920, 1080),
+            Self::Res1440P => (2560, 1440),
+            Self::Res1536P => (2048, 1536),
+            Self::Res2160P => (3840, 2160),
+            Self::Res3072P => (4096, 3072),
+        }
+    }
+
+    pub fn to_sys(self) -> libk4a::sys::k4a_color_resolution_t {
+        match self {
+            Self::Res720P => libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_720P,
+            Self::Res1080P => libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_1080P,
+            Self::Res1440P => libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_1440P,
+            Self::Res1536P => libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_1536P,
+            Self::Res2160P => libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_2160P,
+            Self::Res3072P => libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_3072P,
+        }
+    }
+
+    pub fn from_sys(resolution: libk4a::sys::k4a_color_resolution_t) -> Option<Self> {
+        StreamConfig::COLOR_RESOLUTIONS
+            .into_iter()
+            .find(|candidate| candidate.to_sys() == resolution)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstK4aDepthMode")]
+pub enum DepthMode {
+    NormalFov2x2Binned,
+    NormalFovUnbinned,
+    WideFov2x2Binned,
+    WideFovUnbinned,
+}
+
+impl DepthMode {
+    pub fn dimensions(&self) -> (i32, i32) {
+        match self {
+            Self::NormalFov2x2Binned => (320, 288),
+            Self::NormalFovUnbinned => (640, 576),
+            Self::WideFov2x2Binned => (512, 512),
+            Self::WideFovUnbinned => (1024, 1024),
+        }
+    }
+
+    pub fn to_sys(self) -> libk4a::sys::k4a_depth_mode_t {
+        match self {
+            Self::NormalFov2x2Binned => {
+                libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_NFOV_2X2BINNED
+            }
+            Self::NormalFovUnbinned => libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_NFOV_UNBINNED,
+            Self::WideFov2x2Binned => libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_WFOV_2X2BINNED,
+            Self::WideFovUnbinned => libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_WFOV_UNBINNED,
+        }
+    }
+
+    pub fn from_sys(depth_mode: libk4a::sys::k4a_depth_mode_t) -> Option<Self> {
+        StreamConfig::DEPTH_MODES
+            .into_iter()
+            .find(|candidate| candidate.to_sys() == depth_mode)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstK4aColorFormat")]
+pub enum ColorFormat {
+    Nv12,
+    Yuy2,
+    Bgra32,
+    Depth16,
+    Ir16,
+    Mjpg,
+}
+
+impl ColorFormat {
+    /// The raw video format of the stream, or `None` for JPEG payloads.
+    pub fn video_format(&self) -> Option<VideoFormat> {
+        match self {
+            Self::Nv12 => Some(VideoFormat::Nv12),
+            Self::Yuy2 => Some(VideoFormat::Yuy2),
+            Self::Bgra32 => Some(VideoFormat::Bgra),
+            Self::Depth16 | Self::Ir16 => Some(VideoFormat::Gray16Le),
+            Self::Mjpg => None,
+        }
+    }
+
+    pub fn to_sys(self) -> libk4a::sys::k4a_image_format_t {
+        match self {
+            Self::Nv12 => libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_NV12,
+            Self::Yuy2 => libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_YUY2,
+            Self::Bgra32 => libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_BGRA32,
+            Self::Depth16 => libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_DEPTH16,
+            Self::Ir16 => libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_IR16,
+            Self::Mjpg => libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_MJPG,
+        }
+    }
+
+    /// Only recognizes the formats of the color camera.
+    pub fn from_sys(format: libk4a::sys::k4a_image_format_t) -> Option<Self> {
+        StreamConfig::COLOR_FORMATS
+            .into_iter()
+            .find(|candidate| candidate.to_sys() == format)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstK4aMode")]
+pub enum Mode {
+    Color,
+    Ir,
+    Depth,
+}
+
+impl Mode {
+    pub fn image_type(self) -> libk4a::ImageType {
+        match self {
+            Mode::Color => libk4a::ImageType::Color,
+            Mode::Ir => libk4a::ImageType::Infrared,
+            Mode::Depth => libk4a::ImageType::Depth,
+        }
+    }
+}
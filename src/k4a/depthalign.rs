@@ -0,0 +1,636 @@
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use gstreamer::{glib, prelude::*, subclass::prelude::*};
+use gstreamer_video::VideoFormat;
+use once_cell::sync::Lazy;
+use strum::IntoEnumIterator;
+use strum_macros::{EnumIter, EnumString, IntoStaticStr};
+
+use crate::macros::set_field;
+
+use super::{
+    config::{ColorResolution, DepthMode, Mode, StreamConfig},
+    libk4a::{self, ImageRef, Transformation},
+    meta::{DeviceCalibration, K4aCalibrationMeta, K4aFrameMeta},
+};
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "k4adepthalign",
+        gstreamer::DebugColorFlags::empty(),
+        Some("Azure Kinect Depth Alignment"),
+    )
+});
+
+#[derive(Debug, Clone)]
+struct Settings {
+    direction: AlignDirection,
+    color_resolution: ColorResolution,
+    calibration_file: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            direction: AlignDirection::DepthToColor,
+            color_resolution: ColorResolution::Res720P,
+            calibration_file: "".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, EnumIter, IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
+enum SettingField {
+    Direction,
+    ColorResolution,
+    CalibrationFile,
+}
+
+#[derive(Default)]
+struct State {
+    depth_config: Option<StreamConfig>,
+    color_config: Option<StreamConfig>,
+    /// Recent color frames that depth frames are paired with, oldest first.
+    colors: VecDeque<gstreamer::Buffer>,
+    /// Contents of the calibration file, once read.
+    raw_calibration: Option<Vec<u8>>,
+    transformation: Option<((DepthMode, ColorResolution), Transformation)>,
+    src_caps: Option<gstreamer::Caps>,
+}
+
+impl State {
+    const MAX_COLORS: usize = 4;
+
+    fn color_resolution(&self, settings: &Settings) -> ColorResolution {
+        self.color_config
+            .and_then(|config| config.color_resolution)
+            .unwrap_or(settings.color_resolution)
+    }
+
+    /// The color frame closest in time to a depth frame.
+    fn nearest_color(&self, pts: Option<gstreamer::ClockTime>) -> Option<&gstreamer::Buffer> {
+        let Some(pts) = pts else {
+            return self.colors.back();
+        };
+        self.colors.iter().min_by_key(|color| {
+            color.pts().map_or(u64::MAX, |color_pts| {
+                color_pts.nseconds().abs_diff(pts.nseconds())
+            })
+        })
+    }
+}
+
+/// Reprojects depth into the geometry of the color camera, or color into the geometry of the
+/// depth camera, using the device calibration. Registering color to the depth camera pairs
+/// depth with color from the same device, which only recordings played back by k4aplaybacksrc
+/// provide, so live color is rejected in that direction.
+pub struct K4aDepthAlign {
+    settings: RwLock<Settings>,
+    state: Mutex<State>,
+    depthpad: gstreamer::Pad,
+    colorpad: gstreamer::Pad,
+    srcpad: gstreamer::Pad,
+}
+
+impl K4aDepthAlign {
+    fn src_caps(
+        direction: AlignDirection,
+        depth_config: StreamConfig,
+        color_resolution: ColorResolution,
+    ) -> gstreamer::Caps {
+        let (format, (width, height)) = match direction {
+            AlignDirection::DepthToColor => (VideoFormat::Gray16Le, color_resolution.dimensions()),
+            AlignDirection::ColorToDepth => (VideoFormat::Bgra, depth_config.dimensions()),
+        };
+        gstreamer::Caps::builder("video/x-raw")
+            .field("format", format.to_str().to_owned())
+            .field("width", width)
+            .field("height", height)
+            .field("framerate", depth_config.framerate())
+            .build()
+    }
+
+    /// Sends new caps downstream when the input caps changed what is produced.
+    fn update_src_caps(&self) {
+        let settings = self.settings.read().unwrap().clone();
+        let mut state = self.state.lock().unwrap();
+        let Some(depth_config) = state.depth_config else {
+            return;
+        };
+        let caps = Self::src_caps(
+            settings.direction,
+            depth_config,
+            state.color_resolution(&settings),
+        );
+        if state.src_caps.as_ref() == Some(&caps) {
+            return;
+        }
+        state.src_caps = Some(caps.clone());
+        drop(state);
+
+        gstreamer::debug!(CAT, imp: self, "Producing {}", caps);
+        self.srcpad.push_event(gstreamer::event::Caps::new(&caps));
+    }
+
+    /// Makes sure the transformation matches the current camera modes, preferring the
+    /// calibration file over the calibration attached to `buffer`.
+    fn ensure_transformation(
+        &self,
+        state: &mut State,
+        settings: &Settings,
+        buffer: &gstreamer::BufferRef,
+        key: (DepthMode, ColorResolution),
+    ) -> Result<(), gstreamer::FlowError> {
+        if state
+            .transformation
+            .as_ref()
+            .map_or(false, |(current, _)| *current == key)
+        {
+            return Ok(());
+        }
+        let (depth_mode, color_resolution) = (key.0.to_sys(), key.1.to_sys());
+        if state.raw_calibration.is_none() && !settings.calibration_file.is_empty() {
+            let raw = std::fs::read(&settings.calibration_file).map_err(|err| {
+                gstreamer::element_imp_error!(
+                    self,
+                    gstreamer::ResourceError::OpenRead,
+                    (
+                        "Could not read calibration from {}. Error: {:#?}",
+                        settings.calibration_file,
+                        err
+                    )
+                );
+                gstreamer::FlowError::Error
+            })?;
+            state.raw_calibration = Some(raw);
+        }
+        let meta = buffer.meta::<K4aCalibrationMeta>();
+        let calibration = match (state.raw_calibration.as_deref(), meta.as_ref()) {
+            (Some(raw), _) => libk4a::calibration_from_raw(raw, depth_mode, color_resolution),
+            (None, Some(meta)) => match meta.raw() {
+                Some(raw) => libk4a::calibration_from_raw(raw, depth_mode, color_resolution),
+                // without the JSON the calibration can only be used for the modes it was made for
+                None if meta.calibration().depth_mode == depth_mode
+                    && meta.calibration().color_resolution == color_resolution =>
+                {
+                    Ok(*meta.calibration())
+                }
+                None => Err(libk4a::sys::k4a_result_t::K4A_RESULT_FAILED),
+            },
+            (None, None) => {
+                gstreamer::element_imp_error!(
+                    self,
+                    gstreamer::StreamError::Format,
+                    ("No calibration: set calibration-file or use a k4a source.")
+                );
+                return Err(gstreamer::FlowError::Error);
+            }
+        };
+        let transformation = calibration
+            .and_then(|calibration| Transformation::new(&calibration))
+            .map_err(|err| {
+                gstreamer::element_imp_error!(
+                    self,
+                    gstreamer::LibraryError::Init,
+                    (
+                        "Could not create transformation for {:?}. Error: {:#?}",
+                        key,
+                        err
+                    )
+                );
+                gstreamer::FlowError::Error
+            })?;
+        gstreamer::info!(CAT, imp: self, "Created transformation for {:?}", key);
+        state.transformation = Some((key, transformation));
+        Ok(())
+    }
+
+    fn depth_chain(
+        &self,
+        buffer: gstreamer::Buffer,
+    ) -> Result<gstreamer::FlowSuccess, gstreamer::FlowError> {
+        let settings = self.settings.read().unwrap().clone();
+        let Some(mut output) = self.align(&settings, &buffer)? else {
+            return Ok(gstreamer::FlowSuccess::Ok);
+        };
+
+        {
+            let output = output.get_mut().unwrap();
+            output.set_pts(buffer.pts());
+            output.set_dts(buffer.dts());
+            output.set_duration(buffer.duration());
+            output.set_offset(buffer.offset());
+            if let Some(meta) = buffer.meta::<K4aCalibrationMeta>() {
+                let calibration: Arc<DeviceCalibration> = meta.device_calibration();
+                K4aCalibrationMeta::add(output, calibration);
+            }
+            if let Some(meta) = buffer.meta::<K4aFrameMeta>() {
+                K4aFrameMeta::add(output, *meta.info());
+            }
+        }
+        drop(buffer);
+
+        self.srcpad.push(output)
+    }
+
+    /// Transforms one depth frame, or returns `None` if there is no color frame to pair it with.
+    fn align(
+        &self,
+        settings: &Settings,
+        buffer: &gstreamer::Buffer,
+    ) -> Result<Option<gstreamer::Buffer>, gstreamer::FlowError> {
+        let mut state = self.state.lock().unwrap();
+        let Some((depth_config, depth_mode)) = state
+            .depth_config
+            .and_then(|config| Some((config, config.depth_mode?)))
+        else {
+            return Err(gstreamer::FlowError::NotNegotiated);
+        };
+        let color_resolution = state.color_resolution(settings);
+        self.ensure_transformation(&mut state, settings, buffer, (depth_mode, color_resolution))?;
+        let state = &*state;
+        let transformation = &state.transformation.as_ref().unwrap().1;
+
+        let (depth_width, depth_height) = depth_config.dimensions();
+        let depth_stride = Self::stride(buffer, depth_width as u32 * 2);
+        let depth_map = buffer
+            .map_readable()
+            .map_err(|_| gstreamer::FlowError::Error)?;
+        let depth = ImageRef::new(
+            libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_DEPTH16,
+            depth_width as u32,
+            depth_height as u32,
+            depth_stride,
+            &depth_map,
+        )
+        .map_err(|_| gstreamer::FlowError::Error)?;
+
+        let result = match settings.direction {
+            AlignDirection::DepthToColor => {
+                let (width, height) = color_resolution.dimensions();
+                let (width, height) = (width as u32, height as u32);
+                let mut output = gstreamer::Buffer::with_size((width * height * 2) as usize)
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                let result = {
+                    let mut output_map = output
+                        .get_mut()
+                        .unwrap()
+                        .map_writable()
+                        .map_err(|_| gstreamer::FlowError::Error)?;
+                    let mut transformed = ImageRef::new_mut(
+                        libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_DEPTH16,
+                        width,
+                        height,
+                        width * 2,
+                        &mut output_map,
+                    )
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                    transformation.depth_image_to_color_camera(&depth, &mut transformed)
+                };
+                result.map(|()| output)
+            }
+            AlignDirection::ColorToDepth => {
+                let Some(color) = state.nearest_color(buffer.pts()) else {
+                    gstreamer::debug!(CAT, imp: self, "No color frame to align yet");
+                    return Ok(None);
+                };
+                let Some(color_config) = state.color_config else {
+                    return Err(gstreamer::FlowError::NotNegotiated);
+                };
+                let (color_width, color_height) = color_config.dimensions();
+                let color_stride = Self::stride(color, color_width as u32 * 4);
+                let color_map = color
+                    .map_readable()
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                let color = ImageRef::new(
+                    libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_BGRA32,
+                    color_width as u32,
+                    color_height as u32,
+                    color_stride,
+                    &color_map,
+                )
+                .map_err(|_| gstreamer::FlowError::Error)?;
+
+                let (width, height) = (depth_width as u32, depth_height as u32);
+                let mut output = gstreamer::Buffer::with_size((width * height * 4) as usize)
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                let result = {
+                    let mut output_map = output
+                        .get_mut()
+                        .unwrap()
+                        .map_writable()
+                        .map_err(|_| gstreamer::FlowError::Error)?;
+                    let mut transformed = ImageRef::new_mut(
+                        libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_BGRA32,
+                        width,
+                        height,
+                        width * 4,
+                        &mut output_map,
+                    )
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                    transformation.color_image_to_depth_camera(&depth, &color, &mut transformed)
+                };
+                result.map(|()| output)
+            }
+        };
+        result.map(Some).map_err(|err| {
+            gstreamer::element_imp_error!(
+                self,
+                gstreamer::LibraryError::Failed,
+                ("Could not transform image. Error: {:#?}", err)
+            );
+            gstreamer::FlowError::Error
+        })
+    }
+
+    fn color_chain(
+        &self,
+        buffer: gstreamer::Buffer,
+    ) -> Result<gstreamer::FlowSuccess, gstreamer::FlowError> {
+        if self.settings.read().unwrap().direction == AlignDirection::DepthToColor {
+            return Ok(gstreamer::FlowSuccess::Ok);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.colors.len() == State::MAX_COLORS {
+            state.colors.pop_front();
+        }
+        state.colors.push_back(buffer);
+        Ok(gstreamer::FlowSuccess::Ok)
+    }
+
+    /// Whether color comes from a live source while registering color to depth. Only one k4asrc
+    /// can open a device and each produces a single stream, so live color and depth never come
+    /// from the same device and calibration.
+    fn color_is_live(&self) -> bool {
+        if self.settings.read().unwrap().direction != AlignDirection::ColorToDepth {
+            return false;
+        }
+        let mut query = gstreamer::query::Latency::new();
+        self.colorpad.peer_query(&mut query) && query.result().0
+    }
+
+    /// The stride of the first plane, from the video meta if the producer attached one.
+    fn stride(buffer: &gstreamer::BufferRef, default: u32) -> u32 {
+        buffer
+            .meta::<gstreamer_video::VideoMeta>()
+            .map_or(default, |meta| meta.stride()[0] as u32)
+    }
+
+    fn sink_event(&self, pad: &gstreamer::Pad, event: gstreamer::Event) -> bool {
+        let is_depth = pad == &self.depthpad;
+        match event.view() {
+            gstreamer::EventView::Caps(caps) => {
+                if !is_depth && self.color_is_live() {
+                    gstreamer::element_imp_error!(
+                        self,
+                        gstreamer::StreamError::Format,
+                        ("Cannot register live color: color-to-depth needs color and depth from one k4aplaybacksrc recording.")
+                    );
+                    return false;
+                }
+                let mode = if is_depth { Mode::Depth } else { Mode::Color };
+                let Some(config) = caps
+                    .caps()
+                    .structure(0)
+                    .and_then(|structure| StreamConfig::from_structure(mode, structure))
+                else {
+                    gstreamer::warning!(
+                        CAT,
+                        obj: pad,
+                        "Caps {} do not match a device configuration",
+                        caps.caps()
+                    );
+                    return false;
+                };
+                {
+                    let mut state = self.state.lock().unwrap();
+                    if is_depth {
+                        state.depth_config = Some(config);
+                    } else {
+                        state.color_config = Some(config);
+                    }
+                }
+                self.update_src_caps();
+                true
+            }
+            gstreamer::EventView::FlushStop(_) if !is_depth => {
+                self.state.lock().unwrap().colors.clear();
+                true
+            }
+            _ if is_depth => gstreamer::Pad::event_default(pad, Some(&*self.instance()), event),
+            // color frames only accompany depth frames, so only the depth stream goes downstream
+            _ => true,
+        }
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for K4aDepthAlign {
+    const NAME: &'static str = "k4adepthalign";
+
+    type Type = super::K4aDepthAlign;
+
+    type ParentType = gstreamer::Element;
+
+    fn with_class(klass: &Self::Class) -> Self {
+        let templ = klass.pad_template("depth").unwrap();
+        let depthpad = gstreamer::Pad::builder_with_template(&templ, Some("depth"))
+            .chain_function(|_pad, parent, buffer| {
+                K4aDepthAlign::catch_panic_pad_function(
+                    parent,
+                    || Err(gstreamer::FlowError::Error),
+                    |imp| imp.depth_chain(buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                K4aDepthAlign::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |imp| imp.sink_event(pad, event),
+                )
+            })
+            .build();
+
+        let templ = klass.pad_template("color").unwrap();
+        let colorpad = gstreamer::Pad::builder_with_template(&templ, Some("color"))
+            .chain_function(|_pad, parent, buffer| {
+                K4aDepthAlign::catch_panic_pad_function(
+                    parent,
+                    || Err(gstreamer::FlowError::Error),
+                    |imp| imp.color_chain(buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                K4aDepthAlign::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |imp| imp.sink_event(pad, event),
+                )
+            })
+            .build();
+
+        let templ = klass.pad_template("src").unwrap();
+        let srcpad = gstreamer::Pad::builder_with_template(&templ, Some("src")).build();
+
+        Self {
+            settings: RwLock::new(Settings::default()),
+            state: Mutex::new(State::default()),
+            depthpad,
+            colorpad,
+            srcpad,
+        }
+    }
+}
+
+impl ObjectImpl for K4aDepthAlign {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            SettingField::iter()
+                .map(|setting| match setting {
+                    SettingField::Direction => glib::ParamSpecEnum::builder(
+                        setting.into(),
+                        Settings::default().direction,
+                    )
+                    .nick("Direction")
+                    .blurb("Whether to register depth to the color camera or color to the depth camera (color-to-depth needs color and depth from one k4aplaybacksrc recording)")
+                    .build(),
+                    SettingField::ColorResolution => glib::ParamSpecEnum::builder(
+                        setting.into(),
+                        Settings::default().color_resolution,
+                    )
+                    .nick("Color Resolution")
+                    .blurb("Color camera resolution to register depth to when no color stream is linked")
+                    .build(),
+                    SettingField::CalibrationFile => glib::ParamSpecString::builder(setting.into())
+                        .nick("Calibration File")
+                        .blurb("Raw calibration JSON to use instead of the calibration attached to buffers")
+                        .build(),
+                })
+                .collect()
+        });
+        PROPERTIES.as_ref()
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.instance();
+        obj.add_pad(&self.depthpad).unwrap();
+        obj.add_pad(&self.colorpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match SettingField::from_str(pspec.name()) {
+            Ok(field) => {
+                let mut settings = self.settings.write().unwrap();
+                match field {
+                    SettingField::Direction => {
+                        set_field!(CAT, self, field, enum settings.direction, value)
+                    }
+                    SettingField::ColorResolution => {
+                        set_field!(CAT, self, field, enum settings.color_resolution, value)
+                    }
+                    SettingField::CalibrationFile => {
+                        set_field!(CAT, self, field, settings.calibration_file, value)
+                    }
+                }
+            }
+            Err(err) => {
+                panic!("Unknown field {} ({})", pspec.name(), err);
+            }
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match SettingField::from_str(pspec.name()) {
+            Ok(field) => {
+                let settings = self.settings.read().unwrap();
+                match field {
+                    SettingField::Direction => settings.direction.to_value(),
+                    SettingField::ColorResolution => settings.color_resolution.to_value(),
+                    SettingField::CalibrationFile => settings.calibration_file.to_value(),
+                }
+            }
+            Err(err) => {
+                panic!("Unknown field {} ({})", pspec.name(), err);
+            }
+        }
+    }
+}
+
+impl GstObjectImpl for K4aDepthAlign {}
+
+impl ElementImpl for K4aDepthAlign {
+    fn metadata() -> Option<&'static gstreamer::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gstreamer::subclass::ElementMetadata> = Lazy::new(|| {
+            gstreamer::subclass::ElementMetadata::new(
+                "Azure Kinect Depth Alignment",
+                "Filter/Converter/Video",
+                "Registers Azure Kinect depth to the color camera or color to the depth camera",
+                "William Zhang <wtzhang23@gmail.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gstreamer::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gstreamer::PadTemplate>> = Lazy::new(|| {
+            let bgra_caps = gstreamer::Caps::builder("video/x-raw")
+                .field("format", VideoFormat::Bgra.to_str().to_owned())
+                .build();
+            let mut src_caps = StreamConfig::ir_depth_caps();
+            src_caps.merge(bgra_caps.clone());
+            vec![
+                gstreamer::PadTemplate::new(
+                    "depth",
+                    gstreamer::PadDirection::Sink,
+                    gstreamer::PadPresence::Always,
+                    &StreamConfig::ir_depth_caps(),
+                )
+                .unwrap(),
+                gstreamer::PadTemplate::new(
+                    "color",
+                    gstreamer::PadDirection::Sink,
+                    gstreamer::PadPresence::Always,
+                    &bgra_caps,
+                )
+                .unwrap(),
+                gstreamer::PadTemplate::new(
+                    "src",
+                    gstreamer::PadDirection::Src,
+                    gstreamer::PadPresence::Always,
+                    &src_caps,
+                )
+                .unwrap(),
+            ]
+        });
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gstreamer::StateChange,
+    ) -> Result<gstreamer::StateChangeSuccess, gstreamer::StateChangeError> {
+        let success = self.parent_change_state(transition)?;
+        if transition == gstreamer::StateChange::PausedToReady {
+            *self.state.lock().unwrap() = State::default();
+        }
+        Ok(success)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstK4aAlignDirection")]
+enum AlignDirection {
+    /// Depth at the color resolution, as seen by the color camera.
+    DepthToColor,
+    /// BGRA color at the depth resolution, as seen by the depth camera.
+    ColorToDepth,
+}
@@ -1,5 +1,7 @@
-use std::ops::ControlFlow;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
 use std::{str::FromStr, sync::RwLock, time::Duration};
 
 use gstreamer::{glib, prelude::*, subclass::prelude::*};
@@ -7,14 +9,21 @@ use gstreamer_base::{
     subclass::{base_src::CreateSuccess, prelude::*},
     traits::BaseSrcExt,
 };
-use gstreamer_video::VideoFormat;
 use once_cell::sync::Lazy;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString, IntoStaticStr};
 
-use crate::{frame::FrameData, k4a::libk4a, macros::set_field};
+use crate::{
+    frame::{ClockFit, ClockMapper, FrameQueue, LatencyTracker, Leaky, PopResult},
+    k4a::libk4a,
+    macros::set_field,
+};
 
-use super::libk4a::{Device, Stream};
+use super::{
+    backend::{Backend, Capturer, Device, Sdk, Stream},
+    config::{ColorResolution, DepthMode, FpsMode, Mode, StreamConfig},
+    meta::{self, DeviceCalibration, FrameInfo, K4aCalibrationMeta, K4aFrameMeta},
+};
 
 static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
     gstreamer::DebugCategory::new(
@@ -29,17 +38,64 @@ struct Settings {
     fps_mode: FpsMode,
     color_resolution: ColorResolution,
     depth_mode: DepthMode,
+    passive_ir: bool,
     mode: Mode,
+    exposure_time: i32,
+    white_balance: i32,
+    brightness: i32,
+    contrast: i32,
+    saturation: i32,
+    sharpness: i32,
+    gain: i32,
+    backlight_compensation: bool,
+    powerline_frequency: PowerlineFrequency,
+    /// Color controls the user set. The others are left at whatever the device defaults to.
+    color_controls: HashSet<ColorControl>,
+    disable_streaming_indicator: bool,
+    calibration_file: String,
+    max_buffers: u32,
+    leaky: Leaky,
+    timeout: u32,
+    serial_number: String,
+    reconnect: ReconnectPolicy,
+    reconnect_attempts: u32,
+    timestamp_mode: TimestampMode,
 }
 
 impl Settings {
-    const IR_PASSIVE_RESOLUTION: (i32, i32) = (1024, 1024);
-
     fn resolution(&self) -> (i32, i32) {
         match self.mode {
             Mode::Color => self.color_resolution.dimensions(),
-            Mode::Ir => Self::IR_PASSIVE_RESOLUTION,
-            Mode::Depth => self.depth_mode.dimensions(),
+            Mode::Ir | Mode::Depth => self
+                .active_depth_mode()
+                .map_or(StreamConfig::IR_PASSIVE_RESOLUTION, |depth_mode| {
+                    depth_mode.dimensions()
+                }),
+        }
+    }
+
+    /// The depth mode the depth camera runs in, or `None` if it is off or only captures passive
+    /// IR.
+    fn active_depth_mode(&self) -> Option<DepthMode> {
+        match self.mode {
+            Mode::Color => None,
+            Mode::Ir if self.passive_ir => None,
+            Mode::Ir | Mode::Depth => Some(self.depth_mode),
+        }
+    }
+
+    /// The requested value of a color control, encoded the way libk4a expects it.
+    fn color_control(&self, control: ColorControl) -> i32 {
+        match control {
+            ColorControl::ExposureTime => self.exposure_time,
+            ColorControl::WhiteBalance => self.white_balance,
+            ColorControl::Brightness => self.brightness,
+            ColorControl::Contrast => self.contrast,
+            ColorControl::Saturation => self.saturation,
+            ColorControl::Sharpness => self.sharpness,
+            ColorControl::Gain => self.gain,
+            ColorControl::BacklightCompensation => self.backlight_compensation as i32,
+            ColorControl::PowerlineFrequency => self.powerline_frequency.to_k4a(),
         }
     }
 }
@@ -50,7 +106,29 @@ impl Default for Settings {
             fps_mode: FpsMode::Fps30,
             color_resolution: ColorResolution::Res720P,
             depth_mode: DepthMode::NormalFov2x2Binned,
+            passive_ir: true,
             mode: Mode::Depth,
+            // the color control defaults are the ones the device starts with and are only applied
+            // once set
+            exposure_time: 0,
+            white_balance: 0,
+            brightness: 128,
+            contrast: 5,
+            saturation: 32,
+            sharpness: 2,
+            gain: 0,
+            backlight_compensation: false,
+            powerline_frequency: PowerlineFrequency::Hz60,
+            color_controls: HashSet::new(),
+            disable_streaming_indicator: false,
+            calibration_file: "".to_owned(),
+            max_buffers: 2,
+            leaky: Leaky::Downstream,
+            timeout: 2000,
+            serial_number: "".to_owned(),
+            reconnect: ReconnectPolicy::Never,
+            reconnect_attempts: 0,
+            timestamp_mode: TimestampMode::Device,
         }
     }
 }
@@ -61,74 +139,562 @@ enum SettingField {
     Fps,
     ColorResolution,
     DepthMode,
+    PassiveIr,
     Mode,
+    ExposureTime,
+    WhiteBalance,
+    Brightness,
+    Contrast,
+    Saturation,
+    Sharpness,
+    Gain,
+    BacklightCompensation,
+    PowerlineFrequency,
+    DisableStreamingIndicator,
+    CalibrationFile,
+    MaxBuffers,
+    Leaky,
+    Timeout,
+    SerialNumber,
+    Reconnect,
+    ReconnectAttempts,
+    TimestampMode,
+}
+
+impl SettingField {
+    fn color_control(&self) -> Option<ColorControl> {
+        match self {
+            Self::ExposureTime => Some(ColorControl::ExposureTime),
+            Self::WhiteBalance => Some(ColorControl::WhiteBalance),
+            Self::Brightness => Some(ColorControl::Brightness),
+            Self::Contrast => Some(ColorControl::Contrast),
+            Self::Saturation => Some(ColorControl::Saturation),
+            Self::Sharpness => Some(ColorControl::Sharpness),
+            Self::Gain => Some(ColorControl::Gain),
+            Self::BacklightCompensation => Some(ColorControl::BacklightCompensation),
+            Self::PowerlineFrequency => Some(ColorControl::PowerlineFrequency),
+            _ => None,
+        }
+    }
+}
+
+/// RGB camera controls that can be changed while streaming.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter)]
+enum ColorControl {
+    ExposureTime,
+    WhiteBalance,
+    Brightness,
+    Contrast,
+    Saturation,
+    Sharpness,
+    Gain,
+    BacklightCompensation,
+    PowerlineFrequency,
+}
+
+impl ColorControl {
+    /// The lowest manual white balance the color camera accepts, in Kelvin.
+    const MIN_WHITE_BALANCE: i32 = 2500;
+    /// Manual white balance has to be a multiple of this, in Kelvin.
+    const WHITE_BALANCE_STEP: i32 = 10;
+
+    fn to_sys(self) -> libk4a::ColorControlCommand {
+        match self {
+            Self::ExposureTime => {
+                libk4a::ColorControlCommand::K4A_COLOR_CONTROL_EXPOSURE_TIME_ABSOLUTE
+            }
+            Self::WhiteBalance => libk4a::ColorControlCommand::K4A_COLOR_CONTROL_WHITEBALANCE,
+            Self::Brightness => libk4a::ColorControlCommand::K4A_COLOR_CONTROL_BRIGHTNESS,
+            Self::Contrast => libk4a::ColorControlCommand::K4A_COLOR_CONTROL_CONTRAST,
+            Self::Saturation => libk4a::ColorControlCommand::K4A_COLOR_CONTROL_SATURATION,
+            Self::Sharpness => libk4a::ColorControlCommand::K4A_COLOR_CONTROL_SHARPNESS,
+            Self::Gain => libk4a::ColorControlCommand::K4A_COLOR_CONTROL_GAIN,
+            Self::BacklightCompensation => {
+                libk4a::ColorControlCommand::K4A_COLOR_CONTROL_BACKLIGHT_COMPENSATION
+            }
+            Self::PowerlineFrequency => {
+                libk4a::ColorControlCommand::K4A_COLOR_CONTROL_POWERLINE_FREQUENCY
+            }
+        }
+    }
+
+    /// Exposure and white balance use 0 to hand control back to the camera.
+    fn has_auto(&self) -> bool {
+        matches!(self, Self::ExposureTime | Self::WhiteBalance)
+    }
+
+    fn apply(&self, device: &dyn Device, value: i32) -> Result<(), libk4a::sys::k4a_result_t> {
+        let mode = if self.has_auto() && value == 0 {
+            libk4a::ColorControlMode::K4A_COLOR_CONTROL_MODE_AUTO
+        } else {
+            libk4a::ColorControlMode::K4A_COLOR_CONTROL_MODE_MANUAL
+        };
+        device.set_color_control(self.to_sys(), mode, value)
+    }
+
+    fn read(&self, device: &dyn Device) -> Result<i32, libk4a::sys::k4a_result_t> {
+        let (mode, value) = device.get_color_control(self.to_sys())?;
+        match mode {
+            libk4a::ColorControlMode::K4A_COLOR_CONTROL_MODE_AUTO if self.has_auto() => Ok(0),
+            _ => Ok(value),
+        }
+    }
 }
 
 enum StreamState {
-    Open(Stream),
-    Closed(Device),
+    Open(Box<dyn Stream>),
+    Closed(Box<dyn Device>),
+}
+
+impl StreamState {
+    fn device(&self) -> &dyn Device {
+        match self {
+            Self::Open(stream) => stream.device(),
+            Self::Closed(device) => device.as_ref(),
+        }
+    }
 }
 
 struct State {
     camera: Option<StreamState>,
-    cap_to_use: Option<gstreamer::Caps>,
+    config: Option<StreamConfig>,
+    calibration: Option<Arc<DeviceCalibration>>,
+    capture_thread: Option<CaptureThread>,
+    /// Serial number of the opened device, used to find it again after it was lost.
+    serial_number: Option<String>,
+    device_configuration: Option<libk4a::DeviceConfiguration>,
+    discont: bool,
+    last_end: Option<gstreamer::ClockTime>,
+    device_clock: DeviceClock,
+    clock_mapper: ClockMapper,
+    /// Latency last reported in latency queries, once it was measured.
+    latency: Option<gstreamer::ClockTime>,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
             camera: None,
-            cap_to_use: None,
+            config: None,
+            calibration: None,
+            capture_thread: None,
+            serial_number: None,
+            device_configuration: None,
+            discont: false,
+            last_end: None,
+            device_clock: DeviceClock::default(),
+            clock_mapper: ClockMapper::default(),
+            latency: None,
         }
     }
 }
 
+/// Places device timestamps on the host's monotonic clock. Images reach the host some time after
+/// their exposure, and a line fitted through recent device timestamps against their arrival
+/// follows the drift between the clocks while averaging out the jitter of the transfer.
+#[derive(Default)]
+struct DeviceClock {
+    fit: ClockFit,
+    last: Option<Duration>,
+}
+
+impl DeviceClock {
+    fn reset(&mut self) {
+        self.fit.reset();
+        self.last = None;
+    }
+
+    /// `device` is the device timestamp of an image and `received` the monotonic time at which it
+    /// reached the host. Never goes back from what an earlier image was mapped to, even when the
+    /// fit moves.
+    fn map(&mut self, device: Duration, received: Duration) -> Duration {
+        self.fit
+            .add(device.as_nanos() as u64, received.as_nanos() as u64);
+        let mapped = self
+            .fit
+            .map(device.as_nanos() as u64)
+            .map_or(received, Duration::from_nanos);
+        let mapped = self.last.map_or(mapped, |last| mapped.max(last));
+        self.last = Some(mapped);
+        mapped
+    }
+}
+
+struct CapturedFrame {
+    capture: libk4a::Capture,
+    /// Index of the capture since the stream started, counting dropped captures.
+    sequence: u64,
+    /// Pipeline clock time at which the capture was handed over by the device.
+    arrival: Option<gstreamer::ClockTime>,
+}
+
+type CaptureResult = Result<CapturedFrame, libk4a::sys::k4a_wait_result_t>;
+
+/// Pulls captures off the device so that a stalled device never blocks the streaming thread.
+struct CaptureThread {
+    /// Set to stop the thread, independently of the queue being flushed by `unlock`.
+    cancelled: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl CaptureThread {
+    fn spawn(
+        element: glib::WeakRef<super::K4a>,
+        capturer: Box<dyn Capturer>,
+        captures: Arc<FrameQueue<CaptureResult>>,
+        timeout: Duration,
+    ) -> std::io::Result<Self> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = std::thread::Builder::new()
+            .name("k4asrc-capture".to_owned())
+            .spawn({
+                let cancelled = cancelled.clone();
+                move || {
+                    let mut sequence = 0;
+                    while !cancelled.load(Ordering::Acquire) {
+                        let result = capturer.get_capture(timeout).map(|capture| {
+                            sequence += 1;
+                            CapturedFrame {
+                                capture,
+                                sequence: sequence - 1,
+                                arrival: element
+                                    .upgrade()
+                                    .and_then(|element| element.clock())
+                                    .and_then(|clock| clock.time()),
+                            }
+                        });
+                        let failed = matches!(
+                            result,
+                            Err(libk4a::sys::k4a_wait_result_t::K4A_WAIT_RESULT_FAILED)
+                        );
+                        if captures.push(result, &cancelled).is_some() {
+                            gstreamer::trace!(CAT, "Dropped capture");
+                        }
+                        if failed {
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { cancelled, handle })
+    }
+
+    /// Stops the thread and drops whatever it queued, leaving the flushing state of the queue to
+    /// `unlock` and `unlock_stop`.
+    fn stop(self, captures: &FrameQueue<CaptureResult>) {
+        captures.cancel_push(&self.cancelled);
+        let _ = self.handle.join();
+        captures.clear();
+    }
+}
+
 pub struct K4a {
+    backend: RwLock<Arc<dyn Backend>>,
     settings: RwLock<Settings>,
     state: RwLock<State>,
-    frame_data: Arc<FrameData<()>>,
+    latency_tracker: LatencyTracker,
+    captures: Arc<FrameQueue<CaptureResult>>,
 }
 
 impl K4a {
-    fn color_caps() -> gstreamer::Caps {
-        gstreamer::Caps::builder_full()
-            .structure(
-                gstreamer::Structure::builder("video/x-raw")
-                    .field("format", VideoFormat::Bgra.to_str().to_owned())
-                    .build(),
-            )
-            .structure(
-                gstreamer::Structure::builder("video/x-raw")
-                    .field("format", VideoFormat::Yuy2.to_str().to_owned())
-                    .build(),
-            )
-            .structure(
-                gstreamer::Structure::builder("video/x-raw")
-                    .field("format", VideoFormat::Nv12.to_str().to_owned())
-                    .build(),
+    #[cfg(test)]
+    pub(super) fn set_backend(&self, backend: Arc<dyn Backend>) {
+        *self.backend.write().unwrap() = backend;
+    }
+
+    fn apply_color_control(&self, device: &dyn Device, control: ColorControl, value: i32) {
+        if let Err(err) = control.apply(device, value) {
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "Could not set {:?} to {}. Error: {:#?}",
+                control,
+                value,
+                err
+            );
+        }
+    }
+
+    fn open_device(
+        &self,
+        serial_number: Option<&str>,
+    ) -> Result<Box<dyn Device>, libk4a::sys::k4a_result_t> {
+        let backend = self.backend.read().unwrap().clone();
+        let device = backend.open(serial_number)?;
+        let settings = self.settings.read().unwrap().clone();
+        for control in ColorControl::iter().filter(|c| settings.color_controls.contains(c)) {
+            self.apply_color_control(device.as_ref(), control, settings.color_control(control));
+        }
+        Ok(device)
+    }
+
+    /// Starts the cameras of an open device along with the thread that pulls captures off it.
+    /// On failure the device is left closed in the state.
+    fn start_stream(
+        &self,
+        state: &mut State,
+        device: Box<dyn Device>,
+        device_configuration: libk4a::DeviceConfiguration,
+        settings: &Settings,
+    ) -> Result<(), String> {
+        let stream = match device.start_cameras(device_configuration) {
+            Ok(stream) => stream,
+            Err((device, err)) => {
+                state.camera = Some(StreamState::Closed(device));
+                state.calibration = None;
+                return Err(format!(
+                    "Cannot open device to begin streaming. Error: {:#?}",
+                    err
+                ));
+            }
+        };
+        let calibration = self.fetch_calibration(
+            stream.device(),
+            &device_configuration,
+            &settings.calibration_file,
+        );
+        self.captures
+            .configure(settings.max_buffers as usize, settings.leaky);
+        let capture_thread = CaptureThread::spawn(
+            self.instance().downgrade(),
+            stream.capturer(),
+            self.captures.clone(),
+            Duration::from_millis(settings.timeout as u64),
+        );
+        match capture_thread {
+            Ok(capture_thread) => {
+                state.capture_thread = Some(capture_thread);
+                state.device_clock.reset();
+                state.clock_mapper.reset();
+                state.camera = Some(StreamState::Open(stream));
+                state.calibration = calibration;
+                state.device_configuration = Some(device_configuration);
+                Ok(())
+            }
+            Err(err) => {
+                state.camera = Some(StreamState::Closed(stream.stop_cameras()));
+                state.calibration = None;
+                Err(format!("Could not spawn capture thread. Error: {:#?}", err))
+            }
+        }
+    }
+
+    fn post_device_message(&self, name: &str, serial_number: &str) {
+        let message = gstreamer::message::Element::builder(
+            gstreamer::Structure::builder(name)
+                .field("serial-number", serial_number)
+                .build(),
+        )
+        .src(&*self.instance())
+        .build();
+        if self.instance().post_message(message).is_err() {
+            gstreamer::warning!(CAT, imp: self, "Could not post {} message", name);
+        }
+    }
+
+    /// Reopens a device that disappeared and restarts it with the configuration it had. Returns
+    /// once streaming resumed, or with an error once the reconnect policy gives up or the element
+    /// starts flushing.
+    fn reconnect(&self) -> Result<(), gstreamer::FlowError> {
+        const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+        let settings = self.settings.read().unwrap().clone();
+        let (serial_number, device_configuration, last_end) = {
+            let mut state = self.state.write().unwrap();
+            if let Some(capture_thread) = state.capture_thread.take() {
+                capture_thread.stop(&self.captures);
+            }
+            // the stale handle has to be closed before the device can be opened again
+            state.camera.take();
+            (
+                state.serial_number.clone(),
+                state.device_configuration,
+                state.last_end,
             )
-            .build()
+        };
+        let Some(device_configuration) = device_configuration else {
+            return Err(gstreamer::FlowError::NotNegotiated);
+        };
+        let serial_name = serial_number.clone().unwrap_or_default();
+
+        gstreamer::warning!(CAT, imp: self, "Lost device {}", serial_name);
+        self.instance()
+            .emit_by_name::<()>("device-lost", &[&serial_name]);
+        self.post_device_message("k4a-device-lost", &serial_name);
+        if let Some(last_end) = last_end {
+            self.instance()
+                .src_pad()
+                .push_event(gstreamer::event::Gap::new(last_end, None));
+        }
+
+        let mut backoff = Duration::from_millis(100);
+        let mut attempts = 0;
+        loop {
+            // nothing is producing captures, so this only waits out the backoff unless unlocked
+            if let PopResult::Flushing = self.captures.pop(Some(backoff)) {
+                return Err(gstreamer::FlowError::Flushing);
+            }
+            attempts += 1;
+            gstreamer::info!(
+                CAT,
+                imp: self,
+                "Reconnecting to device {} (attempt {})",
+                serial_name,
+                attempts
+            );
+            match self.open_device(serial_number.as_deref()) {
+                Ok(device) => {
+                    let mut state = self.state.write().unwrap();
+                    match self.start_stream(&mut state, device, device_configuration, &settings) {
+                        Ok(()) => {
+                            state.discont = true;
+                            drop(state);
+                            self.post_device_message("k4a-device-reconnected", &serial_name);
+                            return Ok(());
+                        }
+                        Err(err) => {
+                            // keep no handle around so that the next attempt can reopen it
+                            state.camera.take();
+                            gstreamer::warning!(CAT, imp: self, "{}", err);
+                        }
+                    }
+                }
+                Err(err) => {
+                    gstreamer::debug!(CAT, imp: self, "Could not open device. Error: {:#?}", err);
+                }
+            }
+            if settings.reconnect_attempts != 0 && attempts >= settings.reconnect_attempts {
+                gstreamer::element_imp_error!(
+                    self,
+                    gstreamer::ResourceError::NotFound,
+                    (
+                        "Could not reconnect to device {} after {} attempts.",
+                        serial_name,
+                        attempts
+                    )
+                );
+                return Err(gstreamer::FlowError::Error);
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
     }
 
-    fn ir_depth_caps() -> gstreamer::Caps {
-        gstreamer::Caps::builder("video/x-raw")
-            .field("format", VideoFormat::Gray16Le.to_str().to_owned())
-            .build()
+    /// The pipeline clock time at which `image` was captured, following the timestamp mode.
+    fn timestamp(
+        &self,
+        image: &libk4a::Image,
+        timestamp_mode: TimestampMode,
+        arrival: Option<gstreamer::ClockTime>,
+    ) -> Option<gstreamer::ClockTime> {
+        if timestamp_mode == TimestampMode::Arrival {
+            return arrival;
+        }
+        let clock = self.instance().clock()?;
+        let mut state = self.state.write().unwrap();
+        let monotonic = match timestamp_mode {
+            TimestampMode::Device => state
+                .device_clock
+                .map(image.get_device_timestamp(), image.get_system_timestamp()),
+            _ => image.get_system_timestamp(),
+        };
+        state.clock_mapper.sample(&clock);
+        state.clock_mapper.map(monotonic)
     }
 
-    fn add_fields_to_caps(&self, caps: &mut gstreamer::CapsRef) {
-        let (width, height, fps) = {
-            let settings = self.settings.read().unwrap();
-            let (width, height) = settings.resolution();
-            let fps = settings.fps_mode.fps();
-            (width, height, gstreamer::Fraction::new(fps * 1000, 1001))
+    /// Tracks how long after their timestamp buffers leave the element and asks the pipeline to
+    /// recalculate its latency when that grew beyond what was last reported or clearly dropped
+    /// below it.
+    fn measure_latency(&self, latency: Duration) {
+        self.latency_tracker.update(latency);
+        let Some(measured) = self.latency_tracker.latency() else {
+            return;
         };
-        caps.map_in_place(|_features, structure| {
-            structure.set("width", width);
-            structure.set("height", height);
-            structure.set("framerate", fps);
-            ControlFlow::Continue(())
+        let measured = gstreamer::ClockTime::from_nseconds(measured.as_nanos() as u64);
+        {
+            let mut state = self.state.write().unwrap();
+            // jitter alone should not keep the pipeline recalculating its latency
+            if state.latency.map_or(false, |reported| {
+                measured <= reported && measured.nseconds() * 4 >= reported.nseconds() * 3
+            }) {
+                return;
+            }
+            state.latency = Some(measured);
+        }
+        gstreamer::debug!(CAT, imp: self, "Measured latency {}", measured);
+        let message = gstreamer::message::Latency::builder()
+            .src(&*self.instance())
+            .build();
+        if self.instance().post_message(message).is_err() {
+            gstreamer::warning!(CAT, imp: self, "Could not post latency message");
+        }
+    }
+
+    /// Reads the calibration for the configuration the cameras were started with, writing the raw
+    /// calibration JSON out if a path was given.
+    fn fetch_calibration(
+        &self,
+        device: &dyn Device,
+        device_configuration: &libk4a::DeviceConfiguration,
+        calibration_file: &str,
+    ) -> Option<Arc<DeviceCalibration>> {
+        let raw = match device.get_raw_calibration() {
+            Ok(raw) => Some(raw),
+            Err(err) => {
+                gstreamer::warning!(
+                    CAT,
+                    imp: self,
+                    "Could not read raw calibration. Error: {:#?}",
+                    err
+                );
+                None
+            }
+        };
+        if let (false, Some(raw)) = (calibration_file.is_empty(), raw.as_ref()) {
+            if let Err(err) = std::fs::write(calibration_file, raw) {
+                gstreamer::warning!(
+                    CAT,
+                    imp: self,
+                    "Could not write calibration to {}. Error: {:#?}",
+                    calibration_file,
+                    err
+                );
+            }
+        }
+        match device.get_calibration(
+            device_configuration.depth_mode,
+            device_configuration.color_resolution,
+        ) {
+            Ok(calibration) => Some(Arc::new(DeviceCalibration { calibration, raw })),
+            Err(err) => {
+                gstreamer::warning!(
+                    CAT,
+                    imp: self,
+                    "Could not read device calibration. Error: {:#?}",
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Builds caps with one structure per legal configuration of the selected stream. The
+    /// configuration closest to the properties comes first so that it wins fixation.
+    fn stream_caps(&self) -> gstreamer::Caps {
+        let settings = self.settings.read().unwrap().clone();
+        let mut configs = StreamConfig::all(settings.mode);
+        configs.sort_by_key(|config| {
+            (
+                config.dimensions() != settings.resolution(),
+                config.fps_mode != settings.fps_mode,
+                config.depth_mode != settings.active_depth_mode(),
+            )
         });
+        configs
+            .into_iter()
+            .fold(gstreamer::Caps::builder_full(), |builder, config| {
+                builder.structure(config.to_structure())
+            })
+            .build()
     }
 }
 
@@ -142,9 +708,14 @@ impl ObjectSubclass for K4a {
 
     fn new() -> Self {
         Self {
+            backend: RwLock::new(Arc::new(Sdk)),
             settings: RwLock::new(Settings::default()),
             state: RwLock::new(State::default()),
-            frame_data: Arc::new(FrameData::default()),
+            latency_tracker: LatencyTracker::default(),
+            captures: Arc::new(FrameQueue::new(
+                Settings::default().max_buffers as usize,
+                Settings::default().leaky,
+            )),
         }
     }
 }
@@ -173,18 +744,158 @@ impl ObjectImpl for K4a {
                             .blurb("The depth mode to read from the camera")
                             .build()
                     }
+                    SettingField::PassiveIr => glib::ParamSpecBoolean::builder(setting.into())
+                        .nick("Passive IR")
+                        .blurb("In IR mode, capture passive IR instead of the active IR of the depth mode")
+                        .default_value(Settings::default().passive_ir)
+                        .build(),
                     SettingField::Mode => {
                         glib::ParamSpecEnum::builder(setting.into(), Settings::default().mode)
                             .nick("Mode")
                             .blurb("What image type to read from the camera")
                             .build()
                     }
+                    SettingField::ExposureTime => glib::ParamSpecInt::builder(setting.into())
+                        .nick("Exposure Time")
+                        .blurb("Exposure time of the color camera in microseconds (0 for auto)")
+                        .minimum(0)
+                        .maximum(200000)
+                        .default_value(Settings::default().exposure_time)
+                        .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                        .build(),
+                    SettingField::WhiteBalance => glib::ParamSpecInt::builder(setting.into())
+                        .nick("White Balance")
+                        .blurb("White balance of the color camera in Kelvin, in steps of 10 from 2500 (0 for auto)")
+                        .minimum(0)
+                        .maximum(12500)
+                        .default_value(Settings::default().white_balance)
+                        .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                        .build(),
+                    SettingField::Brightness => glib::ParamSpecInt::builder(setting.into())
+                        .nick("Brightness")
+                        .blurb("Brightness of the color camera")
+                        .minimum(0)
+                        .maximum(255)
+                        .default_value(Settings::default().brightness)
+                        .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                        .build(),
+                    SettingField::Contrast => glib::ParamSpecInt::builder(setting.into())
+                        .nick("Contrast")
+                        .blurb("Contrast of the color camera")
+                        .minimum(0)
+                        .maximum(10)
+                        .default_value(Settings::default().contrast)
+                        .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                        .build(),
+                    SettingField::Saturation => glib::ParamSpecInt::builder(setting.into())
+                        .nick("Saturation")
+                        .blurb("Saturation of the color camera")
+                        .minimum(0)
+                        .maximum(63)
+                        .default_value(Settings::default().saturation)
+                        .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                        .build(),
+                    SettingField::Sharpness => glib::ParamSpecInt::builder(setting.into())
+                        .nick("Sharpness")
+                        .blurb("Sharpness of the color camera")
+                        .minimum(0)
+                        .maximum(4)
+                        .default_value(Settings::default().sharpness)
+                        .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                        .build(),
+                    SettingField::Gain => glib::ParamSpecInt::builder(setting.into())
+                        .nick("Gain")
+                        .blurb("Gain of the color camera")
+                        .minimum(0)
+                        .maximum(255)
+                        .default_value(Settings::default().gain)
+                        .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                        .build(),
+                    SettingField::BacklightCompensation => {
+                        glib::ParamSpecBoolean::builder(setting.into())
+                            .nick("Backlight Compensation")
+                            .blurb("Whether the color camera compensates for backlighting")
+                            .default_value(Settings::default().backlight_compensation)
+                            .flags(
+                                glib::ParamFlags::READWRITE
+                                    | gstreamer::PARAM_FLAG_MUTABLE_PLAYING,
+                            )
+                            .build()
+                    }
+                    SettingField::PowerlineFrequency => glib::ParamSpecEnum::builder(
+                        setting.into(),
+                        Settings::default().powerline_frequency,
+                    )
+                    .nick("Powerline Frequency")
+                    .blurb("The mains frequency the color camera filters flicker from")
+                    .flags(glib::ParamFlags::READWRITE | gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                    .build(),
+                    SettingField::DisableStreamingIndicator => {
+                        glib::ParamSpecBoolean::builder(setting.into())
+                            .nick("Disable Streaming Indicator")
+                            .blurb("Turn off the LED that shows the device is streaming")
+                            .default_value(Settings::default().disable_streaming_indicator)
+                            .build()
+                    }
+                    SettingField::CalibrationFile => glib::ParamSpecString::builder(setting.into())
+                        .nick("Calibration File")
+                        .blurb("Path to write the raw device calibration JSON to on start (empty to disable)")
+                        .build(),
+                    SettingField::MaxBuffers => glib::ParamSpecUInt::builder(setting.into())
+                        .nick("Max Buffers")
+                        .blurb("Maximum number of captures to queue between the capture thread and the pipeline")
+                        .minimum(1)
+                        .default_value(Settings::default().max_buffers)
+                        .build(),
+                    SettingField::Leaky => {
+                        glib::ParamSpecEnum::builder(setting.into(), Settings::default().leaky)
+                            .nick("Leaky")
+                            .blurb("Which captures to drop when the queue is full")
+                            .build()
+                    }
+                    SettingField::Timeout => glib::ParamSpecUInt::builder(setting.into())
+                        .nick("Timeout")
+                        .blurb("Milliseconds to wait for a capture before treating the device as stalled")
+                        .minimum(1)
+                        .default_value(Settings::default().timeout)
+                        .build(),
+                    SettingField::SerialNumber => glib::ParamSpecString::builder(setting.into())
+                        .nick("Device Serial Number")
+                        .blurb("The serial number of the device (empty for the default device)")
+                        .build(),
+                    SettingField::Reconnect => {
+                        glib::ParamSpecEnum::builder(setting.into(), Settings::default().reconnect)
+                            .nick("Reconnect")
+                            .blurb("Whether to reopen the device when it disconnects")
+                            .build()
+                    }
+                    SettingField::ReconnectAttempts => glib::ParamSpecUInt::builder(setting.into())
+                        .nick("Reconnect Attempts")
+                        .blurb("How many times to try reopening a lost device (0 for unlimited)")
+                        .default_value(Settings::default().reconnect_attempts)
+                        .build(),
+                    SettingField::TimestampMode => glib::ParamSpecEnum::builder(
+                        setting.into(),
+                        Settings::default().timestamp_mode,
+                    )
+                    .nick("Timestamp Mode")
+                    .blurb("Which clock buffer timestamps are derived from")
+                    .build(),
                 })
                 .collect()
         });
         PROPERTIES.as_ref()
     }
 
+    fn signals() -> &'static [glib::subclass::Signal] {
+        static SIGNALS: Lazy<Vec<glib::subclass::Signal>> = Lazy::new(|| {
+            vec![glib::subclass::Signal::builder("device-lost")
+                .param_types([String::static_type()])
+                .build()]
+        });
+        SIGNALS.as_ref()
+    }
+
     fn constructed(&self) {
         self.parent_constructed();
         let obj = self.instance();
@@ -205,7 +916,94 @@ impl ObjectImpl for K4a {
                     SettingField::DepthMode => {
                         set_field!(CAT, self, field, enum settings.depth_mode, value)
                     }
+                    SettingField::PassiveIr => {
+                        set_field!(CAT, self, field, settings.passive_ir, value)
+                    }
                     SettingField::Mode => set_field!(CAT, self, field, enum settings.mode, value),
+                    SettingField::ExposureTime => {
+                        set_field!(CAT, self, field, settings.exposure_time, value)
+                    }
+                    SettingField::WhiteBalance => {
+                        let white_balance = value.get::<i32>().unwrap();
+                        if (1..ColorControl::MIN_WHITE_BALANCE).contains(&white_balance) {
+                            gstreamer::warning!(
+                                CAT,
+                                imp: self,
+                                "Ignoring white balance of {}K, it has to be 0 for auto or at least {}K",
+                                white_balance,
+                                ColorControl::MIN_WHITE_BALANCE
+                            );
+                            return;
+                        }
+                        if white_balance % ColorControl::WHITE_BALANCE_STEP != 0 {
+                            gstreamer::warning!(
+                                CAT,
+                                imp: self,
+                                "Ignoring white balance of {}K, it has to be a multiple of {}K",
+                                white_balance,
+                                ColorControl::WHITE_BALANCE_STEP
+                            );
+                            return;
+                        }
+                        set_field!(CAT, self, field, settings.white_balance, (white_balance))
+                    }
+                    SettingField::Brightness => {
+                        set_field!(CAT, self, field, settings.brightness, value)
+                    }
+                    SettingField::Contrast => {
+                        set_field!(CAT, self, field, settings.contrast, value)
+                    }
+                    SettingField::Saturation => {
+                        set_field!(CAT, self, field, settings.saturation, value)
+                    }
+                    SettingField::Sharpness => {
+                        set_field!(CAT, self, field, settings.sharpness, value)
+                    }
+                    SettingField::Gain => set_field!(CAT, self, field, settings.gain, value),
+                    SettingField::BacklightCompensation => {
+                        set_field!(CAT, self, field, settings.backlight_compensation, value)
+                    }
+                    SettingField::PowerlineFrequency => {
+                        set_field!(CAT, self, field, enum settings.powerline_frequency, value)
+                    }
+                    SettingField::DisableStreamingIndicator => {
+                        set_field!(
+                            CAT,
+                            self,
+                            field,
+                            settings.disable_streaming_indicator,
+                            value
+                        )
+                    }
+                    SettingField::CalibrationFile => {
+                        set_field!(CAT, self, field, settings.calibration_file, value)
+                    }
+                    SettingField::MaxBuffers => {
+                        set_field!(CAT, self, field, settings.max_buffers, value)
+                    }
+                    SettingField::Leaky => {
+                        set_field!(CAT, self, field, enum settings.leaky, value)
+                    }
+                    SettingField::Timeout => set_field!(CAT, self, field, settings.timeout, value),
+                    SettingField::SerialNumber => {
+                        set_field!(CAT, self, field, settings.serial_number, value)
+                    }
+                    SettingField::Reconnect => {
+                        set_field!(CAT, self, field, enum settings.reconnect, value)
+                    }
+                    SettingField::ReconnectAttempts => {
+                        set_field!(CAT, self, field, settings.reconnect_attempts, value)
+                    }
+                    SettingField::TimestampMode => {
+                        set_field!(CAT, self, field, enum settings.timestamp_mode, value)
+                    }
+                }
+                if let Some(control) = field.color_control() {
+                    settings.color_controls.insert(control);
+                    let value = settings.color_control(control);
+                    if let Some(camera) = self.state.read().unwrap().camera.as_ref() {
+                        self.apply_color_control(camera.device(), control, value);
+                    }
                 }
             }
             Err(_err) => {
@@ -218,11 +1016,46 @@ impl ObjectImpl for K4a {
         match SettingField::from_str(pspec.name()) {
             Ok(field) => {
                 let settings = self.settings.read().unwrap();
+                // color controls report what the device is actually using whenever it is open
+                let control = field.color_control().map(|control| {
+                    self.state
+                        .read()
+                        .unwrap()
+                        .camera
+                        .as_ref()
+                        .and_then(|camera| control.read(camera.device()).ok())
+                        .unwrap_or_else(|| settings.color_control(control))
+                });
                 match field {
                     SettingField::Fps => settings.fps_mode.to_value(),
                     SettingField::ColorResolution => settings.color_resolution.to_value(),
                     SettingField::DepthMode => settings.depth_mode.to_value(),
+                    SettingField::PassiveIr => settings.passive_ir.to_value(),
                     SettingField::Mode => settings.mode.to_value(),
+                    SettingField::ExposureTime
+                    | SettingField::WhiteBalance
+                    | SettingField::Brightness
+                    | SettingField::Contrast
+                    | SettingField::Saturation
+                    | SettingField::Sharpness
+                    | SettingField::Gain => control.unwrap().to_value(),
+                    SettingField::BacklightCompensation => (control.unwrap() != 0).to_value(),
+                    SettingField::PowerlineFrequency => {
+                        PowerlineFrequency::from_k4a(control.unwrap())
+                            .unwrap_or(settings.powerline_frequency)
+                            .to_value()
+                    }
+                    SettingField::DisableStreamingIndicator => {
+                        settings.disable_streaming_indicator.to_value()
+                    }
+                    SettingField::CalibrationFile => settings.calibration_file.to_value(),
+                    SettingField::MaxBuffers => settings.max_buffers.to_value(),
+                    SettingField::Leaky => settings.leaky.to_value(),
+                    SettingField::Timeout => settings.timeout.to_value(),
+                    SettingField::SerialNumber => settings.serial_number.to_value(),
+                    SettingField::Reconnect => settings.reconnect.to_value(),
+                    SettingField::ReconnectAttempts => settings.reconnect_attempts.to_value(),
+                    SettingField::TimestampMode => settings.timestamp_mode.to_value(),
                 }
             }
             Err(_err) => {
@@ -250,8 +1083,7 @@ impl ElementImpl for K4a {
 
     fn pad_templates() -> &'static [gstreamer::PadTemplate] {
         static PAD_TEMPLATES: Lazy<Vec<gstreamer::PadTemplate>> = Lazy::new(|| {
-            let mut caps = K4a::ir_depth_caps();
-            caps.merge(K4a::color_caps());
+            let caps = StreamConfig::template_caps();
             let pad_template = gstreamer::PadTemplate::new(
                 "src",
                 gstreamer::PadDirection::Src,
@@ -271,8 +1103,12 @@ impl ElementImpl for K4a {
     ) -> Result<gstreamer::StateChangeSuccess, gstreamer::StateChangeError> {
         match transition {
             gstreamer::StateChange::NullToReady => {
-                let mut state = self.state.write().unwrap();
-                let camera = Device::new().map_err(|err| {
+                let settings = self.settings.read().unwrap().clone();
+                let serial_number = match settings.serial_number.as_str() {
+                    "" => None,
+                    serial_number => Some(serial_number),
+                };
+                let camera = self.open_device(serial_number).map_err(|err| {
                     gstreamer::element_imp_error!(
                         self,
                         gstreamer::LibraryError::Init,
@@ -280,11 +1116,14 @@ impl ElementImpl for K4a {
                     );
                     gstreamer::StateChangeError
                 })?;
+                let mut state = self.state.write().unwrap();
+                state.serial_number = camera.serial_number().ok();
                 state.camera.replace(StreamState::Closed(camera));
             }
             gstreamer::StateChange::ReadyToNull => {
                 let mut state = self.state.write().unwrap();
                 state.camera.take();
+                state.serial_number = None;
             }
             _ => (),
         }
@@ -293,171 +1132,115 @@ impl ElementImpl for K4a {
 }
 
 impl BaseSrcImpl for K4a {
-    fn negotiate(&self) -> Result<(), gstreamer::LoggableError> {
-        let cap_used = {
-            self.state.read().unwrap().cap_to_use.clone()
-        };
-        if let Some(caps) = cap_used {
-            let res = self.set_caps(&caps)
-                .map_err(|_| gstreamer::loggable_error!(CAT, "Failed to negotiate caps",));
-            Ok(())
-        } else {
-            Err(gstreamer::loggable_error!(CAT, "Failed to negotiate caps",))
-        }
-    }
-
     fn caps(&self, filter: Option<&gstreamer::Caps>) -> Option<gstreamer::Caps> {
-        let mode = self.settings.read().unwrap().mode;
-        let to_match = match mode {
-            Mode::Color => Self::color_caps(),
-            Mode::Ir | Mode::Depth => Self::ir_depth_caps(),
-        };
-        let mut result = if let Some(filter) = filter {
-            if filter.can_intersect(&to_match) {
-                Some(to_match.intersect(filter))
+        let caps = self.stream_caps();
+        if let Some(filter) = filter {
+            if filter.can_intersect(&caps) {
+                Some(caps.intersect_with_mode(filter, gstreamer::CapsIntersectMode::First))
             } else {
                 None
             }
         } else {
-            Some(to_match)
-        };
-        if let Some(caps) = result.as_mut() {
-            self.add_fields_to_caps(caps.make_mut());
+            Some(caps)
         }
-        result
     }
 
-    fn set_caps(&self, caps: &gstreamer::Caps) -> Result<(), gstreamer::LoggableError> {
-        if let Some(caps) = self.caps(Some(caps)) {
-            self.parent_set_caps(&caps)?;
-            self.state.write().unwrap().cap_to_use.replace(caps);
-            Ok(())
-        } else {
-            Err(gstreamer::loggable_error!(
-                CAT,
-                "Could not set caps to incompatible type"
-            ))
+    fn fixate(&self, caps: gstreamer::Caps) -> gstreamer::Caps {
+        let mode = self.settings.read().unwrap().mode;
+        let legal = caps
+            .iter()
+            .find(|structure| StreamConfig::from_structure(mode, structure).is_some())
+            .map(|structure| structure.to_owned());
+        match legal {
+            Some(structure) => {
+                self.parent_fixate(gstreamer::Caps::builder_full().structure(structure).build())
+            }
+            None => self.parent_fixate(caps),
         }
     }
 
-    fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
+    fn set_caps(&self, caps: &gstreamer::Caps) -> Result<(), gstreamer::LoggableError> {
         let settings = self.settings.read().unwrap().clone();
-        let mut state = self.state.write().unwrap();
-        let Some(format) = state.cap_to_use.as_ref().and_then(|caps| caps.structure(0)).and_then(|structure| structure.get::<String>("format").ok()).map(
-            |format| VideoFormat::from_string(&format)
-        ) else {
-            return Err(gstreamer::error_msg!(
-                gstreamer::CoreError::Negotiation,
-                ("Unknown caps.")
+        let mode = settings.mode;
+        let candidates = caps.structure(0).map_or_else(Vec::new, |structure| {
+            StreamConfig::matching(mode, structure)
+        });
+        // the caps may not tell which depth mode IR comes from, so fall back on the properties
+        let Some(config) = candidates
+            .iter()
+            .find(|config| config.depth_mode == settings.active_depth_mode())
+            .or_else(|| candidates.first())
+            .copied()
+        else {
+            return Err(gstreamer::loggable_error!(
+                CAT,
+                "Caps {} do not describe a supported device configuration",
+                caps
             ));
         };
-        gstreamer::info!(CAT, imp: self, "Starting camera stream",);
-        let (color_format, color_resolution, depth_mode) = match settings.mode {
-            Mode::Color => {
-                let format = match format {
-                    VideoFormat::Bgra => {
-                        libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_BGRA32
-                    }
-                    VideoFormat::Yuy2 => {
-                        libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_YUY2
-                    }
-                    VideoFormat::Nv12 => {
-                        libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_NV12
-                    }
-                    _ => unreachable!(),
-                };
-                let resolution = match settings.color_resolution {
-                    ColorResolution::Res720P => {
-                        libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_720P
-                    }
-                    ColorResolution::Res1080P => {
-                        libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_1080P
-                    }
-                    ColorResolution::Res1440P => {
-                        libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_1440P
-                    }
-                    ColorResolution::Res1536P => {
-                        libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_1536P
-                    }
-                    ColorResolution::Res2160P => {
-                        libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_2160P
-                    }
-                    ColorResolution::Res3072P => {
-                        libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_3072P
-                    }
-                };
-                let depth_mode = libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_OFF;
-                (format, resolution, depth_mode)
-            }
-            Mode::Ir => {
-                let format = libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_MJPG; // default value for disabled
-                let resolution = libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_OFF;
-                let depth_mode = libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_PASSIVE_IR;
-                (format, resolution, depth_mode)
-            }
-            Mode::Depth => {
-                let format = libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_MJPG; // default value for disabled
-                let resolution = libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_OFF;
-                let depth_mode = match settings.depth_mode {
-                    DepthMode::NormalFov2x2Binned => {
-                        libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_NFOV_2X2BINNED
-                    }
-                    DepthMode::NormalFovUnbinned => {
-                        libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_NFOV_UNBINNED
-                    }
-                    DepthMode::WideFov2x2Binned => {
-                        libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_WFOV_2X2BINNED
-                    }
-                    DepthMode::WideFovUnbinned => {
-                        libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_NFOV_UNBINNED
-                    }
-                };
-                (format, resolution, depth_mode)
-            }
-        };
-        let camera_fps = match settings.fps_mode {
-            FpsMode::Fps5 => libk4a::sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_5,
-            FpsMode::Fps15 => libk4a::sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_15,
-            FpsMode::Fps30 => libk4a::sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_30,
-        };
-        let stream_state = state.camera.take();
-        match stream_state {
-            Some(StreamState::Closed(camera)) => {
-                state.camera = Some(StreamState::Open(
-                    camera
-                        .start_cameras(libk4a::sys::k4a_device_configuration_t {
-                            color_format,
-                            color_resolution,
-                            depth_mode,
-                            camera_fps,
-                            synchronized_images_only: Default::default(),
-                            depth_delay_off_color_usec: Default::default(),
-                            wired_sync_mode:
-                                libk4a::sys::k4a_wired_sync_mode_t::K4A_WIRED_SYNC_MODE_STANDALONE,
-                            subordinate_delay_off_master_usec: Default::default(),
-                            disable_streaming_indicator: Default::default(),
-                        })
-                        .map_err(|err| {
-                            gstreamer::error_msg!(
-                                gstreamer::CoreError::Failed,
-                                ("Cannot open device to begin streaming. Error: {:#?}", err)
-                            )
-                        })?,
-                ))
-            }
-            stream_state => {
-                state.camera = stream_state;
-                return Err(gstreamer::error_msg!(
-                    gstreamer::LibraryError::Init,
-                    ("Camera not initialized and ready to start streaming.")
+
+        let mut state = self.state.write().unwrap();
+        if state.config == Some(config) {
+            return Ok(());
+        }
+        gstreamer::info!(
+            CAT,
+            imp: self,
+            "Starting camera stream with {:?}",
+            config
+        );
+        if let Some(capture_thread) = state.capture_thread.take() {
+            capture_thread.stop(&self.captures);
+        }
+        let device = match state.camera.take() {
+            Some(StreamState::Open(stream)) => stream.stop_cameras(),
+            Some(StreamState::Closed(device)) => device,
+            None => {
+                return Err(gstreamer::loggable_error!(
+                    CAT,
+                    "Camera not initialized and ready to start streaming."
                 ));
             }
         };
+        let mut device_configuration = config.device_configuration();
+        device_configuration.disable_streaming_indicator = settings.disable_streaming_indicator;
+        if let Err(err) = self.start_stream(&mut state, device, device_configuration, &settings) {
+            state.config = None;
+            return Err(gstreamer::loggable_error!(CAT, "{}", err));
+        }
+        state.config = Some(config);
+        let calibration = state.calibration.clone();
+        drop(state);
+
+        if let Some(calibration) = calibration {
+            let message = gstreamer::message::Element::builder(meta::calibration_structure(
+                &calibration.calibration,
+            ))
+            .src(&*self.instance())
+            .build();
+            if self.instance().post_message(message).is_err() {
+                gstreamer::warning!(CAT, imp: self, "Could not post calibration message");
+            }
+        }
         Ok(())
     }
 
+    fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
+        let state = self.state.read().unwrap();
+        match state.camera {
+            Some(StreamState::Closed(_)) => Ok(()),
+            _ => Err(gstreamer::error_msg!(
+                gstreamer::LibraryError::Init,
+                ("Camera not initialized and ready to start streaming.")
+            )),
+        }
+    }
+
     fn stop(&self) -> Result<(), gstreamer::ErrorMessage> {
         let mut state = self.state.write().unwrap();
+        if let Some(capture_thread) = state.capture_thread.take() {
+            capture_thread.stop(&self.captures);
+        }
         match state.camera.take() {
             Some(StreamState::Open(stream)) => {
                 state.camera = Some(StreamState::Closed(stream.stop_cameras()));
@@ -466,37 +1249,44 @@ impl BaseSrcImpl for K4a {
                 state.camera = stream_state;
             }
         }
+        state.config = None;
+        state.calibration = None;
+        state.device_configuration = None;
+        state.discont = false;
+        state.last_end = None;
+        state.latency = None;
+        self.latency_tracker.reset();
+        Ok(())
+    }
+
+    fn unlock(&self) -> Result<(), gstreamer::ErrorMessage> {
+        self.captures.set_flushing(true);
+        Ok(())
+    }
+
+    fn unlock_stop(&self) -> Result<(), gstreamer::ErrorMessage> {
+        self.captures.set_flushing(false);
         Ok(())
     }
 
     fn query(&self, query: &mut gstreamer::QueryRef) -> bool {
         match query.view_mut() {
             gstreamer::QueryViewMut::Latency(latency) => {
-                latency.set(
-                    true,
-                    gstreamer::ClockTime::from_nseconds(
-                        Duration::from_secs_f64(
-                            1001f64 / (self.settings.read().unwrap().fps_mode.fps() * 1000) as f64,
-                        )
-                        .as_nanos() as u64,
-                    ),
-                    None,
-                );
-                true
-            }
-            gstreamer::QueryViewMut::Caps(caps_query) => {
-                let mut result = if let Some(caps) =
-                    self.caps(caps_query.filter().map(|cap| cap.to_owned()).as_ref())
-                {
-                    if self.set_caps(&caps).is_ok() {
-                        self.state.read().unwrap().cap_to_use.clone()
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+                let (config, measured) = {
+                    let state = self.state.read().unwrap();
+                    (state.config, state.latency)
                 };
-                caps_query.set_result(result.as_ref());
+                // one frame period until frames were actually timestamped
+                let min = measured.unwrap_or_else(|| {
+                    let fps = config
+                        .map(|config| config.fps_mode)
+                        .unwrap_or(self.settings.read().unwrap().fps_mode)
+                        .fps();
+                    gstreamer::ClockTime::from_nseconds(
+                        Duration::from_secs_f64(1001f64 / (fps * 1000) as f64).as_nanos() as u64,
+                    )
+                });
+                latency.set(true, min, None);
                 true
             }
             _ => BaseSrcImplExt::parent_query(self, query),
@@ -509,27 +1299,59 @@ impl PushSrcImpl for K4a {
         &self,
         _buffer: Option<&mut gstreamer::BufferRef>,
     ) -> Result<CreateSuccess, gstreamer::FlowError> {
-        let (mode, fps) = {
+        let (timeout, reconnect, timestamp_mode) = {
             let settings = self.settings.read().unwrap();
-            (settings.mode, settings.fps_mode.fps())
+            (
+                settings.timeout,
+                settings.reconnect,
+                settings.timestamp_mode,
+            )
         };
-        let image_type = match mode {
-            Mode::Color => libk4a::ImageType::Color,
-            Mode::Ir => libk4a::ImageType::Infrared,
-            Mode::Depth => libk4a::ImageType::Depth,
+        let Some(config) = self.state.read().unwrap().config else {
+            return Err(gstreamer::FlowError::NotNegotiated);
         };
-        let mut state = self.state.write().unwrap();
-        let Some(StreamState::Open(stream)) = state.camera.as_mut() else {
-            return Err(gstreamer::FlowError::NotLinked);
+        let fps = config.fps_mode.fps();
+        let image_type = config.mode.image_type();
+        let CapturedFrame {
+            capture,
+            sequence,
+            arrival,
+        } = loop {
+            match self.captures.pop(None) {
+                PopResult::Frame(Ok(captured)) => break captured,
+                PopResult::Frame(Err(libk4a::sys::k4a_wait_result_t::K4A_WAIT_RESULT_TIMEOUT)) => {
+                    gstreamer::element_imp_error!(
+                        self,
+                        gstreamer::ResourceError::Read,
+                        ("Device stalled: no capture within {} ms.", timeout)
+                    );
+                    return Err(gstreamer::FlowError::Error);
+                }
+                PopResult::Frame(Err(libk4a::sys::k4a_wait_result_t::K4A_WAIT_RESULT_FAILED))
+                    if reconnect == ReconnectPolicy::Always =>
+                {
+                    self.reconnect()?;
+                }
+                PopResult::Frame(Err(err)) => {
+                    gstreamer::element_imp_error!(
+                        self,
+                        gstreamer::CoreError::Failed,
+                        ("Could not capture from device. Error: {:#?}", err)
+                    );
+                    return Err(gstreamer::FlowError::Error);
+                }
+                PopResult::Flushing | PopResult::Timeout => {
+                    return Err(gstreamer::FlowError::Flushing);
+                }
+            }
+        };
+        let (calibration, discont) = {
+            let mut state = self.state.write().unwrap();
+            (
+                state.calibration.clone(),
+                std::mem::take(&mut state.discont),
+            )
         };
-        let capture = stream.get_capture().map_err(|err| {
-            gstreamer::element_imp_error!(
-                self,
-                gstreamer::CoreError::Failed,
-                ("Could not capture from device. Error: {:#?}", err)
-            );
-            gstreamer::FlowError::Error
-        })?;
 
         let Some(image) = capture.get_image(image_type) else {
             gstreamer::element_imp_error!(
@@ -540,56 +1362,74 @@ impl PushSrcImpl for K4a {
             return Err(gstreamer::FlowError::Error);
         };
 
-        let Some(image_bytes) = image.buffer() else {
+        if image.buffer().is_none() {
             gstreamer::element_imp_error!(
                 self,
                 gstreamer::CoreError::Failed,
                 ("Could not get raw pixels from image.")
             );
             return Err(gstreamer::FlowError::Error);
+        }
+        let timestamp = self.timestamp(&image, timestamp_mode, arrival);
+        let frame_info = FrameInfo {
+            sequence,
+            device_timestamp: gstreamer::ClockTime::from_nseconds(
+                image.get_device_timestamp().as_nanos() as u64,
+            ),
+            system_timestamp: gstreamer::ClockTime::from_nseconds(
+                image.get_system_timestamp().as_nanos() as u64,
+            ),
+            exposure: gstreamer::ClockTime::from_nseconds(image.get_exposure().as_nanos() as u64),
+            white_balance: image.get_white_balance(),
+            iso_speed: image.get_iso_speed(),
+            temperature: capture.get_temperature(),
         };
-
-        let mut buffer = gstreamer::Buffer::with_size(image_bytes.len()).map_err(|err| {
+        let mut buffer = config.buffer(image).map_err(|err| {
             gstreamer::element_imp_error!(
                 self,
                 gstreamer::CoreError::Failed,
-                ("Could not allocate buffer. Error: {:#?}", err)
+                ("Could not add video meta. Error: {:#?}", err)
             );
             gstreamer::FlowError::Error
         })?;
 
-        buffer
-            .make_mut()
-            .map_writable()
-            .map_err(|err| {
-                gstreamer::element_imp_error!(
-                    self,
-                    gstreamer::CoreError::Failed,
-                    ("Could not make buffer writable. Error: {:#?}", err)
-                );
-                gstreamer::FlowError::Error
-            })?
-            .copy_from_slice(image_bytes);
-
-        let start_timestamp = self
-            .frame_data
-            .start_timestamp(image.get_system_timestamp());
-
-        let pts = image.get_system_timestamp() - start_timestamp;
+        let base_time = self.instance().base_time();
+        let pts = timestamp.zip(base_time).map(|(timestamp, base_time)| {
+            gstreamer::ClockTime::from_nseconds(
+                timestamp.nseconds().saturating_sub(base_time.nseconds()),
+            )
+        });
         let duration = Duration::from_secs_f64(1001f64 / (fps * 1000) as f64);
+        if let Some((now, timestamp)) = self
+            .instance()
+            .clock()
+            .and_then(|clock| clock.time())
+            .zip(timestamp)
+        {
+            self.measure_latency(Duration::from_nanos(
+                now.nseconds().saturating_sub(timestamp.nseconds()),
+            ));
+        }
 
-        buffer
-            .make_mut()
-            .set_pts(gstreamer::ClockTime::from_nseconds(pts.as_nanos() as u64));
+        buffer.make_mut().set_pts(pts);
         buffer.make_mut().set_dts(None);
-        buffer
-            .make_mut()
-            .set_offset(self.frame_data.add_frame(()) as u64);
+        buffer.make_mut().set_offset(sequence);
         buffer
             .make_mut()
             .set_duration(gstreamer::ClockTime::from_nseconds(
                 duration.as_nanos() as u64
             ));
+        if let Some(calibration) = calibration {
+            K4aCalibrationMeta::add(buffer.make_mut(), calibration);
+        }
+        K4aFrameMeta::add(buffer.make_mut(), frame_info);
+        if discont {
+            buffer.make_mut().set_flags(gstreamer::BufferFlags::DISCONT);
+        }
+        self.state.write().unwrap().last_end = buffer
+            .pts()
+            .zip(buffer.duration())
+            .map(|(pts, duration)| pts + duration);
 
         gstreamer::debug!(
             CAT,
@@ -606,85 +1446,45 @@ impl PushSrcImpl for K4a {
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
 #[repr(u32)]
-#[enum_type(name = "GstK4aFpsMode")]
-enum FpsMode {
-    Fps5,
-    Fps15,
-    Fps30,
+#[enum_type(name = "GstK4aPowerlineFrequency")]
+enum PowerlineFrequency {
+    Hz50,
+    Hz60,
 }
 
-impl FpsMode {
-    fn fps(&self) -> i32 {
+impl PowerlineFrequency {
+    fn to_k4a(self) -> i32 {
         match self {
-            FpsMode::Fps5 => 5,
-            FpsMode::Fps15 => 15,
-            FpsMode::Fps30 => 30,
+            Self::Hz50 => 1,
+            Self::Hz60 => 2,
         }
     }
-}
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
-#[repr(u32)]
-#[enum_type(name = "GstK4aColorResolution")]
-enum ColorResolution {
-    Res720P,
-    Res1080P,
-    Res1440P,
-    Res1536P,
-    Res2160P,
-    Res3072P,
-}
-
-impl ColorResolution {
-    fn dimensions(&self) -> (i32, i32) {
-        match self {
-            Self::Res720P => (1280, 720),
-            Self::Res1080P => (1920, 1080),
-            Self::Res1440P => (2560, 1440),
-            Self::Res1536P => (2048, 1536),
-            Self::Res2160P => (3840, 2160),
-            Self::Res3072P => (4096, 3072),
-        }
-    }
-}
-
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
-#[repr(u32)]
-#[enum_type(name = "GstK4aDepthMode")]
-enum DepthMode {
-    NormalFov2x2Binned,
-    NormalFovUnbinned,
-    WideFov2x2Binned,
-    WideFovUnbinned,
-}
-
-impl DepthMode {
-    fn dimensions(&self) -> (i32, i32) {
-        match self {
-            Self::NormalFov2x2Binned => (320, 288),
-            Self::NormalFovUnbinned => (640, 576),
-            Self::WideFov2x2Binned => (512, 512),
-            Self::WideFovUnbinned => (1024, 1024),
+    fn from_k4a(value: i32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Hz50),
+            2 => Some(Self::Hz60),
+            _ => None,
         }
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
 #[repr(u32)]
-#[enum_type(name = "GstK4aColorFormat")]
-enum ColorFormat {
-    Nv12,
-    Yuy2,
-    Bgra32,
-    Depth16,
-    Ir16,
+#[enum_type(name = "GstK4aReconnectPolicy")]
+enum ReconnectPolicy {
+    Never,
+    Always,
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
 #[repr(u32)]
-#[enum_type(name = "GstK4aMode")]
-enum Mode {
-    Color,
-    Ir,
-    Depth,
+#[enum_type(name = "GstK4aTimestampMode")]
+enum TimestampMode {
+    /// Device hardware timestamps, placed on the host clock by the shortest recent transfer delay.
+    Device,
+    /// Host timestamps taken by the SDK when the capture was received.
+    System,
+    /// Pipeline clock time at which the capture was handed to the element.
+    Arrival,
 }
@@ -1,16 +1,30 @@
-use std::{mem::MaybeUninit, ptr::NonNull, sync::Arc, time::Duration};
+use std::{
+    ffi::{c_void, CString},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    ptr::NonNull,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+pub use sys::k4a_calibration_t as Calibration;
+pub use sys::k4a_color_control_command_t as ColorControlCommand;
+pub use sys::k4a_color_control_mode_t as ColorControlMode;
 pub use sys::k4a_device_configuration_t as DeviceConfiguration;
+pub use sys::k4a_imu_sample_t as ImuSample;
+pub use sys::k4a_record_configuration_t as RecordConfiguration;
 
 struct DeviceWrapper {
     device: NonNull<sys::_k4a_device_t>,
 }
 
 impl DeviceWrapper {
-    unsafe fn new() -> Result<Self, sys::k4a_result_t> {
+    unsafe fn new(index: u32) -> Result<Self, sys::k4a_result_t> {
         let mut device = MaybeUninit::uninit();
 
-        match sys::k4a_device_open(sys::K4A_DEVICE_DEFAULT, device.as_mut_ptr()) {
+        match sys::k4a().k4a_device_open(index, device.as_mut_ptr()) {
             sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(Self {
                 device: NonNull::new(device.assume_init()).unwrap(),
             }),
@@ -18,26 +32,130 @@ impl DeviceWrapper {
         }
     }
 
+    unsafe fn serial_number(&self) -> Result<String, sys::k4a_buffer_result_t> {
+        let mut size = 0;
+        match sys::k4a().k4a_device_get_serialnum(
+            self.device.as_ptr(),
+            std::ptr::null_mut(),
+            &mut size as *mut _,
+        ) {
+            sys::k4a_buffer_result_t::K4A_BUFFER_RESULT_TOO_SMALL => {}
+            err => return Err(err),
+        }
+        let mut serial = vec![0u8; size];
+        match sys::k4a().k4a_device_get_serialnum(
+            self.device.as_ptr(),
+            serial.as_mut_ptr() as *mut _,
+            &mut size as *mut _,
+        ) {
+            sys::k4a_buffer_result_t::K4A_BUFFER_RESULT_SUCCEEDED => {
+                let end = serial.iter().position(|c| *c == 0).unwrap_or(serial.len());
+                serial.truncate(end);
+                Ok(String::from_utf8_lossy(&serial).into_owned())
+            }
+            err => Err(err),
+        }
+    }
+
     unsafe fn start_cameras(
         &self,
         mut config: sys::k4a_device_configuration_t,
     ) -> Result<(), sys::k4a_result_t> {
-        match sys::k4a_device_start_cameras(self.device.as_ptr(), &mut config as *mut _) {
+        match sys::k4a().k4a_device_start_cameras(self.device.as_ptr(), &mut config as *mut _) {
             sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(()),
             err => Err(err),
         }
     }
 
     unsafe fn stop_cameras(&self) {
-        sys::k4a_device_stop_cameras(self.device.as_ptr());
+        sys::k4a().k4a_device_stop_cameras(self.device.as_ptr());
     }
 
-    unsafe fn get_capture(&self) -> Result<NonNull<sys::_k4a_capture_t>, sys::k4a_wait_result_t> {
+    unsafe fn set_color_control(
+        &self,
+        command: ColorControlCommand,
+        mode: ColorControlMode,
+        value: i32,
+    ) -> Result<(), sys::k4a_result_t> {
+        match sys::k4a().k4a_device_set_color_control(self.device.as_ptr(), command, mode, value) {
+            sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(()),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_color_control(
+        &self,
+        command: ColorControlCommand,
+    ) -> Result<(ColorControlMode, i32), sys::k4a_result_t> {
+        let mut mode = MaybeUninit::uninit();
+        let mut value = MaybeUninit::uninit();
+        match sys::k4a().k4a_device_get_color_control(
+            self.device.as_ptr(),
+            command,
+            mode.as_mut_ptr(),
+            value.as_mut_ptr(),
+        ) {
+            sys::k4a_result_t::K4A_RESULT_SUCCEEDED => {
+                Ok((mode.assume_init(), value.assume_init()))
+            }
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_calibration(
+        &self,
+        depth_mode: sys::k4a_depth_mode_t,
+        color_resolution: sys::k4a_color_resolution_t,
+    ) -> Result<Calibration, sys::k4a_result_t> {
+        let mut calibration = MaybeUninit::uninit();
+        match sys::k4a().k4a_device_get_calibration(
+            self.device.as_ptr(),
+            depth_mode,
+            color_resolution,
+            calibration.as_mut_ptr(),
+        ) {
+            sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(calibration.assume_init()),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_raw_calibration(&self) -> Result<Vec<u8>, sys::k4a_buffer_result_t> {
+        let mut size = 0;
+        match sys::k4a().k4a_device_get_raw_calibration(
+            self.device.as_ptr(),
+            std::ptr::null_mut(),
+            &mut size as *mut _,
+        ) {
+            sys::k4a_buffer_result_t::K4A_BUFFER_RESULT_TOO_SMALL => {}
+            err => return Err(err),
+        }
+        let mut data = vec![0u8; size];
+        match sys::k4a().k4a_device_get_raw_calibration(
+            self.device.as_ptr(),
+            data.as_mut_ptr(),
+            &mut size as *mut _,
+        ) {
+            sys::k4a_buffer_result_t::K4A_BUFFER_RESULT_SUCCEEDED => {
+                data.truncate(size);
+                // the JSON is stored null terminated
+                if data.last() == Some(&0) {
+                    data.pop();
+                }
+                Ok(data)
+            }
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_capture(
+        &self,
+        timeout: Duration,
+    ) -> Result<NonNull<sys::_k4a_capture_t>, sys::k4a_wait_result_t> {
         let mut handle = MaybeUninit::uninit();
-        match sys::k4a_device_get_capture(
+        match sys::k4a().k4a_device_get_capture(
             self.device.as_ptr(),
             handle.as_mut_ptr(),
-            sys::K4A_WAIT_INFINITE,
+            timeout.as_millis().min(i32::MAX as u128) as i32,
         ) {
             sys::k4a_wait_result_t::K4A_WAIT_RESULT_SUCCEEDED => {
                 Ok(NonNull::new(handle.assume_init()).unwrap())
@@ -50,7 +168,7 @@ impl DeviceWrapper {
 impl Drop for DeviceWrapper {
     fn drop(&mut self) {
         unsafe {
-            sys::k4a_device_close(self.device.as_ptr());
+            sys::k4a().k4a_device_close(self.device.as_ptr());
         }
     }
 }
@@ -65,26 +183,43 @@ pub struct Device {
 
 impl Device {
     pub fn new() -> Result<Self, sys::k4a_result_t> {
+        Self::open(sys::K4A_DEVICE_DEFAULT)
+    }
+
+    pub fn open(index: u32) -> Result<Self, sys::k4a_result_t> {
         Ok(Self {
-            inner: Arc::new(unsafe { DeviceWrapper::new()? }),
+            inner: Arc::new(unsafe { DeviceWrapper::new(index)? }),
         })
     }
 
+    /// Opens the first device that is not already in use and has the given serial number.
+    pub fn open_serial(serial_number: &str) -> Result<Self, sys::k4a_result_t> {
+        (0..Self::installed_count())
+            .filter_map(|index| Self::open(index).ok())
+            .find(|device| {
+                device
+                    .serial_number()
+                    .map_or(false, |serial| serial == serial_number)
+            })
+            .ok_or(sys::k4a_result_t::K4A_RESULT_FAILED)
+    }
+
+    pub fn installed_count() -> u32 {
+        unsafe { sys::k4a().k4a_device_get_installed_count() }
+    }
+
+    pub fn serial_number(&self) -> Result<String, sys::k4a_buffer_result_t> {
+        unsafe { self.inner.serial_number() }
+    }
+
     pub fn start_cameras(
         self,
         config: sys::k4a_device_configuration_t,
-    ) -> Result<Stream, sys::k4a_result_t> {
-        unsafe {
-            self.inner.start_cameras(config)?;
+    ) -> Result<Stream, (Self, sys::k4a_result_t)> {
+        match unsafe { self.inner.start_cameras(config) } {
+            Ok(()) => Ok(Stream::new(self)),
+            Err(err) => Err((self, err)),
         }
-        Ok(Stream::new(self))
-    }
-
-    fn get_capture(&self) -> Result<Capture, sys::k4a_wait_result_t> {
-        Ok(Capture::new(CaptureWrapper::new(
-            self.inner.clone(),
-            unsafe { self.inner.get_capture()? },
-        )))
     }
 
     fn stop_cameras(&self) {
@@ -92,6 +227,35 @@ impl Device {
             self.inner.stop_cameras();
         }
     }
+
+    pub fn get_calibration(
+        &self,
+        depth_mode: sys::k4a_depth_mode_t,
+        color_resolution: sys::k4a_color_resolution_t,
+    ) -> Result<Calibration, sys::k4a_result_t> {
+        unsafe { self.inner.get_calibration(depth_mode, color_resolution) }
+    }
+
+    /// The factory calibration as the JSON blob stored on the device.
+    pub fn get_raw_calibration(&self) -> Result<Vec<u8>, sys::k4a_buffer_result_t> {
+        unsafe { self.inner.get_raw_calibration() }
+    }
+
+    pub fn set_color_control(
+        &self,
+        command: ColorControlCommand,
+        mode: ColorControlMode,
+        value: i32,
+    ) -> Result<(), sys::k4a_result_t> {
+        unsafe { self.inner.set_color_control(command, mode, value) }
+    }
+
+    pub fn get_color_control(
+        &self,
+        command: ColorControlCommand,
+    ) -> Result<(ColorControlMode, i32), sys::k4a_result_t> {
+        unsafe { self.inner.get_color_control(command) }
+    }
 }
 
 pub struct Stream {
@@ -105,8 +269,15 @@ impl Stream {
         }
     }
 
-    pub fn get_capture(&self) -> Result<Capture, sys::k4a_wait_result_t> {
-        self.device.as_ref().unwrap().get_capture()
+    /// A handle that can wait for captures from another thread while the stream is running.
+    pub fn capturer(&self) -> Capturer {
+        Capturer {
+            inner: self.device().inner.clone(),
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        self.device.as_ref().unwrap()
     }
 
     pub fn stop_cameras(mut self) -> Device {
@@ -124,24 +295,553 @@ impl Drop for Stream {
     }
 }
 
+pub struct Capturer {
+    inner: Arc<DeviceWrapper>,
+}
+
+impl Capturer {
+    pub fn get_capture(&self, timeout: Duration) -> Result<Capture, sys::k4a_wait_result_t> {
+        Ok(Capture::new(CaptureWrapper::new(
+            self.inner.clone(),
+            unsafe { self.inner.get_capture(timeout)? },
+        )))
+    }
+}
+
+/// Parses a raw calibration JSON blob for the given camera modes.
+pub fn calibration_from_raw(
+    raw: &[u8],
+    depth_mode: sys::k4a_depth_mode_t,
+    color_resolution: sys::k4a_color_resolution_t,
+) -> Result<Calibration, sys::k4a_result_t> {
+    // libk4a expects the blob null terminated
+    let mut raw = raw.to_vec();
+    if raw.last() != Some(&0) {
+        raw.push(0);
+    }
+    let mut calibration = MaybeUninit::uninit();
+    unsafe {
+        to_result(sys::k4a().k4a_calibration_get_from_raw(
+            raw.as_mut_ptr() as *mut _,
+            raw.len(),
+            depth_mode,
+            color_resolution,
+            calibration.as_mut_ptr(),
+        ))?;
+        Ok(calibration.assume_init())
+    }
+}
+
+/// An image over memory owned by the caller, which must outlive it.
+pub struct ImageRef<'a> {
+    image: NonNull<sys::_k4a_image_t>,
+    _data: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> ImageRef<'a> {
+    /// Wraps memory that libk4a only reads from.
+    pub fn new(
+        format: sys::k4a_image_format_t,
+        width: u32,
+        height: u32,
+        stride: u32,
+        data: &'a [u8],
+    ) -> Result<Self, sys::k4a_result_t> {
+        unsafe {
+            Self::wrap(
+                format,
+                width,
+                height,
+                stride,
+                data.as_ptr() as *mut u8,
+                data.len(),
+            )
+        }
+    }
+
+    pub fn new_mut(
+        format: sys::k4a_image_format_t,
+        width: u32,
+        height: u32,
+        stride: u32,
+        data: &'a mut [u8],
+    ) -> Result<Self, sys::k4a_result_t> {
+        unsafe { Self::wrap(format, width, height, stride, data.as_mut_ptr(), data.len()) }
+    }
+
+    unsafe fn wrap(
+        format: sys::k4a_image_format_t,
+        width: u32,
+        height: u32,
+        stride: u32,
+        data: *mut u8,
+        size: usize,
+    ) -> Result<Self, sys::k4a_result_t> {
+        let mut image = MaybeUninit::uninit();
+        to_result(sys::k4a().k4a_image_create_from_buffer(
+            format,
+            width as i32,
+            height as i32,
+            stride as i32,
+            data,
+            size,
+            None,
+            std::ptr::null_mut(),
+            image.as_mut_ptr(),
+        ))?;
+        Ok(Self {
+            image: NonNull::new(image.assume_init()).unwrap(),
+            _data: PhantomData,
+        })
+    }
+}
+
+impl Drop for ImageRef<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            sys::k4a().k4a_image_release(self.image.as_ptr());
+        }
+    }
+}
+
+/// Reprojects images between the depth and color cameras of one calibration.
+pub struct Transformation {
+    transformation: NonNull<sys::_k4a_transformation_t>,
+}
+
+impl Transformation {
+    pub fn new(calibration: &Calibration) -> Result<Self, sys::k4a_result_t> {
+        NonNull::new(unsafe { sys::k4a().k4a_transformation_create(calibration) })
+            .map(|transformation| Self { transformation })
+            .ok_or(sys::k4a_result_t::K4A_RESULT_FAILED)
+    }
+
+    /// Writes a depth image in the geometry of the color camera into `transformed`, which has
+    /// the color resolution.
+    pub fn depth_image_to_color_camera(
+        &self,
+        depth: &ImageRef,
+        transformed: &mut ImageRef,
+    ) -> Result<(), sys::k4a_result_t> {
+        unsafe {
+            to_result(sys::k4a().k4a_transformation_depth_image_to_color_camera(
+                self.transformation.as_ptr(),
+                depth.image.as_ptr(),
+                transformed.image.as_ptr(),
+            ))
+        }
+    }
+
+    /// Writes a BGRA color image in the geometry of the depth camera into `transformed`, which
+    /// has the depth resolution.
+    pub fn color_image_to_depth_camera(
+        &self,
+        depth: &ImageRef,
+        color: &ImageRef,
+        transformed: &mut ImageRef,
+    ) -> Result<(), sys::k4a_result_t> {
+        unsafe {
+            to_result(sys::k4a().k4a_transformation_color_image_to_depth_camera(
+                self.transformation.as_ptr(),
+                depth.image.as_ptr(),
+                color.image.as_ptr(),
+                transformed.image.as_ptr(),
+            ))
+        }
+    }
+}
+
+impl Drop for Transformation {
+    fn drop(&mut self) {
+        unsafe {
+            sys::k4a().k4a_transformation_destroy(self.transformation.as_ptr());
+        }
+    }
+}
+
+unsafe impl Send for Transformation {}
+
+struct PlaybackWrapper {
+    playback: NonNull<sys::_k4a_playback_t>,
+}
+
+impl PlaybackWrapper {
+    unsafe fn open(path: &Path) -> Result<Self, sys::k4a_result_t> {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return Err(sys::k4a_result_t::K4A_RESULT_FAILED);
+        };
+        let mut playback = MaybeUninit::uninit();
+        match sys::k4arecord().k4a_playback_open(path.as_ptr(), playback.as_mut_ptr()) {
+            sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(Self {
+                playback: NonNull::new(playback.assume_init()).unwrap(),
+            }),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_calibration(&self) -> Result<Calibration, sys::k4a_result_t> {
+        let mut calibration = MaybeUninit::uninit();
+        match sys::k4arecord()
+            .k4a_playback_get_calibration(self.playback.as_ptr(), calibration.as_mut_ptr())
+        {
+            sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(calibration.assume_init()),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_raw_calibration(&self) -> Result<Vec<u8>, sys::k4a_buffer_result_t> {
+        let mut size = 0;
+        match sys::k4arecord().k4a_playback_get_raw_calibration(
+            self.playback.as_ptr(),
+            std::ptr::null_mut(),
+            &mut size as *mut _,
+        ) {
+            sys::k4a_buffer_result_t::K4A_BUFFER_RESULT_TOO_SMALL => {}
+            err => return Err(err),
+        }
+        let mut data = vec![0u8; size];
+        match sys::k4arecord().k4a_playback_get_raw_calibration(
+            self.playback.as_ptr(),
+            data.as_mut_ptr(),
+            &mut size as *mut _,
+        ) {
+            sys::k4a_buffer_result_t::K4A_BUFFER_RESULT_SUCCEEDED => {
+                data.truncate(size);
+                if data.last() == Some(&0) {
+                    data.pop();
+                }
+                Ok(data)
+            }
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_record_configuration(&self) -> Result<RecordConfiguration, sys::k4a_result_t> {
+        let mut config = MaybeUninit::uninit();
+        match sys::k4arecord()
+            .k4a_playback_get_record_configuration(self.playback.as_ptr(), config.as_mut_ptr())
+        {
+            sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(config.assume_init()),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn get_next_capture(
+        &self,
+    ) -> Result<NonNull<sys::_k4a_capture_t>, sys::k4a_stream_result_t> {
+        let mut handle = MaybeUninit::uninit();
+        match sys::k4arecord()
+            .k4a_playback_get_next_capture(self.playback.as_ptr(), handle.as_mut_ptr())
+        {
+            sys::k4a_stream_result_t::K4A_STREAM_RESULT_SUCCEEDED => {
+                Ok(NonNull::new(handle.assume_init()).unwrap())
+            }
+            err => Err(err),
+        }
+    }
+
+    unsafe fn seek_device_timestamp(&self, timestamp: Duration) -> Result<(), sys::k4a_result_t> {
+        match sys::k4arecord().k4a_playback_seek_timestamp(
+            self.playback.as_ptr(),
+            timestamp.as_micros().min(i64::MAX as u128) as i64,
+            sys::k4a_playback_seek_origin_t::K4A_PLAYBACK_SEEK_DEVICE_TIME,
+        ) {
+            sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(()),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn recording_length(&self) -> Duration {
+        Duration::from_micros(
+            sys::k4arecord().k4a_playback_get_recording_length_usec(self.playback.as_ptr()),
+        )
+    }
+}
+
+impl Drop for PlaybackWrapper {
+    fn drop(&mut self) {
+        unsafe {
+            sys::k4arecord().k4a_playback_close(self.playback.as_ptr());
+        }
+    }
+}
+
+unsafe impl Send for PlaybackWrapper {}
+
+/// An opened Azure Kinect recording. The handle is not thread safe, so every call goes through a
+/// lock.
+pub struct Playback {
+    inner: Arc<Mutex<PlaybackWrapper>>,
+}
+
+impl Playback {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, sys::k4a_result_t> {
+        Ok(Self {
+            inner: Arc::new(Mutex::new(unsafe { PlaybackWrapper::open(path.as_ref())? })),
+        })
+    }
+
+    pub fn get_calibration(&self) -> Result<Calibration, sys::k4a_result_t> {
+        unsafe { self.inner.lock().unwrap().get_calibration() }
+    }
+
+    /// The calibration as the JSON blob stored in the recording.
+    pub fn get_raw_calibration(&self) -> Result<Vec<u8>, sys::k4a_buffer_result_t> {
+        unsafe { self.inner.lock().unwrap().get_raw_calibration() }
+    }
+
+    pub fn get_record_configuration(&self) -> Result<RecordConfiguration, sys::k4a_result_t> {
+        unsafe { self.inner.lock().unwrap().get_record_configuration() }
+    }
+
+    /// Reads the next capture, failing with `K4A_STREAM_RESULT_EOF` at the end of the recording.
+    pub fn get_next_capture(&self) -> Result<Capture, sys::k4a_stream_result_t> {
+        let capture = unsafe { self.inner.lock().unwrap().get_next_capture()? };
+        Ok(Capture::new(CaptureWrapper::new(
+            self.inner.clone(),
+            capture,
+        )))
+    }
+
+    /// Moves to the first capture at or after a device timestamp.
+    pub fn seek_device_timestamp(&self, timestamp: Duration) -> Result<(), sys::k4a_result_t> {
+        unsafe { self.inner.lock().unwrap().seek_device_timestamp(timestamp) }
+    }
+
+    /// Time between the first and last timestamp in the recording.
+    pub fn recording_length(&self) -> Duration {
+        unsafe { self.inner.lock().unwrap().recording_length() }
+    }
+}
+
+fn c_string(value: &str) -> Result<CString, sys::k4a_result_t> {
+    CString::new(value).map_err(|_| sys::k4a_result_t::K4A_RESULT_FAILED)
+}
+
+fn to_result(result: sys::k4a_result_t) -> Result<(), sys::k4a_result_t> {
+    match result {
+        sys::k4a_result_t::K4A_RESULT_SUCCEEDED => Ok(()),
+        err => Err(err),
+    }
+}
+
+unsafe extern "C" fn release_image_memory<T>(_buffer: *mut c_void, context: *mut c_void) {
+    drop(Box::from_raw(context as *mut T));
+}
+
+/// An image to write to a recording, borrowing memory that is kept alive until libk4a is done
+/// with it.
+pub struct RecordImage<T> {
+    pub image_type: ImageType,
+    pub format: sys::k4a_image_format_t,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub device_timestamp: Duration,
+    pub data: T,
+}
+
+/// A recording being written in the Azure Kinect Matroska format. Tracks, tags and attachments
+/// have to be added before the header is written, and data can only be written after.
+pub struct Recording {
+    recording: NonNull<sys::_k4a_record_t>,
+}
+
+impl Recording {
+    /// Creates a recording without a device, so the calibration has to be attached separately.
+    pub fn create(
+        path: impl AsRef<Path>,
+        config: DeviceConfiguration,
+    ) -> Result<Self, sys::k4a_result_t> {
+        let Ok(path) = CString::new(path.as_ref().as_os_str().as_bytes()) else {
+            return Err(sys::k4a_result_t::K4A_RESULT_FAILED);
+        };
+        let mut recording = MaybeUninit::uninit();
+        unsafe {
+            to_result(sys::k4arecord().k4a_record_create(
+                path.as_ptr(),
+                std::ptr::null_mut(),
+                config,
+                recording.as_mut_ptr(),
+            ))?;
+            Ok(Self {
+                recording: NonNull::new(recording.assume_init()).unwrap(),
+            })
+        }
+    }
+
+    pub fn add_tag(&self, name: &str, value: &str) -> Result<(), sys::k4a_result_t> {
+        let (name, value) = (c_string(name)?, c_string(value)?);
+        unsafe {
+            to_result(sys::k4arecord().k4a_record_add_tag(
+                self.recording.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr(),
+            ))
+        }
+    }
+
+    pub fn add_attachment(&self, name: &str, data: &[u8]) -> Result<(), sys::k4a_result_t> {
+        let name = c_string(name)?;
+        unsafe {
+            to_result(sys::k4arecord().k4a_record_add_attachment(
+                self.recording.as_ptr(),
+                name.as_ptr(),
+                data.as_ptr(),
+                data.len(),
+            ))
+        }
+    }
+
+    /// Embeds a calibration JSON blob where playback looks for it.
+    pub fn add_calibration(&self, raw: &[u8]) -> Result<(), sys::k4a_result_t> {
+        const CALIBRATION_FILE: &str = "calibration.json";
+        self.add_attachment(CALIBRATION_FILE, raw)?;
+        self.add_tag("K4A_CALIBRATION_FILE", CALIBRATION_FILE)
+    }
+
+    pub fn add_imu_track(&self) -> Result<(), sys::k4a_result_t> {
+        unsafe { to_result(sys::k4arecord().k4a_record_add_imu_track(self.recording.as_ptr())) }
+    }
+
+    pub fn add_custom_subtitle_track(
+        &self,
+        name: &str,
+        codec_id: &str,
+        codec_context: &[u8],
+    ) -> Result<(), sys::k4a_result_t> {
+        let (name, codec_id) = (c_string(name)?, c_string(codec_id)?);
+        let settings = sys::k4a_record_subtitle_settings_t {
+            high_freq_data: false,
+        };
+        unsafe {
+            to_result(sys::k4arecord().k4a_record_add_custom_subtitle_track(
+                self.recording.as_ptr(),
+                name.as_ptr(),
+                codec_id.as_ptr(),
+                codec_context.as_ptr(),
+                codec_context.len(),
+                &settings,
+            ))
+        }
+    }
+
+    pub fn write_header(&self) -> Result<(), sys::k4a_result_t> {
+        unsafe { to_result(sys::k4arecord().k4a_record_write_header(self.recording.as_ptr())) }
+    }
+
+    /// Writes a capture holding a single image.
+    pub fn write_image<T: AsRef<[u8]> + Send + 'static>(
+        &self,
+        image: RecordImage<T>,
+    ) -> Result<(), sys::k4a_result_t> {
+        unsafe {
+            let data = Box::new(image.data);
+            let bytes = (*data).as_ref();
+            let (ptr, size) = (bytes.as_ptr() as *mut u8, bytes.len());
+            let mut handle = MaybeUninit::uninit();
+            // on failure the release callback is not invoked, so ownership is only handed over
+            // once the image exists
+            let data = Box::into_raw(data);
+            if let Err(err) = to_result(sys::k4a().k4a_image_create_from_buffer(
+                image.format,
+                image.width as i32,
+                image.height as i32,
+                image.stride as i32,
+                ptr,
+                size,
+                Some(release_image_memory::<T>),
+                data as *mut c_void,
+                handle.as_mut_ptr(),
+            )) {
+                drop(Box::from_raw(data));
+                return Err(err);
+            }
+            let handle = handle.assume_init();
+            sys::k4a().k4a_image_set_device_timestamp_usec(
+                handle,
+                image.device_timestamp.as_micros() as u64,
+            );
+
+            let mut capture = MaybeUninit::uninit();
+            if let Err(err) = to_result(sys::k4a().k4a_capture_create(capture.as_mut_ptr())) {
+                sys::k4a().k4a_image_release(handle);
+                return Err(err);
+            }
+            let capture = capture.assume_init();
+            match image.image_type {
+                ImageType::Color => sys::k4a().k4a_capture_set_color_image(capture, handle),
+                ImageType::Infrared => sys::k4a().k4a_capture_set_ir_image(capture, handle),
+                ImageType::Depth => sys::k4a().k4a_capture_set_depth_image(capture, handle),
+            }
+            // the capture holds its own reference
+            sys::k4a().k4a_image_release(handle);
+            let result = to_result(
+                sys::k4arecord().k4a_record_write_capture(self.recording.as_ptr(), capture),
+            );
+            sys::k4a().k4a_capture_release(capture);
+            result
+        }
+    }
+
+    pub fn write_imu_sample(&self, sample: ImuSample) -> Result<(), sys::k4a_result_t> {
+        unsafe {
+            to_result(sys::k4arecord().k4a_record_write_imu_sample(self.recording.as_ptr(), sample))
+        }
+    }
+
+    pub fn write_custom_track_data(
+        &self,
+        name: &str,
+        device_timestamp: Duration,
+        data: &[u8],
+    ) -> Result<(), sys::k4a_result_t> {
+        let name = c_string(name)?;
+        unsafe {
+            to_result(sys::k4arecord().k4a_record_write_custom_track_data(
+                self.recording.as_ptr(),
+                name.as_ptr(),
+                device_timestamp.as_micros() as u64,
+                data.as_ptr() as *mut u8,
+                data.len(),
+            ))
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), sys::k4a_result_t> {
+        unsafe { to_result(sys::k4arecord().k4a_record_flush(self.recording.as_ptr())) }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        unsafe {
+            sys::k4arecord().k4a_record_close(self.recording.as_ptr());
+        }
+    }
+}
+
+unsafe impl Send for Recording {}
+
 struct CaptureWrapper {
-    _owner: Arc<DeviceWrapper>,
+    /// The device or recording the capture came from, kept open while the capture is alive.
+    _owner: Arc<dyn Send + Sync>,
     capture: NonNull<sys::_k4a_capture_t>,
 }
 
 impl CaptureWrapper {
-    fn new(device: Arc<DeviceWrapper>, capture: NonNull<sys::_k4a_capture_t>) -> Self {
+    fn new(owner: Arc<dyn Send + Sync>, capture: NonNull<sys::_k4a_capture_t>) -> Self {
         Self {
-            _owner: device,
+            _owner: owner,
             capture,
         }
     }
 
     unsafe fn get_image(&self, image_type: ImageType) -> Option<NonNull<sys::_k4a_image_t>> {
         let image = match image_type {
-            ImageType::Color => sys::k4a_capture_get_color_image(self.capture.as_ptr()),
-            ImageType::Infrared => sys::k4a_capture_get_ir_image(self.capture.as_ptr()),
-            ImageType::Depth => sys::k4a_capture_get_depth_image(self.capture.as_ptr()),
+            ImageType::Color => sys::k4a().k4a_capture_get_color_image(self.capture.as_ptr()),
+            ImageType::Infrared => sys::k4a().k4a_capture_get_ir_image(self.capture.as_ptr()),
+            ImageType::Depth => sys::k4a().k4a_capture_get_depth_image(self.capture.as_ptr()),
         };
         NonNull::new(image)
     }
@@ -149,7 +849,7 @@ impl CaptureWrapper {
 
 impl Drop for CaptureWrapper {
     fn drop(&mut self) {
-        unsafe { sys::k4a_capture_release(self.capture.as_ptr()) }
+        unsafe { sys::k4a().k4a_capture_release(self.capture.as_ptr()) }
     }
 }
 
@@ -172,6 +872,45 @@ impl Capture {
         unsafe { self.inner.get_image(image_type) }
             .map(|image| Image::new(self.inner.clone(), image, image_type))
     }
+
+    /// Temperature of the device in degrees Celsius when the capture was taken, if known.
+    pub fn get_temperature(&self) -> Option<f32> {
+        let temperature =
+            unsafe { sys::k4a().k4a_capture_get_temperature_c(self.inner.capture.as_ptr()) };
+        (!temperature.is_nan()).then_some(temperature)
+    }
+}
+
+#[cfg(test)]
+impl Capture {
+    /// A capture holding a blank depth image, standing in for one read from a device.
+    pub fn with_depth_image(
+        width: u32,
+        height: u32,
+        device_timestamp: Duration,
+    ) -> Result<Self, sys::k4a_result_t> {
+        unsafe {
+            let mut capture = MaybeUninit::uninit();
+            to_result(sys::k4a().k4a_capture_create(capture.as_mut_ptr()))?;
+            let capture =
+                CaptureWrapper::new(Arc::new(()), NonNull::new(capture.assume_init()).unwrap());
+            let mut image = MaybeUninit::uninit();
+            to_result(sys::k4a().k4a_image_create(
+                sys::k4a_image_format_t::K4A_IMAGE_FORMAT_DEPTH16,
+                width as i32,
+                height as i32,
+                width as i32 * 2,
+                image.as_mut_ptr(),
+            ))?;
+            let image = image.assume_init();
+            sys::k4a()
+                .k4a_image_set_device_timestamp_usec(image, device_timestamp.as_micros() as u64);
+            // the capture takes its own reference
+            sys::k4a().k4a_capture_set_depth_image(capture.capture.as_ptr(), image);
+            sys::k4a().k4a_image_release(image);
+            Ok(Self::new(capture))
+        }
+    }
 }
 
 pub struct Image {
@@ -195,10 +934,11 @@ impl Image {
 
     pub fn buffer(&self) -> Option<&[u8]> {
         unsafe {
-            let Some(ptr) = NonNull::new(sys::k4a_image_get_buffer(self.image.as_ptr())) else {
+            let Some(ptr) = NonNull::new(sys::k4a().k4a_image_get_buffer(self.image.as_ptr()))
+            else {
                 return None;
             };
-            let size = sys::k4a_image_get_size(self.image.as_ptr());
+            let size = sys::k4a().k4a_image_get_size(self.image.as_ptr());
             if size > 0 {
                 Some(std::slice::from_raw_parts(ptr.as_ptr(), size))
             } else {
@@ -207,24 +947,68 @@ impl Image {
         }
     }
 
+    pub fn width(&self) -> u32 {
+        unsafe { sys::k4a().k4a_image_get_width_pixels(self.image.as_ptr()) as u32 }
+    }
+
+    pub fn height(&self) -> u32 {
+        unsafe { sys::k4a().k4a_image_get_height_pixels(self.image.as_ptr()) as u32 }
+    }
+
+    pub fn stride(&self) -> u32 {
+        unsafe { sys::k4a().k4a_image_get_stride_bytes(self.image.as_ptr()) as u32 }
+    }
+
     pub fn get_system_timestamp(&self) -> Duration {
         unsafe {
-            Duration::from_nanos(sys::k4a_image_get_system_timestamp_nsec(
-                self.image.as_ptr(),
-            ))
+            Duration::from_nanos(
+                sys::k4a().k4a_image_get_system_timestamp_nsec(self.image.as_ptr()),
+            )
+        }
+    }
+
+    /// Center of the exposure in the device clock domain.
+    pub fn get_device_timestamp(&self) -> Duration {
+        unsafe {
+            Duration::from_micros(
+                sys::k4a().k4a_image_get_device_timestamp_usec(self.image.as_ptr()),
+            )
+        }
+    }
+
+    /// Exposure time of the image. Only meaningful for color images.
+    pub fn get_exposure(&self) -> Duration {
+        unsafe {
+            Duration::from_micros(sys::k4a().k4a_image_get_exposure_usec(self.image.as_ptr()))
         }
     }
 
+    /// White balance of a color image in Kelvin, or 0 otherwise.
+    pub fn get_white_balance(&self) -> u32 {
+        unsafe { sys::k4a().k4a_image_get_white_balance(self.image.as_ptr()) }
+    }
+
+    /// ISO speed of a color image, or 0 otherwise.
+    pub fn get_iso_speed(&self) -> u32 {
+        unsafe { sys::k4a().k4a_image_get_iso_speed(self.image.as_ptr()) }
+    }
+
     #[allow(unused)]
     pub fn image_type(&self) -> ImageType {
         self.image_type
     }
 }
 
+impl AsRef<[u8]> for Image {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer().unwrap_or(&[])
+    }
+}
+
 impl Drop for Image {
     fn drop(&mut self) {
         unsafe {
-            sys::k4a_image_release(self.image.as_ptr());
+            sys::k4a().k4a_image_release(self.image.as_ptr());
         }
     }
 }
@@ -240,11 +1024,66 @@ pub enum ImageType {
     Depth,
 }
 
+/// Opens libk4a. Nothing else in this module may be used until this succeeds.
+pub fn load() -> Result<(), libloading::Error> {
+    sys::K4A
+        .get_or_try_init(|| {
+            open_first(&["libk4a.so.1.4", "libk4a.so"], |name| unsafe {
+                sys::K4a::new(name)
+            })
+        })
+        .map(|_| ())
+}
+
+/// Opens libk4arecord, which playback and recording additionally need.
+pub fn load_record() -> Result<(), libloading::Error> {
+    sys::K4ARECORD
+        .get_or_try_init(|| {
+            open_first(&["libk4arecord.so.1.4", "libk4arecord.so"], |name| unsafe {
+                sys::K4aRecord::new(name)
+            })
+        })
+        .map(|_| ())
+}
+
+fn open_first<T>(
+    names: &[&str],
+    open: impl Fn(&str) -> Result<T, libloading::Error>,
+) -> Result<T, libloading::Error> {
+    let (first, rest) = names.split_first().unwrap();
+    rest.iter()
+        .fold(open(first), |result, name| result.or_else(|_| open(name)))
+}
+
 pub mod sys {
     #![allow(non_upper_case_globals)]
     #![allow(non_camel_case_types)]
     #![allow(non_snake_case)]
     #![allow(dead_code)]
 
+    use once_cell::sync::OnceCell;
+
     include!(concat!(env!("OUT_DIR"), "/k4a.rs"));
+
+    mod functions {
+        use super::*;
+
+        include!(concat!(env!("OUT_DIR"), "/k4a_functions.rs"));
+        include!(concat!(env!("OUT_DIR"), "/k4arecord_functions.rs"));
+    }
+
+    pub use functions::{K4a, K4aRecord};
+
+    pub(super) static K4A: OnceCell<K4a> = OnceCell::new();
+    pub(super) static K4ARECORD: OnceCell<K4aRecord> = OnceCell::new();
+
+    /// The symbols of libk4a, once `load` succeeded.
+    pub fn k4a() -> &'static K4a {
+        K4A.get().expect("libk4a is not loaded")
+    }
+
+    /// The symbols of libk4arecord, once `load_record` succeeded.
+    pub fn k4arecord() -> &'static K4aRecord {
+        K4ARECORD.get().expect("libk4arecord is not loaded")
+    }
 }
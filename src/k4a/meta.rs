@@ -0,0 +1,403 @@
+use std::{fmt, mem::ManuallyDrop, sync::Arc};
+
+use gstreamer::{glib, prelude::*};
+
+use super::libk4a::{sys, Calibration};
+
+const CALIBRATION_TYPES: [(sys::k4a_calibration_type_t, &str); 4] = [
+    (
+        sys::k4a_calibration_type_t::K4A_CALIBRATION_TYPE_DEPTH,
+        "depth",
+    ),
+    (
+        sys::k4a_calibration_type_t::K4A_CALIBRATION_TYPE_COLOR,
+        "color",
+    ),
+    (
+        sys::k4a_calibration_type_t::K4A_CALIBRATION_TYPE_GYRO,
+        "gyro",
+    ),
+    (
+        sys::k4a_calibration_type_t::K4A_CALIBRATION_TYPE_ACCEL,
+        "accel",
+    ),
+];
+
+fn float_array(values: &[f32]) -> gstreamer::Array {
+    gstreamer::Array::new(values.iter().map(|value| *value as f64))
+}
+
+fn extrinsics_structure(
+    name: &str,
+    extrinsics: &sys::k4a_calibration_extrinsics_t,
+) -> gstreamer::Structure {
+    gstreamer::Structure::builder(name)
+        .field("rotation", float_array(&extrinsics.rotation))
+        .field("translation", float_array(&extrinsics.translation))
+        .build()
+}
+
+fn camera_structure(name: &str, camera: &sys::k4a_calibration_camera_t) -> gstreamer::Structure {
+    // cx, cy, fx, fy, k1-k6, codx, cody, p2, p1, metric_radius
+    let parameters = unsafe { camera.intrinsics.parameters.v };
+    gstreamer::Structure::builder(name)
+        .field("width", camera.resolution_width)
+        .field("height", camera.resolution_height)
+        .field("metric-radius", camera.metric_radius as f64)
+        .field("model", camera.intrinsics.type_ as u32)
+        .field("cx", parameters[0] as f64)
+        .field("cy", parameters[1] as f64)
+        .field("fx", parameters[2] as f64)
+        .field("fy", parameters[3] as f64)
+        .field("distortion", float_array(&parameters[4..14]))
+        .field(
+            "extrinsics",
+            extrinsics_structure("extrinsics", &camera.extrinsics),
+        )
+        .build()
+}
+
+/// Describes a device calibration as a structure suitable for element messages.
+pub fn calibration_structure(calibration: &Calibration) -> gstreamer::Structure {
+    let mut extrinsics = gstreamer::Structure::new_empty("extrinsics");
+    for (from, from_name) in CALIBRATION_TYPES {
+        for (to, to_name) in CALIBRATION_TYPES {
+            if from != to {
+                let name = format!("{}-to-{}", from_name, to_name);
+                extrinsics.set(
+                    name.as_str(),
+                    extrinsics_structure(
+                        &name,
+                        &calibration.extrinsics[from as usize][to as usize],
+                    ),
+                );
+            }
+        }
+    }
+    gstreamer::Structure::builder("k4a-calibration")
+        .field("depth-mode", calibration.depth_mode as u32)
+        .field("color-resolution", calibration.color_resolution as u32)
+        .field(
+            "depth",
+            camera_structure("depth", &calibration.depth_camera_calibration),
+        )
+        .field(
+            "color",
+            camera_structure("color", &calibration.color_camera_calibration),
+        )
+        .field("extrinsics", extrinsics)
+        .build()
+}
+
+/// A calibration along with the JSON blob it was read from, which is what recordings embed.
+pub struct DeviceCalibration {
+    pub calibration: Calibration,
+    pub raw: Option<Vec<u8>>,
+}
+
+/// Device calibration attached to every buffer produced by k4asrc. It only survives plain copies
+/// of the buffer, not scaling or cropping.
+#[repr(transparent)]
+pub struct K4aCalibrationMeta(imp::K4aCalibrationMeta);
+
+unsafe impl Send for K4aCalibrationMeta {}
+unsafe impl Sync for K4aCalibrationMeta {}
+
+impl K4aCalibrationMeta {
+    pub fn add(
+        buffer: &mut gstreamer::BufferRef,
+        calibration: Arc<DeviceCalibration>,
+    ) -> gstreamer::MetaRefMut<Self, gstreamer::meta::Standalone> {
+        unsafe {
+            let mut params = ManuallyDrop::new(imp::K4aCalibrationMetaParams { calibration });
+            let meta = gstreamer::ffi::gst_buffer_add_meta(
+                buffer.as_mut_ptr(),
+                imp::k4a_calibration_meta_get_info(),
+                &mut *params as *mut imp::K4aCalibrationMetaParams as glib::ffi::gpointer,
+            ) as *mut imp::K4aCalibrationMeta;
+            Self::from_mut_ptr(buffer, meta)
+        }
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.0.calibration.calibration
+    }
+
+    /// The calibration JSON, if the producer had it.
+    pub fn raw(&self) -> Option<&[u8]> {
+        self.0.calibration.raw.as_deref()
+    }
+
+    pub fn device_calibration(&self) -> Arc<DeviceCalibration> {
+        self.0.calibration.clone()
+    }
+
+    pub fn structure(&self) -> gstreamer::Structure {
+        calibration_structure(self.calibration())
+    }
+}
+
+unsafe impl MetaAPI for K4aCalibrationMeta {
+    type GstType = imp::K4aCalibrationMeta;
+
+    fn meta_api() -> glib::Type {
+        imp::k4a_calibration_meta_api_get_type()
+    }
+}
+
+impl fmt::Debug for K4aCalibrationMeta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("K4aCalibrationMeta")
+            .field("depth_mode", &self.calibration().depth_mode)
+            .field("color_resolution", &self.calibration().color_resolution)
+            .finish()
+    }
+}
+
+/// Values the device reports alongside each image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameInfo {
+    /// Index of the capture since the stream started. Gaps mean captures were dropped.
+    pub sequence: u64,
+    /// Center of the exposure in the device clock domain.
+    pub device_timestamp: gstreamer::ClockTime,
+    /// Host monotonic time at which the image was received from the device.
+    pub system_timestamp: gstreamer::ClockTime,
+    /// Exposure time of color images.
+    pub exposure: gstreamer::ClockTime,
+    /// White balance of color images in Kelvin.
+    pub white_balance: u32,
+    /// ISO speed of color images.
+    pub iso_speed: u32,
+    /// Device temperature in degrees Celsius, if it was reported.
+    pub temperature: Option<f32>,
+}
+
+/// Per-frame device metadata attached to every buffer produced by k4asrc.
+#[repr(transparent)]
+pub struct K4aFrameMeta(imp::K4aFrameMeta);
+
+unsafe impl Send for K4aFrameMeta {}
+unsafe impl Sync for K4aFrameMeta {}
+
+impl K4aFrameMeta {
+    pub fn add(
+        buffer: &mut gstreamer::BufferRef,
+        info: FrameInfo,
+    ) -> gstreamer::MetaRefMut<Self, gstreamer::meta::Standalone> {
+        unsafe {
+            let mut params = ManuallyDrop::new(imp::K4aFrameMetaParams { info });
+            let meta = gstreamer::ffi::gst_buffer_add_meta(
+                buffer.as_mut_ptr(),
+                imp::k4a_frame_meta_get_info(),
+                &mut *params as *mut imp::K4aFrameMetaParams as glib::ffi::gpointer,
+            ) as *mut imp::K4aFrameMeta;
+            Self::from_mut_ptr(buffer, meta)
+        }
+    }
+
+    pub fn info(&self) -> &FrameInfo {
+        &self.0.info
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.info().sequence
+    }
+
+    pub fn device_timestamp(&self) -> gstreamer::ClockTime {
+        self.info().device_timestamp
+    }
+
+    pub fn system_timestamp(&self) -> gstreamer::ClockTime {
+        self.info().system_timestamp
+    }
+
+    pub fn exposure(&self) -> gstreamer::ClockTime {
+        self.info().exposure
+    }
+
+    pub fn white_balance(&self) -> u32 {
+        self.info().white_balance
+    }
+
+    pub fn iso_speed(&self) -> u32 {
+        self.info().iso_speed
+    }
+
+    pub fn temperature(&self) -> Option<f32> {
+        self.info().temperature
+    }
+}
+
+unsafe impl MetaAPI for K4aFrameMeta {
+    type GstType = imp::K4aFrameMeta;
+
+    fn meta_api() -> glib::Type {
+        imp::k4a_frame_meta_api_get_type()
+    }
+}
+
+impl fmt::Debug for K4aFrameMeta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("K4aFrameMeta").field(self.info()).finish()
+    }
+}
+
+mod imp {
+    use std::{mem, ptr, sync::Arc};
+
+    use gstreamer::glib::{self, translate::*};
+    use once_cell::sync::Lazy;
+
+    use super::{DeviceCalibration, FrameInfo};
+
+    pub(super) struct K4aCalibrationMetaParams {
+        pub calibration: Arc<DeviceCalibration>,
+    }
+
+    #[repr(C)]
+    pub struct K4aCalibrationMeta {
+        parent: gstreamer::ffi::GstMeta,
+        pub(super) calibration: Arc<DeviceCalibration>,
+    }
+
+    pub(super) fn k4a_calibration_meta_api_get_type() -> glib::Type {
+        static TYPE: Lazy<glib::Type> = Lazy::new(|| unsafe {
+            let t = from_glib(gstreamer::ffi::gst_meta_api_type_register(
+                b"GstK4aCalibrationMetaAPI\0".as_ptr() as *const _,
+                [ptr::null::<std::os::raw::c_char>()].as_ptr() as *mut *const _,
+            ));
+            assert_ne!(t, glib::Type::INVALID);
+            t
+        });
+        *TYPE
+    }
+
+    unsafe extern "C" fn k4a_calibration_meta_init(
+        meta: *mut gstreamer::ffi::GstMeta,
+        params: glib::ffi::gpointer,
+        _buffer: *mut gstreamer::ffi::GstBuffer,
+    ) -> glib::ffi::gboolean {
+        assert!(!params.is_null());
+        let meta = &mut *(meta as *mut K4aCalibrationMeta);
+        let params = ptr::read(params as *const K4aCalibrationMetaParams);
+        ptr::write(&mut meta.calibration, params.calibration);
+        true.into_glib()
+    }
+
+    unsafe extern "C" fn k4a_calibration_meta_free(
+        meta: *mut gstreamer::ffi::GstMeta,
+        _buffer: *mut gstreamer::ffi::GstBuffer,
+    ) {
+        let meta = &mut *(meta as *mut K4aCalibrationMeta);
+        ptr::drop_in_place(&mut meta.calibration);
+    }
+
+    unsafe extern "C" fn k4a_calibration_meta_transform(
+        dest: *mut gstreamer::ffi::GstBuffer,
+        meta: *mut gstreamer::ffi::GstMeta,
+        _buffer: *mut gstreamer::ffi::GstBuffer,
+        type_: glib::ffi::GQuark,
+        _data: glib::ffi::gpointer,
+    ) -> glib::ffi::gboolean {
+        // the intrinsics describe the image as it came off the sensor, so they no longer hold
+        // once it is scaled or cropped
+        if type_ != glib::Quark::from_str("gst-copy").into_glib() {
+            return false.into_glib();
+        }
+        let meta = &*(meta as *mut K4aCalibrationMeta);
+        super::K4aCalibrationMeta::add(
+            gstreamer::BufferRef::from_mut_ptr(dest),
+            meta.calibration.clone(),
+        );
+        true.into_glib()
+    }
+
+    pub(super) fn k4a_calibration_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
+        struct MetaInfo(ptr::NonNull<gstreamer::ffi::GstMetaInfo>);
+        unsafe impl Send for MetaInfo {}
+        unsafe impl Sync for MetaInfo {}
+
+        static META_INFO: Lazy<MetaInfo> = Lazy::new(|| unsafe {
+            MetaInfo(
+                ptr::NonNull::new(gstreamer::ffi::gst_meta_register(
+                    k4a_calibration_meta_api_get_type().into_glib(),
+                    b"GstK4aCalibrationMeta\0".as_ptr() as *const _,
+                    mem::size_of::<K4aCalibrationMeta>(),
+                    Some(k4a_calibration_meta_init),
+                    Some(k4a_calibration_meta_free),
+                    Some(k4a_calibration_meta_transform),
+                ) as *mut gstreamer::ffi::GstMetaInfo)
+                .expect("Failed to register meta API"),
+            )
+        });
+        META_INFO.0.as_ptr()
+    }
+
+    pub(super) struct K4aFrameMetaParams {
+        pub info: FrameInfo,
+    }
+
+    #[repr(C)]
+    pub struct K4aFrameMeta {
+        parent: gstreamer::ffi::GstMeta,
+        pub(super) info: FrameInfo,
+    }
+
+    pub(super) fn k4a_frame_meta_api_get_type() -> glib::Type {
+        static TYPE: Lazy<glib::Type> = Lazy::new(|| unsafe {
+            let t = from_glib(gstreamer::ffi::gst_meta_api_type_register(
+                b"GstK4aFrameMetaAPI\0".as_ptr() as *const _,
+                [ptr::null::<std::os::raw::c_char>()].as_ptr() as *mut *const _,
+            ));
+            assert_ne!(t, glib::Type::INVALID);
+            t
+        });
+        *TYPE
+    }
+
+    unsafe extern "C" fn k4a_frame_meta_init(
+        meta: *mut gstreamer::ffi::GstMeta,
+        params: glib::ffi::gpointer,
+        _buffer: *mut gstreamer::ffi::GstBuffer,
+    ) -> glib::ffi::gboolean {
+        assert!(!params.is_null());
+        let meta = &mut *(meta as *mut K4aFrameMeta);
+        let params = ptr::read(params as *const K4aFrameMetaParams);
+        ptr::write(&mut meta.info, params.info);
+        true.into_glib()
+    }
+
+    unsafe extern "C" fn k4a_frame_meta_transform(
+        dest: *mut gstreamer::ffi::GstBuffer,
+        meta: *mut gstreamer::ffi::GstMeta,
+        _buffer: *mut gstreamer::ffi::GstBuffer,
+        _type_: glib::ffi::GQuark,
+        _data: glib::ffi::gpointer,
+    ) -> glib::ffi::gboolean {
+        let meta = &*(meta as *mut K4aFrameMeta);
+        super::K4aFrameMeta::add(gstreamer::BufferRef::from_mut_ptr(dest), meta.info);
+        true.into_glib()
+    }
+
+    pub(super) fn k4a_frame_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
+        struct MetaInfo(ptr::NonNull<gstreamer::ffi::GstMetaInfo>);
+        unsafe impl Send for MetaInfo {}
+        unsafe impl Sync for MetaInfo {}
+
+        static META_INFO: Lazy<MetaInfo> = Lazy::new(|| unsafe {
+            MetaInfo(
+                ptr::NonNull::new(gstreamer::ffi::gst_meta_register(
+                    k4a_frame_meta_api_get_type().into_glib(),
+                    b"GstK4aFrameMeta\0".as_ptr() as *const _,
+                    mem::size_of::<K4aFrameMeta>(),
+                    Some(k4a_frame_meta_init),
+                    None,
+                    Some(k4a_frame_meta_transform),
+                ) as *mut gstreamer::ffi::GstMetaInfo)
+                .expect("Failed to register meta API"),
+            )
+        });
+        META_INFO.0.as_ptr()
+    }
+}
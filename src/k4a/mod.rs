@@ -2,18 +2,101 @@ use std::error::Error;
 
 use gstreamer::{glib, prelude::StaticType};
 
+mod backend;
+mod config;
+mod depthalign;
 mod imp;
 mod libk4a;
+pub mod meta;
+mod playback;
+mod provider;
+mod record;
+#[cfg(test)]
+mod tests;
 
 glib::wrapper! {
     pub struct K4a(ObjectSubclass<imp::K4a>) @extends gstreamer_base::PushSrc, gstreamer_base::BaseSrc, gstreamer::Element, gstreamer::Object;
 }
 
+glib::wrapper! {
+    pub struct K4aPlayback(ObjectSubclass<playback::K4aPlayback>) @extends gstreamer_base::PushSrc, gstreamer_base::BaseSrc, gstreamer::Element, gstreamer::Object;
+}
+
+glib::wrapper! {
+    pub struct K4aDepthAlign(ObjectSubclass<depthalign::K4aDepthAlign>) @extends gstreamer::Element, gstreamer::Object;
+}
+
+glib::wrapper! {
+    pub struct K4aRecord(ObjectSubclass<record::K4aRecord>) @extends gstreamer_base::Aggregator, gstreamer::Element, gstreamer::Object;
+}
+
+glib::wrapper! {
+    pub struct K4aDeviceProvider(ObjectSubclass<provider::K4aDeviceProvider>) @extends gstreamer::DeviceProvider, gstreamer::Object;
+}
+
+glib::wrapper! {
+    pub struct K4aDevice(ObjectSubclass<provider::K4aDevice>) @extends gstreamer::Device, gstreamer::Object;
+}
+
+#[cfg(test)]
+impl K4a {
+    /// A k4asrc that streams from `backend` instead of the devices attached to this machine.
+    fn with_backend(backend: std::sync::Arc<dyn backend::Backend>) -> Self {
+        use gstreamer::subclass::prelude::*;
+
+        let element = glib::Object::new::<Self>(&[]);
+        element.imp().set_backend(backend);
+        element
+    }
+}
+
 pub fn register(plugin: &gstreamer::Plugin) -> Result<(), impl Error> {
+    // the SDK is opened at runtime so the rest of the plugin loads on machines without it
+    if let Err(err) = libk4a::load() {
+        gstreamer::warning!(
+            gstreamer::CAT_PLUGIN_LOADING,
+            "Could not load libk4a, skipping Azure Kinect elements: {}",
+            err
+        );
+        return Ok(());
+    }
     gstreamer::Element::register(
         Some(plugin),
         "k4asrc",
         gstreamer::Rank::None,
         K4a::static_type(),
+    )?;
+    gstreamer::Element::register(
+        Some(plugin),
+        "k4adepthalign",
+        gstreamer::Rank::None,
+        K4aDepthAlign::static_type(),
+    )?;
+    gstreamer::DeviceProvider::register(
+        Some(plugin),
+        "k4adeviceprovider",
+        gstreamer::Rank::Primary,
+        K4aDeviceProvider::static_type(),
+    )?;
+
+    if let Err(err) = libk4a::load_record() {
+        gstreamer::warning!(
+            gstreamer::CAT_PLUGIN_LOADING,
+            "Could not load libk4arecord, skipping Azure Kinect playback and recording: {}",
+            err
+        );
+        return Ok(());
+    }
+    gstreamer::Element::register(
+        Some(plugin),
+        "k4aplaybacksrc",
+        gstreamer::Rank::None,
+        K4aPlayback::static_type(),
+    )?;
+    gstreamer::Element::register(
+        Some(plugin),
+        "k4arecordsink",
+        gstreamer::Rank::None,
+        K4aRecord::static_type(),
     )
 }
@@ -0,0 +1,401 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use gstreamer::{glib, prelude::*, subclass::prelude::*};
+use gstreamer_base::{
+    subclass::{base_src::CreateSuccess, prelude::*},
+    traits::BaseSrcExt,
+};
+use once_cell::sync::Lazy;
+use strum::IntoEnumIterator;
+use strum_macros::{EnumIter, EnumString, IntoStaticStr};
+
+use crate::macros::set_field;
+
+use super::{
+    config::{Mode, StreamConfig},
+    libk4a::{self, Playback},
+    meta::{self, DeviceCalibration, K4aCalibrationMeta},
+};
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "k4aplaybacksrc",
+        gstreamer::DebugColorFlags::empty(),
+        Some("Azure Kinect Playback Source"),
+    )
+});
+
+#[derive(Debug, Clone)]
+struct Settings {
+    location: String,
+    mode: Mode,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            location: "".to_owned(),
+            mode: Mode::Color,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, EnumIter, IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
+enum SettingField {
+    Location,
+    Mode,
+}
+
+#[derive(Default)]
+struct State {
+    playback: Option<Playback>,
+    config: Option<StreamConfig>,
+    calibration: Option<Arc<DeviceCalibration>>,
+    /// Device timestamp of the end of the recording.
+    end: Option<gstreamer::ClockTime>,
+}
+
+/// Plays back one track of a recording made by `k4arecorder` or k4arecordsink. Buffers keep the
+/// device timestamps they were recorded with.
+#[derive(Default)]
+pub struct K4aPlayback {
+    settings: RwLock<Settings>,
+    state: Mutex<State>,
+}
+
+impl K4aPlayback {
+    fn post_calibration(&self, calibration: &libk4a::Calibration) {
+        let message =
+            gstreamer::message::Element::builder(meta::calibration_structure(calibration))
+                .src(&*self.instance())
+                .build();
+        if self.instance().post_message(message).is_err() {
+            gstreamer::warning!(CAT, imp: self, "Could not post calibration message");
+        }
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for K4aPlayback {
+    const NAME: &'static str = "k4aplaybacksrc";
+
+    type Type = super::K4aPlayback;
+
+    type ParentType = gstreamer_base::PushSrc;
+}
+
+impl ObjectImpl for K4aPlayback {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            SettingField::iter()
+                .map(|setting| match setting {
+                    SettingField::Location => glib::ParamSpecString::builder(setting.into())
+                        .nick("Location")
+                        .blurb("Path of the Azure Kinect recording to play back")
+                        .build(),
+                    SettingField::Mode => {
+                        glib::ParamSpecEnum::builder(setting.into(), Settings::default().mode)
+                            .nick("Mode")
+                            .blurb("Which track of the recording to play back")
+                            .build()
+                    }
+                })
+                .collect()
+        });
+        PROPERTIES.as_ref()
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.instance();
+        obj.set_format(gstreamer::Format::Time);
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match SettingField::from_str(pspec.name()) {
+            Ok(field) => {
+                let mut settings = self.settings.write().unwrap();
+                match field {
+                    SettingField::Location => {
+                        set_field!(CAT, self, field, settings.location, value)
+                    }
+                    SettingField::Mode => set_field!(CAT, self, field, enum settings.mode, value),
+                }
+            }
+            Err(err) => {
+                panic!("Unknown field {} ({})", pspec.name(), err);
+            }
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match SettingField::from_str(pspec.name()) {
+            Ok(field) => {
+                let settings = self.settings.read().unwrap();
+                match field {
+                    SettingField::Location => settings.location.to_value(),
+                    SettingField::Mode => settings.mode.to_value(),
+                }
+            }
+            Err(err) => {
+                panic!("Unknown field {} ({})", pspec.name(), err);
+            }
+        }
+    }
+}
+
+impl GstObjectImpl for K4aPlayback {}
+
+impl ElementImpl for K4aPlayback {
+    fn metadata() -> Option<&'static gstreamer::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gstreamer::subclass::ElementMetadata> = Lazy::new(|| {
+            gstreamer::subclass::ElementMetadata::new(
+                "Azure Kinect Playback Source",
+                "Source/Video",
+                "Plays back Azure Kinect MKV recordings",
+                "William Zhang <wtzhang23@gmail.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gstreamer::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gstreamer::PadTemplate>> = Lazy::new(|| {
+            let caps = StreamConfig::template_caps();
+            let pad_template = gstreamer::PadTemplate::new(
+                "src",
+                gstreamer::PadDirection::Src,
+                gstreamer::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            vec![pad_template]
+        });
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+impl BaseSrcImpl for K4aPlayback {
+    fn caps(&self, filter: Option<&gstreamer::Caps>) -> Option<gstreamer::Caps> {
+        let caps = match self.state.lock().unwrap().config {
+            Some(config) => gstreamer::Caps::builder_full()
+                .structure(config.to_structure())
+                .build(),
+            None => StreamConfig::template_caps(),
+        };
+        if let Some(filter) = filter {
+            if filter.can_intersect(&caps) {
+                Some(caps.intersect_with_mode(filter, gstreamer::CapsIntersectMode::First))
+            } else {
+                None
+            }
+        } else {
+            Some(caps)
+        }
+    }
+
+    fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
+        let settings = self.settings.read().unwrap().clone();
+        if settings.location.is_empty() {
+            return Err(gstreamer::error_msg!(
+                gstreamer::ResourceError::NotFound,
+                ("No recording location set.")
+            ));
+        }
+        let playback = Playback::open(&settings.location).map_err(|err| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::OpenRead,
+                (
+                    "Could not open recording {}. Error: {:#?}",
+                    settings.location,
+                    err
+                )
+            )
+        })?;
+        let record_configuration = playback.get_record_configuration().map_err(|err| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::Read,
+                ("Could not read recording configuration. Error: {:#?}", err)
+            )
+        })?;
+        let Some(config) =
+            StreamConfig::from_record_configuration(settings.mode, &record_configuration)
+        else {
+            return Err(gstreamer::error_msg!(
+                gstreamer::ResourceError::Settings,
+                (
+                    "Recording {} has no {:?} track in a supported format.",
+                    settings.location,
+                    settings.mode
+                )
+            ));
+        };
+        let calibration = match playback.get_calibration() {
+            Ok(calibration) => Some(Arc::new(DeviceCalibration {
+                calibration,
+                raw: playback.get_raw_calibration().ok(),
+            })),
+            Err(err) => {
+                gstreamer::warning!(
+                    CAT,
+                    imp: self,
+                    "Could not read calibration from recording. Error: {:#?}",
+                    err
+                );
+                None
+            }
+        };
+        let end = Duration::from_micros(record_configuration.start_timestamp_offset_usec as u64)
+            + playback.recording_length();
+        gstreamer::info!(
+            CAT,
+            imp: self,
+            "Playing back {} with {:?}",
+            settings.location,
+            config
+        );
+
+        *self.state.lock().unwrap() = State {
+            playback: Some(playback),
+            config: Some(config),
+            calibration: calibration.clone(),
+            end: Some(gstreamer::ClockTime::from_nseconds(end.as_nanos() as u64)),
+        };
+
+        if let Some(calibration) = calibration {
+            self.post_calibration(&calibration.calibration);
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), gstreamer::ErrorMessage> {
+        *self.state.lock().unwrap() = State::default();
+        Ok(())
+    }
+
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn do_seek(&self, segment: &mut gstreamer::Segment) -> bool {
+        let Some(segment) = segment.downcast_ref::<gstreamer::ClockTime>() else {
+            return false;
+        };
+        if segment.rate() < 0.0 {
+            gstreamer::warning!(CAT, imp: self, "Reverse playback is not supported");
+            return false;
+        }
+        let state = self.state.lock().unwrap();
+        let Some(playback) = state.playback.as_ref() else {
+            return false;
+        };
+        let position = segment.start().unwrap_or(gstreamer::ClockTime::ZERO);
+        match playback.seek_device_timestamp(Duration::from_nanos(position.nseconds())) {
+            Ok(()) => {
+                gstreamer::debug!(CAT, imp: self, "Seeked to {}", position);
+                true
+            }
+            Err(err) => {
+                gstreamer::warning!(
+                    CAT,
+                    imp: self,
+                    "Could not seek to {}. Error: {:#?}",
+                    position,
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    fn query(&self, query: &mut gstreamer::QueryRef) -> bool {
+        match query.view_mut() {
+            gstreamer::QueryViewMut::Duration(duration)
+                if duration.format() == gstreamer::Format::Time =>
+            {
+                match self.state.lock().unwrap().end {
+                    Some(end) => {
+                        duration.set(end);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            _ => BaseSrcImplExt::parent_query(self, query),
+        }
+    }
+}
+
+impl PushSrcImpl for K4aPlayback {
+    fn create(
+        &self,
+        _buffer: Option<&mut gstreamer::BufferRef>,
+    ) -> Result<CreateSuccess, gstreamer::FlowError> {
+        let state = self.state.lock().unwrap();
+        let (Some(playback), Some(config)) = (state.playback.as_ref(), state.config) else {
+            return Err(gstreamer::FlowError::NotNegotiated);
+        };
+        let image_type = config.mode.image_type();
+        let image = loop {
+            let capture = match playback.get_next_capture() {
+                Ok(capture) => capture,
+                Err(libk4a::sys::k4a_stream_result_t::K4A_STREAM_RESULT_EOF) => {
+                    return Err(gstreamer::FlowError::Eos);
+                }
+                Err(err) => {
+                    gstreamer::element_imp_error!(
+                        self,
+                        gstreamer::ResourceError::Read,
+                        ("Could not read capture from recording. Error: {:#?}", err)
+                    );
+                    return Err(gstreamer::FlowError::Error);
+                }
+            };
+            // a capture holds the images recorded together, which need not include this track
+            if let Some(image) = capture.get_image(image_type) {
+                break image;
+            }
+        };
+        let calibration = state.calibration.clone();
+        drop(state);
+
+        let pts =
+            gstreamer::ClockTime::from_nseconds(image.get_device_timestamp().as_nanos() as u64);
+        let stop = self
+            .instance()
+            .segment()
+            .downcast_ref::<gstreamer::ClockTime>()
+            .and_then(|segment| segment.stop());
+        if stop.map_or(false, |stop| pts >= stop) {
+            return Err(gstreamer::FlowError::Eos);
+        }
+
+        let mut buffer = config.buffer(image).map_err(|err| {
+            gstreamer::element_imp_error!(
+                self,
+                gstreamer::CoreError::Failed,
+                ("Could not add video meta. Error: {:#?}", err)
+            );
+            gstreamer::FlowError::Error
+        })?;
+        {
+            let buffer = buffer.make_mut();
+            buffer.set_pts(pts);
+            buffer.set_duration(config.frame_duration());
+            if let Some(calibration) = calibration {
+                K4aCalibrationMeta::add(buffer, calibration);
+            }
+        }
+
+        gstreamer::trace!(CAT, imp: self, "Played back frame. pts={}", pts);
+
+        Ok(CreateSuccess::NewBuffer(buffer))
+    }
+}
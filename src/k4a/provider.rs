@@ -0,0 +1,264 @@
+use std::sync::Mutex;
+
+use gstreamer::{glib, prelude::*, subclass::prelude::*};
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::libusb_hotplug::HotplugMonitor;
+
+use super::{
+    config::{Mode, StreamConfig},
+    libk4a,
+};
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "k4adeviceprovider",
+        gstreamer::DebugColorFlags::empty(),
+        Some("Azure Kinect Device Provider"),
+    )
+});
+
+const MODES: [Mode; 3] = [Mode::Color, Mode::Depth, Mode::Ir];
+
+/// Vendor ID of the USB devices an Azure Kinect shows up as.
+const USBVID_MICROSOFT: u16 = 0x045e;
+
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Color => "color",
+        Mode::Depth => "depth",
+        Mode::Ir => "ir",
+    }
+}
+
+/// The serial number of a device, or `None` if it could not be opened to read it.
+type DeviceKey = Option<String>;
+
+#[derive(Default)]
+struct State {
+    devices: Vec<(DeviceKey, Vec<gstreamer::Device>)>,
+    monitor: Option<HotplugMonitor>,
+}
+
+/// Lists every installed Azure Kinect once per stream, since each k4asrc captures one stream,
+/// reporting them as they are plugged in and out where libusb supports hotplug.
+#[derive(Default)]
+pub struct K4aDeviceProvider {
+    state: Mutex<State>,
+}
+
+impl K4aDeviceProvider {
+    fn device(serial_number: Option<&str>, index: u32, mode: Mode) -> super::K4aDevice {
+        let caps = StreamConfig::all(mode)
+            .into_iter()
+            .fold(gstreamer::Caps::builder_full(), |builder, config| {
+                builder.structure(config.to_structure())
+            })
+            .build();
+        let mut properties = gstreamer::Structure::builder("k4a-device")
+            .field("device.api", "k4a")
+            .field("k4a.mode", mode_name(mode));
+        if let Some(serial_number) = serial_number {
+            properties = properties.field("device.serial", serial_number);
+        }
+        let display_name = match serial_number {
+            Some(serial_number) => format!("Azure Kinect {} ({})", serial_number, mode_name(mode)),
+            None => format!("Azure Kinect {} ({})", index, mode_name(mode)),
+        };
+        let device: super::K4aDevice = glib::Object::builder()
+            .property("display-name", display_name)
+            .property("device-class", "Video/Source")
+            .property("caps", &caps)
+            .property("properties", &properties.build())
+            .build();
+        device
+            .imp()
+            .info
+            .set(DeviceInfo {
+                serial_number: serial_number.map(str::to_owned),
+                mode,
+            })
+            .unwrap();
+        device
+    }
+
+    fn scan(&self) -> Vec<(DeviceKey, Vec<gstreamer::Device>)> {
+        (0..libk4a::Device::installed_count())
+            .map(|index| {
+                // a device streaming elsewhere cannot be opened, so it is listed without a serial
+                let serial_number = match libk4a::Device::open(index)
+                    .map_err(|err| format!("{:?}", err))
+                    .and_then(|device| device.serial_number().map_err(|err| format!("{:?}", err)))
+                {
+                    Ok(serial_number) => Some(serial_number),
+                    Err(err) => {
+                        gstreamer::debug!(
+                            CAT,
+                            imp: self,
+                            "Could not query device {}. Error: {}",
+                            index,
+                            err
+                        );
+                        None
+                    }
+                };
+                let devices = MODES
+                    .into_iter()
+                    .map(|mode| Self::device(serial_number.as_deref(), index, mode).upcast())
+                    .collect();
+                (serial_number, devices)
+            })
+            .collect()
+    }
+
+    /// Reports the devices that came and went since the last scan.
+    fn rescan(&self) {
+        let mut found = self.scan();
+        let (removed, added) = {
+            let mut state = self.state.lock().unwrap();
+            let (mut kept, mut unmatched) = (Vec::new(), Vec::new());
+            for (key, devices) in std::mem::take(&mut state.devices) {
+                match found.iter().position(|(found, _)| *found == key) {
+                    Some(index) => {
+                        found.remove(index);
+                        kept.push((key, devices));
+                    }
+                    None => unmatched.push((key, devices)),
+                }
+            }
+            // a listed device that started streaming can no longer be opened to read its serial
+            // number, so it is taken to be one of the devices that could not be opened
+            let mut removed = Vec::new();
+            for (key, devices) in unmatched {
+                match found.iter().position(|(found, _)| found.is_none()) {
+                    Some(index) if key.is_some() => {
+                        found.remove(index);
+                        kept.push((key, devices));
+                    }
+                    _ => removed.push((key, devices)),
+                }
+            }
+            state.devices = kept;
+            state.devices.extend(found.iter().cloned());
+            (removed, found)
+        };
+
+        let obj = self.instance();
+        for device in removed.into_iter().flat_map(|(_, devices)| devices) {
+            gstreamer::info!(CAT, imp: self, "Removed {}", device.display_name());
+            obj.device_remove(&device);
+        }
+        for device in added.into_iter().flat_map(|(_, devices)| devices) {
+            gstreamer::info!(CAT, imp: self, "Added {}", device.display_name());
+            obj.device_add(&device);
+        }
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for K4aDeviceProvider {
+    const NAME: &'static str = "k4adeviceprovider";
+
+    type Type = super::K4aDeviceProvider;
+
+    type ParentType = gstreamer::DeviceProvider;
+}
+
+impl ObjectImpl for K4aDeviceProvider {}
+
+impl GstObjectImpl for K4aDeviceProvider {}
+
+impl DeviceProviderImpl for K4aDeviceProvider {
+    fn metadata() -> Option<&'static gstreamer::subclass::DeviceProviderMetadata> {
+        static METADATA: Lazy<gstreamer::subclass::DeviceProviderMetadata> = Lazy::new(|| {
+            gstreamer::subclass::DeviceProviderMetadata::new(
+                "Azure Kinect Device Provider",
+                "Source/Video",
+                "Lists Azure Kinect devices",
+                "William Zhang <wtzhang23@gmail.com>",
+            )
+        });
+
+        Some(&*METADATA)
+    }
+
+    fn probe(&self) -> Vec<gstreamer::Device> {
+        self.scan()
+            .into_iter()
+            .flat_map(|(_, devices)| devices)
+            .collect()
+    }
+
+    fn start(&self) -> Result<(), gstreamer::LoggableError> {
+        self.rescan();
+
+        let provider = self.instance().downgrade();
+        let monitor = HotplugMonitor::start(USBVID_MICROSOFT, move || {
+            if let Some(provider) = provider.upgrade() {
+                provider.imp().rescan();
+            }
+        });
+        if monitor.is_none() {
+            gstreamer::info!(
+                CAT,
+                imp: self,
+                "Hotplug is not supported, only listing devices present at start"
+            );
+        }
+        self.state.lock().unwrap().monitor = monitor;
+        Ok(())
+    }
+
+    fn stop(&self) {
+        // the monitor thread rescans under the state lock, so it is joined without holding it
+        let monitor = self.state.lock().unwrap().monitor.take();
+        if let Some(monitor) = monitor {
+            monitor.stop();
+        }
+        self.state.lock().unwrap().devices.clear();
+    }
+}
+
+struct DeviceInfo {
+    serial_number: Option<String>,
+    mode: Mode,
+}
+
+/// One stream of an Azure Kinect, which creates a k4asrc opening it by serial number, or the
+/// first free device if the serial number could not be read.
+#[derive(Default)]
+pub struct K4aDevice {
+    info: OnceCell<DeviceInfo>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for K4aDevice {
+    const NAME: &'static str = "k4adevice";
+
+    type Type = super::K4aDevice;
+
+    type ParentType = gstreamer::Device;
+}
+
+impl ObjectImpl for K4aDevice {}
+
+impl GstObjectImpl for K4aDevice {}
+
+impl DeviceImpl for K4aDevice {
+    fn create_element(
+        &self,
+        name: Option<&str>,
+    ) -> Result<gstreamer::Element, gstreamer::LoggableError> {
+        let info = self.info.get().unwrap();
+        let mut builder = gstreamer::ElementFactory::make("k4asrc").property("mode", info.mode);
+        if let Some(serial_number) = info.serial_number.as_ref() {
+            builder = builder.property("serial-number", serial_number);
+        }
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        builder
+            .build()
+            .map_err(|err| gstreamer::loggable_error!(CAT, "Could not create k4asrc: {}", err))
+    }
+}
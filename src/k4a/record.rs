@@ -0,0 +1,731 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Mutex, MutexGuard, RwLock},
+    time::Duration,
+};
+
+use gstreamer::{glib, prelude::*, subclass::prelude::*};
+use gstreamer_base::{prelude::*, subclass::prelude::*};
+use once_cell::sync::Lazy;
+use strum::IntoEnumIterator;
+use strum_macros::{EnumIter, EnumString, IntoStaticStr};
+
+use crate::macros::set_field;
+
+use super::{
+    config::{Mode, StreamConfig},
+    libk4a::{self, Recording},
+    meta::{K4aCalibrationMeta, K4aFrameMeta},
+};
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "k4arecordsink",
+        gstreamer::DebugColorFlags::empty(),
+        Some("Azure Kinect Recording Sink"),
+    )
+});
+
+/// Caps of buffers holding packed `k4a_imu_sample_t`s.
+const IMU_CAPS: &str = "application/x-k4a-imu";
+
+/// Caps of the source pad, which only ever carries events: stream-start, caps, segment and EOS
+/// once the recording was closed.
+const RECORDING_CAPS: &str = "application/x-k4a-recording";
+
+#[derive(Debug, Clone, Default)]
+struct Settings {
+    location: String,
+    calibration_file: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, EnumIter, IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
+enum SettingField {
+    Location,
+    CalibrationFile,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Track {
+    Color,
+    Depth,
+    Ir,
+    Imu,
+    Custom(String),
+}
+
+impl Track {
+    const CUSTOM_PREFIX: &'static str = "custom_";
+
+    fn from_pad_name(name: &str) -> Option<Self> {
+        match name {
+            "color" => Some(Self::Color),
+            "depth" => Some(Self::Depth),
+            "ir" => Some(Self::Ir),
+            "imu" => Some(Self::Imu),
+            _ => name
+                .strip_prefix(Self::CUSTOM_PREFIX)
+                .filter(|track| !track.is_empty())
+                .map(|track| Self::Custom(track.to_owned())),
+        }
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        match self {
+            Self::Color => Some(Mode::Color),
+            Self::Depth => Some(Mode::Depth),
+            Self::Ir => Some(Mode::Ir),
+            Self::Imu | Self::Custom(_) => None,
+        }
+    }
+}
+
+struct SinkPad {
+    track: Track,
+    caps: Option<gstreamer::Caps>,
+    /// Set for video tracks once caps were received.
+    config: Option<StreamConfig>,
+}
+
+#[derive(Default)]
+struct State {
+    pads: BTreeMap<String, SinkPad>,
+    recording: Option<Recording>,
+    /// Set once every pad reached EOS and the recording was closed.
+    finished: bool,
+    /// Raw calibration carried by incoming buffers, used if no calibration file was given.
+    calibration: Option<Vec<u8>>,
+}
+
+/// Writes the streams of k4a sources into the Matroska format of the Azure Kinect SDK. The
+/// tracks are fixed by the pads that exist and their caps, so the header is only written once
+/// every pad negotiated or ended. Buffers are written in running time order across pads, stamped
+/// with the device timestamp of their [`K4aFrameMeta`] where they carry one.
+///
+/// The element is an aggregator for its handling of several live sink pads, which requires an
+/// always present source pad. That pad never carries buffers and can be left unlinked or linked to
+/// anything accepting [`RECORDING_CAPS`], where EOS tells that the recording was closed. The
+/// element is flagged as a sink and posts EOS itself, so pipelines end once the file is complete
+/// either way.
+#[derive(Default)]
+pub struct K4aRecord {
+    settings: RwLock<Settings>,
+    state: Mutex<State>,
+}
+
+impl K4aRecord {
+    /// Finds the configuration described by the caps of a video pad. IR caps at 1024x1024 are
+    /// passive IR on their own but the IR of the wide unbinned depth mode next to a depth pad,
+    /// so the depth pad has to be requested before IR negotiates. Caps that do not fit the
+    /// configurations of the other pads are rejected.
+    fn pad_configuration(
+        state: &State,
+        name: &str,
+        mode: Mode,
+        caps: &gstreamer::Caps,
+    ) -> Result<StreamConfig, String> {
+        let matching = caps
+            .structure(0)
+            .map(|structure| StreamConfig::matching(mode, structure))
+            .unwrap_or_default();
+        let with_depth = state.pads.values().any(|pad| pad.track == Track::Depth);
+        let config = match matching.as_slice() {
+            [] => return Err(format!("Caps {} do not match a device configuration", caps)),
+            [config] => *config,
+            configs => *configs
+                .iter()
+                .find(|config| config.depth_mode.is_some() == with_depth)
+                .ok_or_else(|| format!("Caps {} are ambiguous", caps))?,
+        };
+
+        let depth_mode = config.device_configuration().depth_mode;
+        for (other_name, other) in state.pads.iter() {
+            let Some(other) = other.config.filter(|_| other_name != name) else {
+                continue;
+            };
+            if other.fps_mode != config.fps_mode {
+                return Err(format!(
+                    "Caps {} do not run at the frame rate of pad {}",
+                    caps, other_name
+                ));
+            }
+            if mode != Mode::Color
+                && other.mode != Mode::Color
+                && other.device_configuration().depth_mode != depth_mode
+            {
+                return Err(format!(
+                    "Caps {} do not match the depth mode of pad {}",
+                    caps, other_name
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Combines the configurations of the video pads into the one device configuration the
+    /// recording is made for. The configurations were checked against each other when the pads
+    /// negotiated.
+    fn device_configuration(state: &State) -> libk4a::DeviceConfiguration {
+        let mut configuration = libk4a::DeviceConfiguration {
+            color_format: libk4a::sys::k4a_image_format_t::K4A_IMAGE_FORMAT_COLOR_MJPG,
+            color_resolution: libk4a::sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_OFF,
+            depth_mode: libk4a::sys::k4a_depth_mode_t::K4A_DEPTH_MODE_OFF,
+            camera_fps: libk4a::sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_30,
+            synchronized_images_only: Default::default(),
+            depth_delay_off_color_usec: Default::default(),
+            wired_sync_mode: libk4a::sys::k4a_wired_sync_mode_t::K4A_WIRED_SYNC_MODE_STANDALONE,
+            subordinate_delay_off_master_usec: Default::default(),
+            disable_streaming_indicator: Default::default(),
+        };
+        for config in state.pads.values().filter_map(|pad| pad.config) {
+            let device_configuration = config.device_configuration();
+            configuration.camera_fps = device_configuration.camera_fps;
+            match config.mode {
+                Mode::Color => {
+                    configuration.color_format = device_configuration.color_format;
+                    configuration.color_resolution = device_configuration.color_resolution;
+                }
+                Mode::Depth | Mode::Ir => {
+                    configuration.depth_mode = device_configuration.depth_mode;
+                }
+            }
+        }
+        configuration
+    }
+
+    fn start_recording(&self, state: &State) -> Result<Recording, gstreamer::ErrorMessage> {
+        let settings = self.settings.read().unwrap().clone();
+        if settings.location.is_empty() {
+            return Err(gstreamer::error_msg!(
+                gstreamer::ResourceError::NotFound,
+                ("No recording location set.")
+            ));
+        }
+        let device_configuration = Self::device_configuration(state);
+        let recording =
+            Recording::create(&settings.location, device_configuration).map_err(|err| {
+                gstreamer::error_msg!(
+                    gstreamer::ResourceError::OpenWrite,
+                    (
+                        "Could not create recording {}. Error: {:#?}",
+                        settings.location,
+                        err
+                    )
+                )
+            })?;
+
+        let calibration = if settings.calibration_file.is_empty() {
+            state.calibration.clone()
+        } else {
+            Some(std::fs::read(&settings.calibration_file).map_err(|err| {
+                gstreamer::error_msg!(
+                    gstreamer::ResourceError::OpenRead,
+                    (
+                        "Could not read calibration from {}. Error: {:#?}",
+                        settings.calibration_file,
+                        err
+                    )
+                )
+            })?)
+        };
+        match calibration {
+            Some(calibration) => {
+                if let Err(err) = recording.add_calibration(&calibration) {
+                    gstreamer::warning!(
+                        CAT,
+                        imp: self,
+                        "Could not embed calibration. Error: {:#?}",
+                        err
+                    );
+                }
+            }
+            None => gstreamer::warning!(
+                CAT,
+                imp: self,
+                "No calibration available, the recording will have none"
+            ),
+        }
+
+        // pads that ended without negotiating have nothing to record
+        for pad in state.pads.values().filter(|pad| pad.caps.is_some()) {
+            let result = match &pad.track {
+                Track::Imu => recording.add_imu_track(),
+                Track::Custom(name) => {
+                    let (codec_id, codec_context) = match pad.caps.as_ref() {
+                        Some(caps)
+                            if caps
+                                .structure(0)
+                                .map_or(false, |structure| structure.name() == "text/x-raw") =>
+                        {
+                            ("S_TEXT/UTF8", String::new())
+                        }
+                        // keep the caps with the track so that readers know what the data is
+                        caps => (
+                            "S_GST/CAPS",
+                            caps.map(|caps| caps.to_string()).unwrap_or_default(),
+                        ),
+                    };
+                    recording.add_custom_subtitle_track(name, codec_id, codec_context.as_bytes())
+                }
+                Track::Color | Track::Depth | Track::Ir => Ok(()),
+            };
+            result.map_err(|err| {
+                gstreamer::error_msg!(
+                    gstreamer::ResourceError::Write,
+                    ("Could not add {:?} track. Error: {:#?}", pad.track, err)
+                )
+            })?;
+        }
+
+        recording.write_header().map_err(|err| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::Write,
+                ("Could not write recording header. Error: {:#?}", err)
+            )
+        })?;
+        gstreamer::info!(
+            CAT,
+            imp: self,
+            "Recording to {} with {:?}",
+            settings.location,
+            device_configuration
+        );
+        Ok(recording)
+    }
+
+    fn finish_recording(&self, recording: Recording) {
+        if let Err(err) = recording.flush() {
+            gstreamer::warning!(CAT, imp: self, "Could not flush recording. Error: {:#?}", err);
+        }
+    }
+
+    fn aggregator_pads(&self) -> Vec<gstreamer_base::AggregatorPad> {
+        self.instance()
+            .sink_pads()
+            .into_iter()
+            .filter_map(|pad| pad.downcast().ok())
+            .collect()
+    }
+
+    fn write_buffer(
+        &self,
+        recording: &Recording,
+        sink_pad: &SinkPad,
+        device_timestamp: Duration,
+        buffer: gstreamer::Buffer,
+    ) -> Result<(), gstreamer::FlowError> {
+        let result = match &sink_pad.track {
+            Track::Color | Track::Depth | Track::Ir => {
+                let Some(config) = sink_pad.config else {
+                    return Err(gstreamer::FlowError::NotNegotiated);
+                };
+                let (width, height) = config.dimensions();
+                let stride = match config.format.video_format() {
+                    Some(format) => buffer
+                        .meta::<gstreamer_video::VideoMeta>()
+                        .map(|meta| meta.stride()[0] as u32)
+                        .or_else(|| {
+                            gstreamer_video::VideoInfo::builder(format, width as u32, height as u32)
+                                .build()
+                                .ok()
+                                .map(|info| info.stride()[0] as u32)
+                        })
+                        .ok_or(gstreamer::FlowError::NotNegotiated)?,
+                    // compressed images have no stride
+                    None => 0,
+                };
+                let data = buffer
+                    .into_mapped_buffer_readable()
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                recording.write_image(libk4a::RecordImage {
+                    image_type: config.mode.image_type(),
+                    format: config.format.to_sys(),
+                    width: width as u32,
+                    height: height as u32,
+                    stride,
+                    device_timestamp,
+                    data,
+                })
+            }
+            Track::Imu => {
+                let map = buffer
+                    .map_readable()
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                map.chunks_exact(std::mem::size_of::<libk4a::ImuSample>())
+                    .try_for_each(|chunk| {
+                        let sample = unsafe {
+                            std::ptr::read_unaligned(chunk.as_ptr() as *const libk4a::ImuSample)
+                        };
+                        recording.write_imu_sample(sample)
+                    })
+            }
+            Track::Custom(name) => {
+                let map = buffer
+                    .map_readable()
+                    .map_err(|_| gstreamer::FlowError::Error)?;
+                recording.write_custom_track_data(name, device_timestamp, &map)
+            }
+        };
+        result.map_err(|err| {
+            gstreamer::element_imp_error!(
+                self,
+                gstreamer::ResourceError::Write,
+                (
+                    "Could not write {:?} data. Error: {:#?}",
+                    sink_pad.track,
+                    err
+                )
+            );
+            gstreamer::FlowError::Error
+        })
+    }
+
+    /// Closes the recording once every pad ended. The source pad is normally left unlinked, so
+    /// the element announces the end of the stream to the pipeline like any other sink.
+    fn finish(&self, mut state: MutexGuard<State>) -> gstreamer::FlowError {
+        state.finished = true;
+        let recording = state.recording.take();
+        drop(state);
+
+        if let Some(recording) = recording {
+            self.finish_recording(recording);
+        }
+        let message = gstreamer::message::Eos::builder()
+            .src(&*self.instance())
+            .build();
+        if self.instance().post_message(message).is_err() {
+            gstreamer::warning!(CAT, imp: self, "Could not post EOS message");
+        }
+        gstreamer::FlowError::Eos
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for K4aRecord {
+    const NAME: &'static str = "k4arecordsink";
+
+    type Type = super::K4aRecord;
+
+    type ParentType = gstreamer_base::Aggregator;
+}
+
+impl ObjectImpl for K4aRecord {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            SettingField::iter()
+                .map(|setting| match setting {
+                    SettingField::Location => glib::ParamSpecString::builder(setting.into())
+                        .nick("Location")
+                        .blurb("Path of the recording to write")
+                        .build(),
+                    SettingField::CalibrationFile => glib::ParamSpecString::builder(setting.into())
+                        .nick("Calibration File")
+                        .blurb("Raw calibration JSON to embed (empty to use the calibration attached to buffers)")
+                        .build(),
+                })
+                .collect()
+        });
+        PROPERTIES.as_ref()
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        // posts EOS like a sink so that pipelines wait for the recording to be closed
+        self.instance()
+            .set_element_flags(gstreamer::ElementFlags::SINK);
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match SettingField::from_str(pspec.name()) {
+            Ok(field) => {
+                let mut settings = self.settings.write().unwrap();
+                match field {
+                    SettingField::Location => {
+                        set_field!(CAT, self, field, settings.location, value)
+                    }
+                    SettingField::CalibrationFile => {
+                        set_field!(CAT, self, field, settings.calibration_file, value)
+                    }
+                }
+            }
+            Err(err) => {
+                panic!("Unknown field {} ({})", pspec.name(), err);
+            }
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match SettingField::from_str(pspec.name()) {
+            Ok(field) => {
+                let settings = self.settings.read().unwrap();
+                match field {
+                    SettingField::Location => settings.location.to_value(),
+                    SettingField::CalibrationFile => settings.calibration_file.to_value(),
+                }
+            }
+            Err(err) => {
+                panic!("Unknown field {} ({})", pspec.name(), err);
+            }
+        }
+    }
+}
+
+impl GstObjectImpl for K4aRecord {}
+
+impl ElementImpl for K4aRecord {
+    fn metadata() -> Option<&'static gstreamer::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gstreamer::subclass::ElementMetadata> = Lazy::new(|| {
+            gstreamer::subclass::ElementMetadata::new(
+                "Azure Kinect Recording Sink",
+                "Sink/Video",
+                "Records Azure Kinect streams to MKV files readable by the Azure Kinect SDK",
+                "William Zhang <wtzhang23@gmail.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gstreamer::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gstreamer::PadTemplate>> = Lazy::new(|| {
+            // required of aggregators, only passing on the end of the recording
+            let src = gstreamer::PadTemplate::new(
+                "src",
+                gstreamer::PadDirection::Src,
+                gstreamer::PadPresence::Always,
+                &gstreamer::Caps::builder(RECORDING_CAPS).build(),
+            )
+            .unwrap();
+            let sinks = [
+                ("color", StreamConfig::color_caps()),
+                ("depth", StreamConfig::ir_depth_caps()),
+                ("ir", StreamConfig::ir_depth_caps()),
+                ("imu", gstreamer::Caps::builder(IMU_CAPS).build()),
+                ("custom_%s", gstreamer::Caps::new_any()),
+            ]
+            .into_iter()
+            .map(|(name, caps)| {
+                gstreamer::PadTemplate::with_gtype(
+                    name,
+                    gstreamer::PadDirection::Sink,
+                    gstreamer::PadPresence::Request,
+                    &caps,
+                    gstreamer_base::AggregatorPad::static_type(),
+                )
+                .unwrap()
+            });
+            std::iter::once(src).chain(sinks).collect()
+        });
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn release_pad(&self, pad: &gstreamer::Pad) {
+        self.state.lock().unwrap().pads.remove(pad.name().as_str());
+        self.parent_release_pad(pad);
+    }
+}
+
+impl AggregatorImpl for K4aRecord {
+    fn create_new_pad(
+        &self,
+        templ: &gstreamer::PadTemplate,
+        req_name: Option<&str>,
+        _caps: Option<&gstreamer::Caps>,
+    ) -> Option<gstreamer_base::AggregatorPad> {
+        let mut state = self.state.lock().unwrap();
+        let name = match req_name {
+            Some(name) => name.to_owned(),
+            None if templ.name_template().contains("%s") => (0..)
+                .map(|index| format!("{}{}", Track::CUSTOM_PREFIX, index))
+                .find(|name| !state.pads.contains_key(name))
+                .unwrap(),
+            None => templ.name_template().to_string(),
+        };
+        let Some(track) = Track::from_pad_name(&name) else {
+            gstreamer::warning!(CAT, imp: self, "Invalid pad name {}", name);
+            return None;
+        };
+        if state.recording.is_some() {
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "Cannot add {} once recording started",
+                name
+            );
+            return None;
+        }
+        if state.pads.contains_key(&name) {
+            gstreamer::warning!(CAT, imp: self, "Pad {} already exists", name);
+            return None;
+        }
+
+        let pad = glib::Object::new::<gstreamer_base::AggregatorPad>(&[
+            ("name", &name),
+            ("direction", &gstreamer::PadDirection::Sink),
+            ("template", templ),
+        ]);
+        state.pads.insert(
+            name,
+            SinkPad {
+                track,
+                caps: None,
+                config: None,
+            },
+        );
+        Some(pad)
+    }
+
+    fn sink_event(
+        &self,
+        aggregator_pad: &gstreamer_base::AggregatorPad,
+        event: gstreamer::Event,
+    ) -> bool {
+        if let gstreamer::EventView::Caps(caps) = event.view() {
+            let caps = caps.caps_owned();
+            let mut state = self.state.lock().unwrap();
+            let started = state.recording.is_some();
+            let name = aggregator_pad.name();
+            let Some(sink_pad) = state.pads.get(name.as_str()) else {
+                return false;
+            };
+            if started && sink_pad.caps.as_ref() != Some(&caps) {
+                gstreamer::warning!(
+                    CAT,
+                    obj: aggregator_pad,
+                    "Caps cannot change once recording started"
+                );
+                return false;
+            }
+            let config = match sink_pad.track.mode() {
+                Some(mode) => match Self::pad_configuration(&state, &name, mode, &caps) {
+                    Ok(config) => Some(config),
+                    Err(err) => {
+                        gstreamer::warning!(CAT, obj: aggregator_pad, "{}", err);
+                        return false;
+                    }
+                },
+                None => None,
+            };
+            let sink_pad = state.pads.get_mut(name.as_str()).unwrap();
+            sink_pad.caps = Some(caps);
+            sink_pad.config = config;
+        }
+        self.parent_sink_event(aggregator_pad, event)
+    }
+
+    fn aggregate(&self, _timeout: bool) -> Result<gstreamer::FlowSuccess, gstreamer::FlowError> {
+        let pads = self.aggregator_pads();
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return Err(gstreamer::FlowError::Eos);
+        }
+
+        if state.recording.is_none() {
+            let (negotiated, pending): (Vec<_>, Vec<_>) = pads.iter().partition(|pad| {
+                state
+                    .pads
+                    .get(pad.name().as_str())
+                    .map_or(false, |sink_pad| sink_pad.caps.is_some())
+            });
+            // the tracks are fixed by the header, so wait for every pad to know its format
+            if pending.iter().any(|pad| !pad.is_eos()) {
+                return Ok(gstreamer::FlowSuccess::Ok);
+            }
+            if negotiated.is_empty() {
+                return Err(self.finish(state));
+            }
+            for pad in pending {
+                gstreamer::warning!(
+                    CAT,
+                    obj: pad,
+                    "Ended before negotiating, leaving it out of the recording"
+                );
+            }
+            if state.calibration.is_none() {
+                state.calibration = pads.iter().find_map(|pad| {
+                    pad.peek_buffer()?
+                        .meta::<K4aCalibrationMeta>()?
+                        .raw()
+                        .map(<[u8]>::to_vec)
+                });
+            }
+            match self.start_recording(&state) {
+                Ok(recording) => state.recording = Some(recording),
+                Err(err) => {
+                    self.post_error_message(err);
+                    return Err(gstreamer::FlowError::Error);
+                }
+            }
+        }
+
+        // buffers without a running time sort first and are dropped
+        let next = pads
+            .iter()
+            .filter_map(|pad| {
+                let buffer = pad.peek_buffer()?;
+                let segment = pad.segment();
+                let running_time = buffer.pts().and_then(|pts| {
+                    segment
+                        .downcast_ref::<gstreamer::ClockTime>()?
+                        .to_running_time(pts)
+                });
+                Some((pad, running_time))
+            })
+            .min_by_key(|(_, running_time)| *running_time);
+        let Some((pad, running_time)) = next else {
+            if pads.iter().all(|pad| pad.is_eos()) {
+                return Err(self.finish(state));
+            }
+            return Ok(gstreamer::FlowSuccess::Ok);
+        };
+        let Some(buffer) = pad.pop_buffer() else {
+            return Ok(gstreamer::FlowSuccess::Ok);
+        };
+        // images of one capture share their device timestamp, which running time would split up
+        let device_timestamp = buffer
+            .meta::<K4aFrameMeta>()
+            .map(|meta| meta.device_timestamp())
+            .or(running_time);
+        let Some(device_timestamp) = device_timestamp else {
+            gstreamer::debug!(
+                CAT,
+                obj: pad,
+                "Dropping buffer without a timestamp {:?}",
+                buffer
+            );
+            return Ok(gstreamer::FlowSuccess::Ok);
+        };
+
+        let state = &*state;
+        let Some(sink_pad) = state.pads.get(pad.name().as_str()) else {
+            return Err(gstreamer::FlowError::NotLinked);
+        };
+        let recording = state.recording.as_ref().unwrap();
+        self.write_buffer(
+            recording,
+            sink_pad,
+            Duration::from_nanos(device_timestamp.nseconds()),
+            buffer,
+        )?;
+        Ok(gstreamer::FlowSuccess::Ok)
+    }
+
+    fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
+        let mut state = self.state.lock().unwrap();
+        state.finished = false;
+        state.calibration = None;
+        drop(state);
+        self.parent_start()
+    }
+
+    fn stop(&self) -> Result<(), gstreamer::ErrorMessage> {
+        let recording = self.state.lock().unwrap().recording.take();
+        // a pipeline stopped without EOS still leaves a readable recording behind
+        if let Some(recording) = recording {
+            self.finish_recording(recording);
+        }
+        self.parent_stop()
+    }
+}
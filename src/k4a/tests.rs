@@ -0,0 +1,334 @@
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Once,
+};
+use std::time::Duration;
+
+use gstreamer::{glib, prelude::*};
+use gstreamer_check::Harness;
+
+use super::{
+    backend::{Backend, Capturer, Device, Stream},
+    config::{ColorResolution, DepthMode, Mode},
+    libk4a::{
+        self, sys, Calibration, Capture, ColorControlCommand, ColorControlMode, ImageType,
+        Playback, RecordImage, Recording,
+    },
+    meta::K4aCalibrationMeta,
+    K4a, K4aDepthAlign, K4aPlayback, K4aRecord,
+};
+
+const FRAME_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A factory calibration in the JSON format devices store it in.
+const CALIBRATION: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/k4a/calibration.json"
+);
+
+/// Initializes GStreamer, returning whether libk4a could be loaded. Even without a device the
+/// captures are made by libk4a, so the tests needing it are ignored unless asked for.
+fn init() -> bool {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| gstreamer::init().unwrap());
+    libk4a::load().is_ok()
+}
+
+/// Like [`init`], additionally requiring libk4arecord.
+fn init_record() -> bool {
+    init() && libk4a::load_record().is_ok()
+}
+
+/// A path in the temporary directory that is unique to the test.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{}", std::process::id(), name))
+}
+
+/// Writes a recording of `frames` blank 320x288 depth images at 30 fps, with the fixture
+/// calibration attached.
+fn write_recording(path: &Path, frames: u32) {
+    let config = libk4a::DeviceConfiguration {
+        color_resolution: sys::k4a_color_resolution_t::K4A_COLOR_RESOLUTION_OFF,
+        depth_mode: sys::k4a_depth_mode_t::K4A_DEPTH_MODE_NFOV_2X2BINNED,
+        camera_fps: sys::k4a_fps_t::K4A_FRAMES_PER_SECOND_30,
+        ..Default::default()
+    };
+    let recording = Recording::create(path, config).unwrap();
+    recording
+        .add_calibration(&std::fs::read(CALIBRATION).unwrap())
+        .unwrap();
+    recording.write_header().unwrap();
+    for frame in 0..frames {
+        recording
+            .write_image(RecordImage {
+                image_type: ImageType::Depth,
+                format: sys::k4a_image_format_t::K4A_IMAGE_FORMAT_DEPTH16,
+                width: 320,
+                height: 288,
+                stride: 640,
+                device_timestamp: Duration::from_micros(33_333) * frame,
+                data: vec![0u8; 640 * 288],
+            })
+            .unwrap();
+    }
+    recording.flush().unwrap();
+}
+
+/// Hands out devices whose first stream fails after a few captures, as if the device dropped off
+/// the bus.
+struct Disconnecting {
+    captures_before_failure: u32,
+    opened: Arc<AtomicU32>,
+}
+
+impl Backend for Disconnecting {
+    fn open(&self, _serial_number: Option<&str>) -> Result<Box<dyn Device>, sys::k4a_result_t> {
+        let opened = self.opened.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(FakeDevice {
+            fail_after: (opened == 0).then_some(self.captures_before_failure),
+        }))
+    }
+}
+
+struct FakeDevice {
+    fail_after: Option<u32>,
+}
+
+impl Device for FakeDevice {
+    fn serial_number(&self) -> Result<String, sys::k4a_buffer_result_t> {
+        Ok("000000000000".to_owned())
+    }
+
+    fn start_cameras(
+        self: Box<Self>,
+        config: libk4a::DeviceConfiguration,
+    ) -> Result<Box<dyn Stream>, (Box<dyn Device>, sys::k4a_result_t)> {
+        let Some(depth_mode) = DepthMode::from_sys(config.depth_mode) else {
+            return Err((
+                self as Box<dyn Device>,
+                sys::k4a_result_t::K4A_RESULT_FAILED,
+            ));
+        };
+        let (width, height) = depth_mode.dimensions();
+        Ok(Box::new(FakeStream {
+            device: *self,
+            width: width as u32,
+            height: height as u32,
+        }))
+    }
+
+    fn get_calibration(
+        &self,
+        _depth_mode: sys::k4a_depth_mode_t,
+        _color_resolution: sys::k4a_color_resolution_t,
+    ) -> Result<Calibration, sys::k4a_result_t> {
+        Err(sys::k4a_result_t::K4A_RESULT_FAILED)
+    }
+
+    fn get_raw_calibration(&self) -> Result<Vec<u8>, sys::k4a_buffer_result_t> {
+        Err(sys::k4a_buffer_result_t::K4A_BUFFER_RESULT_FAILED)
+    }
+
+    fn set_color_control(
+        &self,
+        _command: ColorControlCommand,
+        _mode: ColorControlMode,
+        _value: i32,
+    ) -> Result<(), sys::k4a_result_t> {
+        Ok(())
+    }
+
+    fn get_color_control(
+        &self,
+        _command: ColorControlCommand,
+    ) -> Result<(ColorControlMode, i32), sys::k4a_result_t> {
+        Err(sys::k4a_result_t::K4A_RESULT_FAILED)
+    }
+}
+
+struct FakeStream {
+    device: FakeDevice,
+    width: u32,
+    height: u32,
+}
+
+impl Stream for FakeStream {
+    fn device(&self) -> &dyn Device {
+        &self.device
+    }
+
+    fn capturer(&self) -> Box<dyn Capturer> {
+        Box::new(FakeCapturer {
+            width: self.width,
+            height: self.height,
+            fail_after: self.device.fail_after,
+            count: AtomicU32::new(0),
+        })
+    }
+
+    fn stop_cameras(self: Box<Self>) -> Box<dyn Device> {
+        Box::new(self.device)
+    }
+}
+
+struct FakeCapturer {
+    width: u32,
+    height: u32,
+    fail_after: Option<u32>,
+    count: AtomicU32,
+}
+
+impl Capturer for FakeCapturer {
+    fn get_capture(&self, _timeout: Duration) -> Result<Capture, sys::k4a_wait_result_t> {
+        let count = self.count.fetch_add(1, Ordering::SeqCst);
+        if self
+            .fail_after
+            .map_or(false, |fail_after| count >= fail_after)
+        {
+            return Err(sys::k4a_wait_result_t::K4A_WAIT_RESULT_FAILED);
+        }
+        std::thread::sleep(FRAME_INTERVAL);
+        Capture::with_depth_image(self.width, self.height, FRAME_INTERVAL * count)
+            .map_err(|_| sys::k4a_wait_result_t::K4A_WAIT_RESULT_FAILED)
+    }
+}
+
+#[test]
+#[ignore = "needs libk4a"]
+fn reconnects_after_device_loss() {
+    assert!(init(), "Could not load libk4a");
+    let opened = Arc::new(AtomicU32::new(0));
+    let element = K4a::with_backend(Arc::new(Disconnecting {
+        captures_before_failure: 3,
+        opened: opened.clone(),
+    }));
+    element.set_property_from_str("reconnect", "always");
+    element.set_property_from_str("leaky", "no");
+    let lost = Arc::new(AtomicU32::new(0));
+    element.connect("device-lost", false, {
+        let lost = lost.clone();
+        move |_| {
+            lost.fetch_add(1, Ordering::SeqCst);
+            None
+        }
+    });
+
+    let mut harness = Harness::with_element(&element, None, Some("src"));
+    harness.play();
+    harness.pull().unwrap();
+    for _ in 1..3 {
+        let buffer = harness.pull().unwrap();
+        assert!(!buffer.flags().contains(gstreamer::BufferFlags::DISCONT));
+    }
+
+    let buffer = harness.pull().unwrap();
+    assert!(buffer.flags().contains(gstreamer::BufferFlags::DISCONT));
+    assert_eq!(opened.load(Ordering::SeqCst), 2);
+    assert_eq!(lost.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+#[ignore = "needs libk4a and libk4arecord"]
+fn plays_back_recording() {
+    assert!(init_record(), "Could not load libk4a and libk4arecord");
+    let path = temp_path("playback.mkv");
+    write_recording(&path, 5);
+    let element = glib::Object::new::<K4aPlayback>(&[
+        ("location", &path.to_str().unwrap()),
+        ("mode", &Mode::Depth),
+    ]);
+
+    let mut harness = Harness::with_element(&element, None, Some("src"));
+    harness.play();
+    let mut last = None;
+    for _ in 0..5 {
+        let buffer = harness.pull().unwrap();
+        assert_eq!(buffer.size(), 320 * 288 * 2);
+        assert!(buffer.meta::<K4aCalibrationMeta>().is_some());
+        assert!(buffer.pts() > last);
+        last = buffer.pts();
+    }
+    let caps = harness.sinkpad().unwrap().current_caps().unwrap();
+    let structure = caps.structure(0).unwrap();
+    assert_eq!(structure.get::<i32>("width").unwrap(), 320);
+    assert_eq!(structure.get::<i32>("height").unwrap(), 288);
+    let eos = std::iter::from_fn(|| harness.pull_event().ok())
+        .any(|event| event.type_() == gstreamer::EventType::Eos);
+    assert!(eos);
+
+    drop(harness);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+#[ignore = "needs libk4a and libk4arecord"]
+fn records_depth() {
+    assert!(init_record(), "Could not load libk4a and libk4arecord");
+    let path = temp_path("record.mkv");
+    let element = glib::Object::new::<K4aRecord>(&[
+        ("location", &path.to_str().unwrap()),
+        ("calibration-file", &CALIBRATION),
+    ]);
+
+    let mut harness = Harness::with_element(&element, Some("depth"), Some("src"));
+    harness.play();
+    harness
+        .set_src_caps_str("video/x-raw,format=GRAY16_LE,width=320,height=288,framerate=30000/1001");
+    for frame in 0..3 {
+        let mut buffer = gstreamer::Buffer::from_mut_slice(vec![0u8; 320 * 288 * 2]);
+        buffer
+            .get_mut()
+            .unwrap()
+            .set_pts(gstreamer::ClockTime::from_mseconds(33) * frame);
+        harness.push(buffer).unwrap();
+    }
+    assert!(harness.push_event(gstreamer::event::Eos::new()));
+    let eos = std::iter::from_fn(|| harness.pull_event().ok())
+        .any(|event| event.type_() == gstreamer::EventType::Eos);
+    assert!(eos);
+    // the source pad only announces the end of the recording
+    assert!(harness.try_pull().is_none());
+    let caps = harness.sinkpad().unwrap().current_caps().unwrap();
+    assert_eq!(
+        caps.structure(0).unwrap().name(),
+        "application/x-k4a-recording"
+    );
+    drop(harness);
+
+    let playback = Playback::open(&path).unwrap();
+    let configuration = playback.get_record_configuration().unwrap();
+    assert_eq!(
+        configuration.depth_mode,
+        sys::k4a_depth_mode_t::K4A_DEPTH_MODE_NFOV_2X2BINNED
+    );
+    assert!(playback.get_calibration().is_ok());
+    let captures = std::iter::from_fn(|| playback.get_next_capture().ok()).count();
+    assert_eq!(captures, 3);
+
+    drop(playback);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+#[ignore = "needs libk4a"]
+fn aligns_depth_to_color_resolution() {
+    assert!(init(), "Could not load libk4a");
+    let element = glib::Object::new::<K4aDepthAlign>(&[
+        ("calibration-file", &CALIBRATION),
+        ("color-resolution", &ColorResolution::Res1080P),
+    ]);
+
+    let mut harness = Harness::with_element(&element, Some("depth"), Some("src"));
+    harness.play();
+    harness
+        .set_src_caps_str("video/x-raw,format=GRAY16_LE,width=320,height=288,framerate=30000/1001");
+    let buffer = harness
+        .push_and_pull(gstreamer::Buffer::from_mut_slice(vec![0u8; 320 * 288 * 2]))
+        .unwrap();
+    assert_eq!(buffer.size(), 1920 * 1080 * 2);
+    let caps = harness.sinkpad().unwrap().current_caps().unwrap();
+    let structure = caps.structure(0).unwrap();
+    assert_eq!(structure.get::<i32>("width").unwrap(), 1920);
+    assert_eq!(structure.get::<i32>("height").unwrap(), 1080);
+}
@@ -3,6 +3,8 @@ mod dcolorizer;
 mod frame;
 #[cfg(feature = "k4a")]
 mod k4a;
+#[cfg(any(feature = "k4a", feature = "theta"))]
+mod libusb_hotplug;
 mod macros;
 #[cfg(feature = "theta")]
 mod theta;
@@ -0,0 +1,140 @@
+use std::{
+    os::raw::{c_int, c_void},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+/// A libusb context with hotplug callbacks registered on it.
+struct Registration {
+    ctx: NonNull<sys::libusb_context>,
+    callbacks: Vec<sys::libusb_hotplug_callback_handle>,
+    /// Set by the callbacks, which point at it.
+    changed: Arc<AtomicBool>,
+}
+
+impl Registration {
+    unsafe fn new(vendor_id: u16) -> Option<Self> {
+        let mut ctx = std::ptr::null_mut();
+        if sys::libusb_init(&mut ctx as *mut _) != 0 {
+            return None;
+        }
+        let mut registration = Self {
+            ctx: NonNull::new(ctx)?,
+            callbacks: Vec::new(),
+            changed: Arc::new(AtomicBool::new(false)),
+        };
+        // one callback per event since older libusb types the event mask as an enum
+        for event in [
+            sys::LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED,
+            sys::LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+        ] {
+            let mut callback = 0;
+            if sys::libusb_hotplug_register_callback(
+                registration.ctx.as_ptr(),
+                event as _,
+                0,
+                vendor_id as c_int,
+                sys::LIBUSB_HOTPLUG_MATCH_ANY,
+                sys::LIBUSB_HOTPLUG_MATCH_ANY,
+                Some(hotplug_callback),
+                Arc::as_ptr(&registration.changed) as *mut c_void,
+                &mut callback as *mut _,
+            ) != 0
+            {
+                return None;
+            }
+            registration.callbacks.push(callback);
+        }
+        Some(registration)
+    }
+
+    /// Dispatches pending events, waiting up to 100 ms for one.
+    unsafe fn handle_events(&self) {
+        let mut timeout = sys::timeval {
+            tv_sec: 0,
+            tv_usec: 100_000,
+        };
+        sys::libusb_handle_events_timeout_completed(
+            self.ctx.as_ptr(),
+            &mut timeout as *mut _,
+            std::ptr::null_mut(),
+        );
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        unsafe {
+            for callback in self.callbacks.drain(..) {
+                sys::libusb_hotplug_deregister_callback(self.ctx.as_ptr(), callback);
+            }
+            sys::libusb_exit(self.ctx.as_ptr());
+        }
+    }
+}
+
+unsafe impl Send for Registration {}
+
+/// Watches for USB devices of one vendor arriving or leaving.
+pub struct HotplugMonitor {
+    running: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl HotplugMonitor {
+    /// Calls `on_change` from a background thread whenever a device of `vendor_id` arrived or
+    /// left. Returns `None` where libusb cannot report hotplug events.
+    pub fn start(vendor_id: u16, on_change: impl Fn() + Send + 'static) -> Option<Self> {
+        let registration = unsafe {
+            if sys::libusb_has_capability(sys::LIBUSB_CAP_HAS_HOTPLUG as _) == 0 {
+                return None;
+            }
+            Registration::new(vendor_id)?
+        };
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = std::thread::Builder::new()
+            .name("usb-hotplug".to_owned())
+            .spawn({
+                let running = running.clone();
+                move || {
+                    while running.load(Ordering::Acquire) {
+                        unsafe { registration.handle_events() };
+                        if registration.changed.swap(false, Ordering::AcqRel) {
+                            on_change();
+                        }
+                    }
+                }
+            })
+            .ok()?;
+        Some(Self { running, handle })
+    }
+
+    pub fn stop(self) {
+        self.running.store(false, Ordering::Release);
+        let _ = self.handle.join();
+    }
+}
+
+unsafe extern "C" fn hotplug_callback(
+    _ctx: *mut sys::libusb_context,
+    _device: *mut sys::libusb_device,
+    _event: sys::libusb_hotplug_event,
+    user_data: *mut c_void,
+) -> c_int {
+    (*(user_data as *const AtomicBool)).store(true, Ordering::Release);
+    // stay registered
+    0
+}
+
+mod sys {
+    #![allow(non_upper_case_globals)]
+    #![allow(non_camel_case_types)]
+    #![allow(non_snake_case)]
+    #![allow(dead_code)]
+
+    include!(concat!(env!("OUT_DIR"), "/usb.rs"));
+}
@@ -0,0 +1,134 @@
+use std::{sync::Arc, time::Duration};
+
+use super::libuvc_theta::{
+    FrameDescriptor, StreamParameters, UvcContext, UvcDevice, UvcDeviceHandle, UvcError, UvcFrame,
+    UvcStreamHandle,
+};
+
+/// Called with every frame a stream produces, from a thread owned by the backend.
+pub type FrameCallback = Box<dyn FnMut(&dyn Frame, &dyn StreamHandle) + Send + Sync>;
+
+/// A source of UVC devices: libuvc, or a simulation of it.
+pub trait Backend: Send + Sync {
+    fn find_devices(
+        &self,
+        vid: Option<i32>,
+        pid: Option<i32>,
+        serial_number: Option<&str>,
+    ) -> Result<Vec<Arc<dyn Device>>, UvcError>;
+}
+
+pub trait Device: Send + Sync {
+    /// The USB product ID, if the device can report it without being opened.
+    fn product_id(&self) -> Option<u16>;
+    fn serial_number(&self) -> Option<String>;
+    fn open(&self) -> Result<Box<dyn DeviceHandle>, UvcError>;
+}
+
+pub trait DeviceHandle: Send + Sync {
+    /// What the device can stream, or nothing if it cannot describe itself.
+    fn frame_descriptors(&self) -> Vec<FrameDescriptor>;
+
+    /// Starts streaming. The stream stops when the returned handle is dropped.
+    fn start_streaming(
+        self: Box<Self>,
+        params: StreamParameters,
+        callback: FrameCallback,
+    ) -> Result<Box<dyn StreamHandle>, UvcError>;
+}
+
+pub trait StreamHandle: Send + Sync {
+    fn frame_interval(&self) -> Duration;
+}
+
+pub trait Frame {
+    fn data(&self) -> &[u8];
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn sequence(&self) -> usize;
+    /// When the last data of the frame arrived, on the monotonic clock.
+    fn finish_timestamp(&self) -> Duration;
+}
+
+/// The clock libuvc stamps finished frames with.
+pub use crate::frame::monotonic_time;
+
+impl Backend for UvcContext {
+    fn find_devices(
+        &self,
+        vid: Option<i32>,
+        pid: Option<i32>,
+        serial_number: Option<&str>,
+    ) -> Result<Vec<Arc<dyn Device>>, UvcError> {
+        Ok(UvcContext::find_devices(self, vid, pid, serial_number)?
+            .into_iter()
+            .map(|device| Arc::new(device) as Arc<dyn Device>)
+            .collect())
+    }
+}
+
+impl Device for UvcDevice {
+    fn product_id(&self) -> Option<u16> {
+        self.descriptor()
+            .ok()
+            .map(|descriptor| descriptor.product_id)
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        self.descriptor()
+            .ok()
+            .and_then(|descriptor| descriptor.serial_number)
+    }
+
+    fn open(&self) -> Result<Box<dyn DeviceHandle>, UvcError> {
+        Ok(Box::new(UvcDevice::open(self)?))
+    }
+}
+
+impl DeviceHandle for UvcDeviceHandle {
+    fn frame_descriptors(&self) -> Vec<FrameDescriptor> {
+        UvcDeviceHandle::frame_descriptors(self)
+    }
+
+    fn start_streaming(
+        self: Box<Self>,
+        params: StreamParameters,
+        mut callback: FrameCallback,
+    ) -> Result<Box<dyn StreamHandle>, UvcError> {
+        let stream = UvcDeviceHandle::start_streaming(
+            *self,
+            params,
+            move |frame, _: &mut (), stream| callback(&frame, stream),
+            (),
+        )?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl StreamHandle for UvcStreamHandle {
+    fn frame_interval(&self) -> Duration {
+        UvcStreamHandle::frame_interval(self)
+    }
+}
+
+impl Frame for UvcFrame {
+    fn data(&self) -> &[u8] {
+        UvcFrame::data(self)
+    }
+
+    fn width(&self) -> usize {
+        UvcFrame::width(self)
+    }
+
+    fn height(&self) -> usize {
+        UvcFrame::height(self)
+    }
+
+    fn sequence(&self) -> usize {
+        UvcFrame::sequence(self)
+    }
+
+    fn finish_timestamp(&self) -> Duration {
+        UvcFrame::finish_timestamp(self)
+    }
+}
@@ -0,0 +1,167 @@
+const NAL_SLICE: u8 = 1;
+const NAL_IDR_SLICE: u8 = 5;
+const NAL_SEI: u8 = 6;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+/// An access unit delimiter allowing any slice type.
+const AUD: [u8; 6] = [0, 0, 0, 1, NAL_AUD, 0xf0];
+
+/// How H.264 is split into buffers, as in the `alignment` caps field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// One access unit per buffer, led by an access unit delimiter.
+    Au,
+    /// One NAL unit per buffer.
+    Nal,
+}
+
+impl Alignment {
+    pub fn from_caps_field(alignment: &str) -> Option<Self> {
+        match alignment {
+            "au" => Some(Alignment::Au),
+            "nal" => Some(Alignment::Nal),
+            _ => None,
+        }
+    }
+}
+
+fn nal_type(payload: &[u8]) -> Option<u8> {
+    payload.first().map(|header| header & 0x1f)
+}
+
+/// Splits an Annex B byte stream into NAL units, each returned with and without its start code.
+pub fn nal_units(stream: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= stream.len() {
+        if stream[i..i + 3] == [0, 0, 1] {
+            // a four byte start code has an extra leading zero
+            let start = if i > 0 && stream[i - 1] == 0 {
+                i - 1
+            } else {
+                i
+            };
+            starts.push((start, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &(start, payload))| {
+            let end = starts.get(n + 1).map_or(stream.len(), |next| next.0);
+            (&stream[start..end], &stream[payload..end])
+        })
+        .collect()
+}
+
+/// Groups the NAL units of an Annex B byte stream into access units, which is what the camera
+/// sends as one UVC frame.
+#[cfg(test)]
+pub fn access_units(stream: &[u8]) -> Vec<Vec<u8>> {
+    let mut access_units = Vec::new();
+    let mut current = Vec::new();
+    let mut has_slice = false;
+    for (nal, payload) in nal_units(stream) {
+        let Some(nal_type) = nal_type(payload) else {
+            continue;
+        };
+        let is_slice = matches!(nal_type, NAL_SLICE | NAL_IDR_SLICE);
+        // first_mb_in_slice is 0, coded as a single set bit, only in the first slice of a picture
+        let first_slice = is_slice && payload.get(1).map_or(false, |byte| byte & 0x80 != 0);
+        // SEI, SPS, PPS and access unit delimiters lead the access unit they belong to
+        let starts_access_unit =
+            has_slice && (matches!(nal_type, NAL_SEI..=NAL_AUD) || first_slice);
+        if starts_access_unit {
+            access_units.push(std::mem::take(&mut current));
+            has_slice = false;
+        }
+        current.extend_from_slice(nal);
+        has_slice |= is_slice;
+    }
+    if has_slice {
+        access_units.push(current);
+    }
+    access_units
+}
+
+pub struct AccessUnit {
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+}
+
+/// Normalizes the access units a camera sends so that every IDR frame can be decoded on its own.
+pub struct AccessUnitParser {
+    alignment: Alignment,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    waiting_for_idr: bool,
+}
+
+impl AccessUnitParser {
+    pub fn new(alignment: Alignment) -> Self {
+        Self {
+            alignment,
+            sps: None,
+            pps: None,
+            waiting_for_idr: true,
+        }
+    }
+
+    /// Parses one access unit, returning `None` while waiting for an IDR frame. SPS and PPS are
+    /// remembered and put in front of IDR frames that come without them, and access unit
+    /// delimiters are replaced by a single leading one for `Alignment::Au`.
+    pub fn parse(&mut self, frame: &[u8]) -> Option<AccessUnit> {
+        let nals = nal_units(frame);
+        let mut keyframe = false;
+        let (mut has_sps, mut has_pps) = (false, false);
+        for &(_, payload) in &nals {
+            match nal_type(payload) {
+                Some(NAL_SPS) => {
+                    self.sps.replace(payload.to_vec());
+                    has_sps = true;
+                }
+                Some(NAL_PPS) => {
+                    self.pps.replace(payload.to_vec());
+                    has_pps = true;
+                }
+                Some(NAL_IDR_SLICE) => keyframe = true,
+                _ => {}
+            }
+        }
+        if self.waiting_for_idr && !keyframe {
+            return None;
+        }
+        self.waiting_for_idr = false;
+
+        let mut data = Vec::with_capacity(frame.len() + AUD.len());
+        if self.alignment == Alignment::Au {
+            data.extend_from_slice(&AUD);
+        }
+        let mut inserted_parameter_sets = false;
+        for &(nal, payload) in &nals {
+            match nal_type(payload) {
+                Some(NAL_AUD) | None => continue,
+                Some(NAL_IDR_SLICE) if !inserted_parameter_sets => {
+                    inserted_parameter_sets = true;
+                    let missing = [
+                        (!has_sps).then_some(&self.sps),
+                        (!has_pps).then_some(&self.pps),
+                    ];
+                    for parameter_set in missing.into_iter().flatten().flatten() {
+                        data.extend_from_slice(&START_CODE);
+                        data.extend_from_slice(parameter_set);
+                    }
+                }
+                _ => {}
+            }
+            data.extend_from_slice(nal);
+        }
+        Some(AccessUnit { data, keyframe })
+    }
+}
@@ -0,0 +1,46 @@
+use std::ops::RangeInclusive;
+
+use super::h264::{nal_units, AccessUnit};
+
+/// NAL unit types of IRAP pictures (BLA, IDR, CRA and the reserved IRAP types), which decode
+/// without any earlier picture.
+const IRAP: RangeInclusive<u8> = 16..=23;
+
+fn nal_type(payload: &[u8]) -> Option<u8> {
+    payload.first().map(|header| (header >> 1) & 0x3f)
+}
+
+/// Flags the H.265 access units a camera sends as keyframes when they hold an IRAP picture.
+pub struct AccessUnitParser {
+    waiting_for_irap: bool,
+}
+
+impl AccessUnitParser {
+    pub fn new() -> Self {
+        Self {
+            waiting_for_irap: true,
+        }
+    }
+
+    /// Parses one access unit, returning `None` while waiting for an IRAP picture.
+    pub fn parse(&mut self, frame: &[u8]) -> Option<AccessUnit> {
+        let keyframe = nal_units(frame)
+            .iter()
+            .filter_map(|&(_, payload)| nal_type(payload))
+            .any(|nal_type| IRAP.contains(&nal_type));
+        if self.waiting_for_irap && !keyframe {
+            return None;
+        }
+        self.waiting_for_irap = false;
+        Some(AccessUnit {
+            data: frame.to_vec(),
+            keyframe,
+        })
+    }
+}
+
+impl Default for AccessUnitParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
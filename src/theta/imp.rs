@@ -1,4 +1,6 @@
-use std::sync::{RwLock, Weak};
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
 use std::time::Duration;
 use std::{str::FromStr, sync::Arc};
 
@@ -11,8 +13,14 @@ use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString, IntoStaticStr};
 
 use crate::{
-    frame::FrameData,
-    theta::libuvc_theta::{UvcContext, UvcDevice, UvcFrame, UvcStreamHandle},
+    frame::{ClockMapper, FrameQueue, LatencyTracker, Leaky, PopResult},
+    libusb_hotplug::HotplugMonitor,
+    theta::{
+        backend::{Backend, Device, DeviceHandle, Frame, StreamHandle},
+        h264::{self, nal_units, AccessUnit, Alignment},
+        h265,
+        libuvc_theta::{FrameDescriptor, FrameFormat, UvcContext, UvcError},
+    },
 };
 use crate::{macros::set_field, theta::libuvc_theta::StreamParameters};
 
@@ -24,9 +32,11 @@ static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
     )
 });
 
-const USBVID_RICOH: u16 = 0x05ca;
-const USBPID_THETAV_UVC: u16 = 0x2712;
+pub(super) const USBVID_RICOH: u16 = 0x05ca;
+pub(super) const USBPID_THETAV_UVC: u16 = 0x2712;
 const USBPID_THETAZ1_UVC: u16 = 0x2715;
+// as defined in Ricoh's libuvc-theta-sample
+const USBPID_THETAX_UVC: u16 = 0x2717;
 
 #[derive(Debug, Clone)]
 struct Settings {
@@ -35,8 +45,16 @@ struct Settings {
     fps: u32,
     mode: Mode,
     product: Product,
+    vendor_id: u32,
+    product_id: u32,
     serial_number: String,
     device_index: u32,
+    max_buffers: u32,
+    leaky: Leaky,
+    timeout: u32,
+    reconnect: ReconnectPolicy,
+    reconnect_attempts: u32,
+    timestamp_mode: TimestampMode,
 }
 
 impl Default for Settings {
@@ -56,7 +74,15 @@ impl Default for Settings {
             device_index: 0,
             mode,
             product: Product::AnyProduct,
+            vendor_id: 0,
+            product_id: 0,
             serial_number: "".to_owned(),
+            max_buffers: 4,
+            leaky: Leaky::Downstream,
+            timeout: 5000,
+            reconnect: ReconnectPolicy::Always,
+            reconnect_attempts: 0,
+            timestamp_mode: TimestampMode::Capture,
         }
     }
 }
@@ -69,38 +95,687 @@ enum SettingField {
     Fps,
     Mode,
     Product,
+    VendorId,
+    ProductId,
     SerialNumber,
     DeviceIndex,
+    MaxBuffers,
+    Leaky,
+    Timeout,
+    Stats,
+    Reconnect,
+    ReconnectAttempts,
+    TimestampMode,
+    CurrentLatency,
 }
 
 struct State {
-    device: Option<Arc<UvcDevice>>,
-    stream: Option<UvcStreamHandle>,
+    backend: Option<Arc<dyn Backend>>,
+    /// What the device was looked up by, to find it again after it drops off the bus.
+    vid: Option<i32>,
+    pid: Option<i32>,
+    serial_number: Option<String>,
+    device: Option<Arc<dyn Device>>,
+    /// The device once opened, until streaming starts on negotiation.
+    handle: Option<Box<dyn DeviceHandle>>,
+    frame_descriptors: Vec<FrameDescriptor>,
+    stream: Option<Box<dyn StreamHandle>>,
+    /// Cancels pushes from the current stream, so it can be stopped while the queue is full.
+    stream_cancelled: Arc<AtomicBool>,
+    params: Option<StreamParameters>,
+    alignment: Alignment,
+    monitor: Option<HotplugMonitor>,
+    /// Set after reconnecting, so the next buffer is flagged DISCONT.
+    discont: bool,
+    clock_mapper: ClockMapper,
+    /// Latency last reported in latency queries, once it was measured.
+    latency: Option<gstreamer::ClockTime>,
+    /// The offset the next buffer should have if no frame went missing.
+    next_offset: Option<u64>,
+    last_end: Option<gstreamer::ClockTime>,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
+            backend: None,
+            vid: None,
+            pid: None,
+            serial_number: None,
             device: None,
+            handle: None,
+            frame_descriptors: Vec::new(),
             stream: None,
+            stream_cancelled: Arc::new(AtomicBool::new(false)),
+            params: None,
+            alignment: Alignment::Au,
+            monitor: None,
+            discont: false,
+            clock_mapper: ClockMapper::default(),
+            latency: None,
+            next_offset: None,
+            last_end: None,
+        }
+    }
+}
+
+struct CapturedFrame {
+    buffer: gstreamer::Buffer,
+    /// When libuvc finished receiving the frame, on the monotonic clock.
+    finish: Duration,
+    /// Pipeline clock time at which the frame was handed to the element.
+    arrival: Option<gstreamer::ClockTime>,
+}
+
+/// What the camera side hands to the streaming thread.
+enum Delivery {
+    Frame(CapturedFrame),
+    /// The device dropped off the bus, so no more frames are coming from this stream.
+    DeviceLost,
+}
+
+fn is_delta_unit(delivery: &Delivery) -> bool {
+    match delivery {
+        Delivery::Frame(frame) => frame
+            .buffer
+            .flags()
+            .contains(gstreamer::BufferFlags::DELTA_UNIT),
+        Delivery::DeviceLost => false,
+    }
+}
+
+/// Counts frames as they are handed from the camera to the streaming thread.
+#[derive(Default)]
+struct Stats {
+    captured: AtomicU64,
+    /// Dropped by the element to keep the queue bounded, along with the frames depending on them.
+    dropped: AtomicU64,
+    /// Never delivered by libuvc, going by the sequence numbers of the frames that were.
+    lost: AtomicU64,
+}
+
+impl Stats {
+    fn reset(&self) {
+        self.captured.store(0, Ordering::Relaxed);
+        self.dropped.store(0, Ordering::Relaxed);
+        self.lost.store(0, Ordering::Relaxed);
+    }
+
+    fn structure(&self) -> gstreamer::Structure {
+        gstreamer::Structure::builder("thetauvcsrc-stats")
+            .field("captured", self.captured.load(Ordering::Relaxed))
+            .field("dropped", self.dropped.load(Ordering::Relaxed))
+            .field("lost", self.lost.load(Ordering::Relaxed))
+            .build()
+    }
+}
+
+/// The camera's end of the frame queue. For codecs a dropped frame breaks every frame after it
+/// up to the next keyframe, so those are dropped along with it.
+struct FrameSink {
+    element: glib::WeakRef<super::ThetaUvc>,
+    frames: Arc<FrameQueue<Delivery>>,
+    cancelled: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    leaky: Leaky,
+    codec: bool,
+    skipping_to_keyframe: bool,
+    /// Set from the first dropped group of pictures until a delta frame gets through again, so
+    /// that a queue that stays full is only warned about once.
+    overflowing: bool,
+    next_sequence: Option<usize>,
+}
+
+impl FrameSink {
+    fn track_sequence(&mut self, sequence: usize) {
+        if let Some(expected) = self.next_sequence.filter(|&expected| sequence > expected) {
+            let lost = (sequence - expected) as u64;
+            self.stats.lost.fetch_add(lost, Ordering::Relaxed);
+            gstreamer::debug!(CAT, "Camera lost {} frames before frame {}", lost, sequence);
+        }
+        self.next_sequence = Some(sequence + 1);
+    }
+
+    fn push(&mut self, frame: CapturedFrame) {
+        self.stats.captured.fetch_add(1, Ordering::Relaxed);
+        let keyframe = !frame
+            .buffer
+            .flags()
+            .contains(gstreamer::BufferFlags::DELTA_UNIT);
+        if self.skipping_to_keyframe {
+            if !keyframe {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            self.skipping_to_keyframe = false;
+        }
+        if self
+            .frames
+            .push(Delivery::Frame(frame), &self.cancelled)
+            .is_none()
+        {
+            self.overflowing &= keyframe;
+            return;
+        }
+        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        if self.frames.is_flushing() || self.cancelled.load(Ordering::Acquire) {
+            // nothing is lost on anyone, but decoding resumes at a keyframe afterwards
+            self.skipping_to_keyframe = self.codec;
+            return;
+        }
+        if !self.codec {
+            gstreamer::trace!(CAT, "Dropped frame");
+            return;
+        }
+
+        self.skipping_to_keyframe = match self.leaky {
+            // waits for space rather than dropping
+            Leaky::No => return,
+            // the new frame was dropped, and the frames after it refer back to it
+            Leaky::Upstream => true,
+            // the oldest frame was dropped, breaking the delta frames queued behind it
+            Leaky::Downstream => {
+                let dropped = self.frames.drop_while(is_delta_unit);
+                self.stats
+                    .dropped
+                    .fetch_add(dropped as u64, Ordering::Relaxed);
+                // the new frame went too unless a keyframe was queued
+                self.frames.is_empty()
+            }
+        };
+        if std::mem::replace(&mut self.overflowing, true) {
+            gstreamer::debug!(CAT, "Frame queue still full, dropping to the next keyframe");
+            return;
+        }
+        if let Some(element) = self.element.upgrade() {
+            gstreamer::element_warning!(
+                element,
+                gstreamer::CoreError::Failed,
+                ("Frame queue full, dropping reference frames up to the next keyframe")
+            );
         }
     }
 }
 
 pub struct ThetaUvc {
+    /// Finds cameras instead of libuvc when set.
+    backend: RwLock<Option<Arc<dyn Backend>>>,
     settings: RwLock<Settings>,
     state: RwLock<State>,
-    frame_data: RwLock<Option<Arc<FrameData<gstreamer::Buffer>>>>,
+    latency_tracker: LatencyTracker,
+    frames: Arc<FrameQueue<Delivery>>,
+    stats: Arc<Stats>,
+}
+
+/// A format and frame size to offer in caps, with the framerates available at it.
+struct CapsEntry {
+    format: FrameFormat,
+    width: u32,
+    height: u32,
+    framerates: Vec<gstreamer::Fraction>,
 }
 
 impl ThetaUvc {
+    #[cfg(test)]
+    pub(super) fn set_backend(&self, backend: Arc<dyn Backend>) {
+        self.backend.write().unwrap().replace(backend);
+    }
+
+    fn format_structure(format: FrameFormat) -> gstreamer::Structure {
+        match format {
+            FrameFormat::H264 => gstreamer::Structure::builder("video/x-h264")
+                .field("stream-format", "byte-stream")
+                .field("profile", "constrained-baseline")
+                .field("alignment", gstreamer::List::new(["au", "nal"]))
+                .build(),
+            FrameFormat::H265 => gstreamer::Structure::builder("video/x-h265")
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au")
+                .build(),
+            FrameFormat::Mjpeg => gstreamer::Structure::new_empty("image/jpeg"),
+            FrameFormat::Yuy2 => gstreamer::Structure::builder("video/x-raw")
+                .field("format", "YUY2")
+                .build(),
+            FrameFormat::Nv12 => gstreamer::Structure::builder("video/x-raw")
+                .field("format", "NV12")
+                .build(),
+        }
+    }
+
+    fn format_from_structure(structure: &gstreamer::StructureRef) -> Option<FrameFormat> {
+        match structure.name() {
+            "video/x-h264" => Some(FrameFormat::H264),
+            "video/x-h265" => Some(FrameFormat::H265),
+            "image/jpeg" => Some(FrameFormat::Mjpeg),
+            "video/x-raw" => match structure.get::<&str>("format").ok()? {
+                "YUY2" => Some(FrameFormat::Yuy2),
+                "NV12" => Some(FrameFormat::Nv12),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     fn camera_caps() -> gstreamer::Caps {
-        gstreamer::Caps::builder("video/x-h264")
-            .field("stream-format", "byte-stream")
-            .field("profile", "constrained-baseline")
-            .field("alignment", "nal")
+        FrameFormat::ALL
+            .into_iter()
+            .fold(gstreamer::Caps::builder_full(), |builder, format| {
+                builder.structure(Self::format_structure(format))
+            })
+            .build()
+    }
+
+    /// One structure per streaming preset of `product`, describing what a device can produce.
+    pub(super) fn preset_caps(product: Product) -> gstreamer::Caps {
+        Self::sized_caps(Self::preset_entries(product))
+    }
+
+    /// The presets `product` streams over UVC.
+    fn preset_entries(product: Product) -> Vec<CapsEntry> {
+        product
+            .supported_modes()
+            .iter()
+            .filter_map(|mode| mode.get_mode_settings())
+            .map(|preset| CapsEntry {
+                format: FrameFormat::H264,
+                width: preset.width,
+                height: preset.height,
+                framerates: vec![gstreamer::Fraction::new(preset.fps as i32, 1)],
+            })
+            .collect()
+    }
+
+    /// The product of the opened device, or the one set if the device does not identify as one.
+    fn product(state: &State, settings: &Settings) -> Product {
+        state
+            .device
+            .as_ref()
+            .and_then(|device| device.product_id())
+            .map(Product::from_product_id)
+            .filter(|&product| product != Product::AnyProduct)
+            .unwrap_or(settings.product)
+    }
+
+    /// Everything in `descriptors`, as a device describes what it can stream.
+    pub(super) fn descriptor_caps(descriptors: &[FrameDescriptor]) -> gstreamer::Caps {
+        Self::sized_caps(Self::descriptor_entries(descriptors))
+    }
+
+    fn descriptor_entries(descriptors: &[FrameDescriptor]) -> Vec<CapsEntry> {
+        descriptors
+            .iter()
+            .map(|descriptor| CapsEntry {
+                format: descriptor.format,
+                width: descriptor.width,
+                height: descriptor.height,
+                framerates: descriptor
+                    .intervals
+                    .iter()
+                    .map(|&interval| gstreamer::Fraction::new(10_000_000, interval as i32))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// One structure per format and frame size, listing the framerates available at it.
+    fn sized_caps(entries: Vec<CapsEntry>) -> gstreamer::Caps {
+        entries
+            .into_iter()
+            .fold(gstreamer::Caps::builder_full(), |builder, entry| {
+                let mut structure = Self::format_structure(entry.format);
+                structure.set("width", entry.width as i32);
+                structure.set("height", entry.height as i32);
+                match entry.framerates.as_slice() {
+                    [framerate] => structure.set("framerate", *framerate),
+                    _ => structure.set("framerate", gstreamer::List::new(entry.framerates)),
+                }
+                builder.structure(structure)
+            })
             .build()
     }
+
+    /// What the open device can stream, or the presets of the product before it is opened.
+    /// Structures and framerates matching the width, height and fps properties come first, so
+    /// they are what fixating picks unless downstream asks for something else. Otherwise formats
+    /// are preferred in the order of `FrameFormat::ALL`, compressed ones first.
+    fn stream_caps(&self) -> gstreamer::Caps {
+        let settings = self.settings.read().unwrap().clone();
+        let state = self.state.read().unwrap();
+        let mut entries = if state.frame_descriptors.is_empty() {
+            Self::preset_entries(Self::product(&state, &settings))
+        } else {
+            Self::descriptor_entries(&state.frame_descriptors)
+        };
+        entries.sort_by_key(|entry| {
+            (
+                (entry.width, entry.height) != (settings.width, settings.height),
+                FrameFormat::ALL
+                    .iter()
+                    .position(|&format| format == entry.format),
+                Reverse(entry.width * entry.height),
+            )
+        });
+        for entry in entries.iter_mut() {
+            entry.framerates.sort_by_key(|&framerate| {
+                let fps = fps(framerate);
+                (fps != settings.fps, Reverse(fps))
+            });
+            entry.framerates.dedup();
+        }
+        Self::sized_caps(entries)
+    }
+
+    /// Starts streaming `params` from `device_handle`, with a fresh parser so that the stream
+    /// begins at a keyframe.
+    fn start_stream(
+        &self,
+        state: &mut State,
+        device_handle: Box<dyn DeviceHandle>,
+        params: StreamParameters,
+        alignment: Alignment,
+        settings: &Settings,
+    ) -> Result<(), String> {
+        let mut parser = Parser::new(params.format, alignment);
+        self.frames
+            .configure(settings.max_buffers as usize, settings.leaky);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut sink = FrameSink {
+            element: self.instance().downgrade(),
+            frames: self.frames.clone(),
+            cancelled: cancelled.clone(),
+            stats: self.stats.clone(),
+            leaky: settings.leaky,
+            codec: matches!(params.format, FrameFormat::H264 | FrameFormat::H265),
+            skipping_to_keyframe: false,
+            overflowing: false,
+            next_sequence: None,
+        };
+
+        let stream_handle = device_handle
+            .start_streaming(
+                params,
+                Box::new(move |frame, stream_handle| {
+                    on_frame_callback(frame, parser.as_mut(), &mut sink, stream_handle)
+                }),
+            )
+            .map_err(|err| format!("Cannot open device to begin streaming. Error: {:#?}", err))?;
+        state.stream.replace(stream_handle);
+        state.stream_cancelled = cancelled;
+        state.params.replace(params);
+        state.alignment = alignment;
+        // sequence numbers start over with the stream
+        state.next_offset = None;
+        Ok(())
+    }
+
+    /// Called on every hotplug event for the vendor. Wakes the streaming thread if the device
+    /// being streamed from is no longer on the bus.
+    fn check_device_present(&self) {
+        let (backend, vid, pid, serial_number, cancelled) = {
+            let state = self.state.read().unwrap();
+            if state.stream.is_none() {
+                return;
+            }
+            let (Some(backend), Some(serial_number)) =
+                (state.backend.clone(), state.serial_number.clone())
+            else {
+                // without a serial number the device cannot be told apart, leaving it to the
+                // stall timeout
+                return;
+            };
+            (
+                backend,
+                state.vid,
+                state.pid,
+                serial_number,
+                state.stream_cancelled.clone(),
+            )
+        };
+        let present = backend
+            .find_devices(vid, pid, Some(&serial_number))
+            .map_or(false, |devices| !devices.is_empty());
+        if !present {
+            gstreamer::info!(CAT, imp: self, "Device {} was removed", serial_number);
+            // with upstream leaking this can be dropped, in which case the timeout catches it
+            let _ = self.frames.push(Delivery::DeviceLost, &cancelled);
+        }
+    }
+
+    /// The pipeline clock time at which `frame` was captured, following the timestamp mode.
+    fn timestamp(
+        &self,
+        frame: &CapturedFrame,
+        timestamp_mode: TimestampMode,
+    ) -> Option<gstreamer::ClockTime> {
+        match timestamp_mode {
+            TimestampMode::Capture => {
+                let clock = self.instance().clock()?;
+                let mut state = self.state.write().unwrap();
+                state.clock_mapper.sample(&clock);
+                state.clock_mapper.map(frame.finish)
+            }
+            TimestampMode::Arrival => frame.arrival,
+        }
+    }
+
+    /// Tracks how long after their capture timestamp buffers leave the element and asks the
+    /// pipeline to recalculate its latency when that grew beyond what was last reported or clearly
+    /// dropped below it.
+    fn measure_latency(&self, latency: Duration) {
+        self.latency_tracker.update(latency);
+        let Some(measured) = self.latency_tracker.latency() else {
+            return;
+        };
+        let measured = gstreamer::ClockTime::from_nseconds(measured.as_nanos() as u64);
+        {
+            let mut state = self.state.write().unwrap();
+            // jitter alone should not keep the pipeline recalculating its latency
+            if state.latency.map_or(false, |reported| {
+                measured <= reported && measured.nseconds() * 4 >= reported.nseconds() * 3
+            }) {
+                return;
+            }
+            state.latency = Some(measured);
+        }
+        gstreamer::debug!(CAT, imp: self, "Measured latency {}", measured);
+        let message = gstreamer::message::Latency::builder()
+            .src(&*self.instance())
+            .build();
+        if self.instance().post_message(message).is_err() {
+            gstreamer::warning!(CAT, imp: self, "Could not post latency message");
+        }
+    }
+
+    /// Tells downstream that `missing` frames before `buffer` never made it, whether libuvc lost
+    /// them or the queue dropped them: the buffer is flagged DISCONT, a gap event covers the time
+    /// they would have taken and a QoS message reports the loss.
+    fn handle_gap(
+        &self,
+        buffer: &mut gstreamer::Buffer,
+        missing: u64,
+        last_end: Option<gstreamer::ClockTime>,
+    ) {
+        gstreamer::warning!(
+            CAT,
+            imp: self,
+            "{} frames missing before frame {}",
+            missing,
+            buffer.offset()
+        );
+        buffer.make_mut().set_flags(gstreamer::BufferFlags::DISCONT);
+        let (pts, duration) = (buffer.pts(), buffer.duration());
+        let Some(pts) = pts else {
+            return;
+        };
+
+        let start = last_end
+            .filter(|&end| end < pts)
+            .or_else(|| {
+                duration.map(|duration| {
+                    gstreamer::ClockTime::from_nseconds(
+                        pts.nseconds().saturating_sub(duration.nseconds() * missing),
+                    )
+                })
+            })
+            .filter(|&start| start < pts);
+        if let Some(start) = start {
+            let gap = gstreamer::ClockTime::from_nseconds(pts.nseconds() - start.nseconds());
+            if !self
+                .instance()
+                .src_pad()
+                .push_event(gstreamer::event::Gap::new(start, gap))
+            {
+                gstreamer::debug!(CAT, imp: self, "Gap event was not handled");
+            }
+        }
+
+        let dropped = self.stats.dropped.load(Ordering::Relaxed);
+        let lost = self.stats.lost.load(Ordering::Relaxed);
+        let processed = self
+            .stats
+            .captured
+            .load(Ordering::Relaxed)
+            .saturating_sub(dropped);
+        let message = gstreamer::message::Qos::builder(true, pts, pts, pts, duration)
+            .stats(
+                gstreamer::format::Buffers::from_u64(processed),
+                gstreamer::format::Buffers::from_u64(dropped + lost),
+            )
+            .src(&*self.instance())
+            .build();
+        if self.instance().post_message(message).is_err() {
+            gstreamer::warning!(CAT, imp: self, "Could not post QoS message");
+        }
+    }
+
+    fn post_device_message(&self, name: &str, serial_number: &str) {
+        let message = gstreamer::message::Element::builder(
+            gstreamer::Structure::builder(name)
+                .field("serial-number", serial_number)
+                .build(),
+        )
+        .src(&*self.instance())
+        .build();
+        if self.instance().post_message(message).is_err() {
+            gstreamer::warning!(CAT, imp: self, "Could not post {} message", name);
+        }
+    }
+
+    /// Finds the device again by its serial number, or by the device index if it has none.
+    fn find_device(
+        &self,
+        backend: &dyn Backend,
+        vid: Option<i32>,
+        pid: Option<i32>,
+        serial_number: Option<&str>,
+    ) -> Result<Arc<dyn Device>, UvcError> {
+        let index = match serial_number {
+            Some(_) => 0,
+            None => self.settings.read().unwrap().device_index as usize,
+        };
+        backend
+            .find_devices(vid, pid, serial_number)?
+            .into_iter()
+            .nth(index)
+            .ok_or(UvcError::UVC_ERROR_NO_DEVICE)
+    }
+
+    /// Reopens a camera that stopped streaming and restarts it with the stream it had. Returns
+    /// once streaming resumed, or with an error once the reconnect attempts run out or the
+    /// element starts flushing.
+    fn reconnect(&self) -> Result<(), gstreamer::FlowError> {
+        const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+        let settings = self.settings.read().unwrap().clone();
+        let (backend, vid, pid, serial_number, params, alignment) = {
+            let mut state = self.state.write().unwrap();
+            // unblock the camera thread if it waits for space, so the stream can be stopped,
+            // without touching the flushing set by unlock
+            self.frames.cancel_push(&state.stream_cancelled);
+            state.stream.take();
+            self.frames.clear();
+            // the stale device has to be released before it can be opened again
+            state.device.take();
+            (
+                state.backend.clone(),
+                state.vid,
+                state.pid,
+                state.serial_number.clone(),
+                state.params.take(),
+                state.alignment,
+            )
+        };
+        let (Some(backend), Some(params)) = (backend, params) else {
+            return Err(gstreamer::FlowError::NotNegotiated);
+        };
+        let serial_name = serial_number.clone().unwrap_or_default();
+
+        gstreamer::warning!(CAT, imp: self, "Lost device {}", serial_name);
+        self.instance()
+            .emit_by_name::<()>("device-lost", &[&serial_name]);
+        self.post_device_message("theta-device-lost", &serial_name);
+
+        let mut backoff = Duration::from_millis(100);
+        let mut attempts = 0;
+        loop {
+            // nothing is producing frames, so this only waits out the backoff unless unlocked
+            if let PopResult::Flushing = self.frames.pop(Some(backoff)) {
+                return Err(gstreamer::FlowError::Flushing);
+            }
+            attempts += 1;
+            gstreamer::info!(
+                CAT,
+                imp: self,
+                "Reconnecting to device {} (attempt {})",
+                serial_name,
+                attempts
+            );
+            let opened = self
+                .find_device(&*backend, vid, pid, serial_number.as_deref())
+                .and_then(|device| Ok((device.open()?, device)));
+            match opened {
+                Ok((device_handle, device)) => {
+                    let mut state = self.state.write().unwrap();
+                    state.device.replace(device);
+                    match self.start_stream(&mut state, device_handle, params, alignment, &settings)
+                    {
+                        Ok(()) => {
+                            state.discont = true;
+                            drop(state);
+                            self.post_device_message("theta-device-reconnected", &serial_name);
+                            return Ok(());
+                        }
+                        Err(err) => {
+                            // keep no device around so that the next attempt looks it up again
+                            state.device.take();
+                            gstreamer::warning!(CAT, imp: self, "{}", err);
+                        }
+                    }
+                }
+                Err(err) => {
+                    gstreamer::debug!(CAT, imp: self, "Could not open device. Error: {:#?}", err);
+                }
+            }
+            if settings.reconnect_attempts != 0 && attempts >= settings.reconnect_attempts {
+                gstreamer::element_imp_error!(
+                    self,
+                    gstreamer::ResourceError::NotFound,
+                    (
+                        "Could not reconnect to device {} after {} attempts.",
+                        serial_name,
+                        attempts
+                    )
+                );
+                return Err(gstreamer::FlowError::Error);
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Whole frames per second, which is how libuvc matches framerates.
+fn fps(framerate: gstreamer::Fraction) -> u32 {
+    (framerate.numer() / framerate.denom().max(1)) as u32
 }
 
 #[glib::object_subclass]
@@ -113,9 +788,15 @@ impl ObjectSubclass for ThetaUvc {
 
     fn new() -> Self {
         Self {
+            backend: RwLock::new(None),
             settings: RwLock::new(Settings::default()),
             state: RwLock::new(State::default()),
-            frame_data: RwLock::new(None),
+            latency_tracker: LatencyTracker::default(),
+            frames: Arc::new(FrameQueue::new(
+                Settings::default().max_buffers as usize,
+                Settings::default().leaky,
+            )),
+            stats: Arc::new(Stats::default()),
         }
     }
 }
@@ -128,25 +809,25 @@ impl ObjectImpl for ThetaUvc {
                     SettingField::Width => {
                         glib::ParamSpecUInt::builder(SettingField::Width.into())
                             .nick("Camera Width")
-                            .blurb("The width of the camera stream")
+                            .blurb("The default width of the camera stream, used unless downstream caps ask for another")
                             .build()
                     },
                     SettingField::Height => {
                         glib::ParamSpecUInt::builder(SettingField::Height.into())
                             .nick("Camera Height")
-                            .blurb("The height of the camera stream")
+                            .blurb("The default height of the camera stream, used unless downstream caps ask for another")
                             .build()
                     },
                     SettingField::Fps => {
                         glib::ParamSpecUInt::builder(SettingField::Fps.into())
                             .nick("Camera FPS")
-                            .blurb("The FPS to read from the camera")
+                            .blurb("The default FPS to read from the camera, used unless downstream caps ask for another")
                             .build()
                     },
                     SettingField::Mode => {
                         glib::ParamSpecEnum::builder(SettingField::Mode.into(), Settings::default().mode)
                             .nick("Stream Mode Presets")
-                            .blurb("Which preset to use for streaming")
+                            .blurb("Which preset to default to for streaming, setting width, height and fps")
                             .build()
                     },
                     SettingField::Product => {
@@ -155,6 +836,20 @@ impl ObjectImpl for ThetaUvc {
                             .blurb("The product type of the camera")
                             .build()
                     },
+                    SettingField::VendorId => {
+                        glib::ParamSpecUInt::builder(SettingField::VendorId.into())
+                            .nick("USB Vendor ID")
+                            .blurb("Overrides the USB vendor ID to look for (0 for Ricoh)")
+                            .maximum(u16::MAX as u32)
+                            .build()
+                    },
+                    SettingField::ProductId => {
+                        glib::ParamSpecUInt::builder(SettingField::ProductId.into())
+                            .nick("USB Product ID")
+                            .blurb("Overrides the USB product ID to look for (0 to use the one of the product)")
+                            .maximum(u16::MAX as u32)
+                            .build()
+                    },
                     SettingField::SerialNumber => {
                         glib::ParamSpecString::builder(SettingField::SerialNumber.into())
                             .nick("Device Serial Number")
@@ -167,12 +862,75 @@ impl ObjectImpl for ThetaUvc {
                             .blurb("Given a list of devices that matches the capabilities of the desired device, chooses which one to use")
                             .build()
                     },
+                    SettingField::MaxBuffers => {
+                        glib::ParamSpecUInt::builder(SettingField::MaxBuffers.into())
+                            .nick("Max Buffers")
+                            .blurb("Maximum number of frames to queue between the camera and the pipeline")
+                            .minimum(1)
+                            .default_value(Settings::default().max_buffers)
+                            .build()
+                    },
+                    SettingField::Leaky => {
+                        glib::ParamSpecEnum::builder(SettingField::Leaky.into(), Settings::default().leaky)
+                            .nick("Leaky")
+                            .blurb("Which frames to drop when the queue is full. Compressed streams drop up to the next keyframe")
+                            .build()
+                    },
+                    SettingField::Timeout => {
+                        glib::ParamSpecUInt::builder(SettingField::Timeout.into())
+                            .nick("Timeout")
+                            .blurb("Milliseconds to wait for a frame before treating the camera as stalled (0 to wait forever)")
+                            .default_value(Settings::default().timeout)
+                            .build()
+                    },
+                    SettingField::Stats => {
+                        glib::ParamSpecBoxed::builder::<gstreamer::Structure>(SettingField::Stats.into())
+                            .nick("Statistics")
+                            .blurb("Frames captured, dropped when the queue was full and lost by the camera since the stream started")
+                            .read_only()
+                            .build()
+                    },
+                    SettingField::Reconnect => {
+                        glib::ParamSpecEnum::builder(SettingField::Reconnect.into(), Settings::default().reconnect)
+                            .nick("Reconnect")
+                            .blurb("Whether to reopen the camera when it drops off the bus or stops streaming")
+                            .build()
+                    },
+                    SettingField::ReconnectAttempts => {
+                        glib::ParamSpecUInt::builder(SettingField::ReconnectAttempts.into())
+                            .nick("Reconnect Attempts")
+                            .blurb("How many times to try reopening a lost camera (0 for unlimited)")
+                            .default_value(Settings::default().reconnect_attempts)
+                            .build()
+                    },
+                    SettingField::TimestampMode => {
+                        glib::ParamSpecEnum::builder(SettingField::TimestampMode.into(), Settings::default().timestamp_mode)
+                            .nick("Timestamp Mode")
+                            .blurb("Which clock buffer timestamps are derived from")
+                            .build()
+                    },
+                    SettingField::CurrentLatency => {
+                        glib::ParamSpecUInt64::builder(SettingField::CurrentLatency.into())
+                            .nick("Current Latency")
+                            .blurb("Nanoseconds from the capture timestamp to the buffer leaving the element, averaged over recent frames (0 until measured or with arrival timestamps)")
+                            .read_only()
+                            .build()
+                    },
                 }
             }).collect()
         });
         PROPERTIES.as_ref()
     }
 
+    fn signals() -> &'static [glib::subclass::Signal] {
+        static SIGNALS: Lazy<Vec<glib::subclass::Signal>> = Lazy::new(|| {
+            vec![glib::subclass::Signal::builder("device-lost")
+                .param_types([String::static_type()])
+                .build()]
+        });
+        SIGNALS.as_ref()
+    }
+
     fn constructed(&self) {
         self.parent_constructed();
         let obj = self.instance();
@@ -207,12 +965,39 @@ impl ObjectImpl for ThetaUvc {
                     SettingField::Product => {
                         set_field!(CAT, self, field, enum settings.product, value)
                     }
+                    SettingField::VendorId => {
+                        set_field!(CAT, self, field, settings.vendor_id, value)
+                    }
+                    SettingField::ProductId => {
+                        set_field!(CAT, self, field, settings.product_id, value)
+                    }
                     SettingField::DeviceIndex => {
                         set_field!(CAT, self, field, settings.device_index, value)
                     }
                     SettingField::SerialNumber => {
                         set_field!(CAT, self, field, settings.serial_number, value)
                     }
+                    SettingField::MaxBuffers => {
+                        set_field!(CAT, self, field, settings.max_buffers, value)
+                    }
+                    SettingField::Leaky => {
+                        set_field!(CAT, self, field, enum settings.leaky, value)
+                    }
+                    SettingField::Timeout => {
+                        set_field!(CAT, self, field, settings.timeout, value)
+                    }
+                    SettingField::TimestampMode => {
+                        set_field!(CAT, self, field, enum settings.timestamp_mode, value)
+                    }
+                    SettingField::Stats | SettingField::CurrentLatency => {
+                        gstreamer::warning!(CAT, imp: self, "{} is read-only", pspec.name());
+                    }
+                    SettingField::Reconnect => {
+                        set_field!(CAT, self, field, enum settings.reconnect, value)
+                    }
+                    SettingField::ReconnectAttempts => {
+                        set_field!(CAT, self, field, settings.reconnect_attempts, value)
+                    }
                 };
             }
             Err(_err) => {
@@ -233,7 +1018,21 @@ impl ObjectImpl for ThetaUvc {
                     SettingField::DeviceIndex => settings.device_index.to_value(),
                     SettingField::Mode => settings.mode.to_value(),
                     SettingField::Product => settings.product.to_value(),
+                    SettingField::VendorId => settings.vendor_id.to_value(),
+                    SettingField::ProductId => settings.product_id.to_value(),
                     SettingField::SerialNumber => settings.serial_number.to_value(),
+                    SettingField::MaxBuffers => settings.max_buffers.to_value(),
+                    SettingField::Leaky => settings.leaky.to_value(),
+                    SettingField::Timeout => settings.timeout.to_value(),
+                    SettingField::Stats => self.stats.structure().to_value(),
+                    SettingField::Reconnect => settings.reconnect.to_value(),
+                    SettingField::ReconnectAttempts => settings.reconnect_attempts.to_value(),
+                    SettingField::TimestampMode => settings.timestamp_mode.to_value(),
+                    SettingField::CurrentLatency => self
+                        .latency_tracker
+                        .latency()
+                        .map_or(0, |latency| latency.as_nanos() as u64)
+                        .to_value(),
                 }
             }
             Err(_err) => {
@@ -251,7 +1050,7 @@ impl ElementImpl for ThetaUvc {
             gstreamer::subclass::ElementMetadata::new(
                 "Ricoh Theta Source",
                 "Source/Video",
-                "Ricoh Theta Source",
+                "Captures H.264, H.265, MJPEG or raw video from a Ricoh Theta or another UVC camera",
                 "William Zhang <wtzhang23@gmail.com>",
             )
         });
@@ -283,27 +1082,33 @@ impl ElementImpl for ThetaUvc {
                     let settings = self.settings.read().unwrap();
                     settings.clone()
                 };
-                let context = UvcContext::new().map_err(|err| {
-                    gstreamer::element_imp_error!(
-                        self,
-                        gstreamer::LibraryError::Init,
-                        ("Could not create a libuvc context. Error: {:#?}", err)
-                    );
-                    gstreamer::StateChangeError
-                })?;
-
-                let vid = Some(USBVID_RICOH).map(|i| i as i32);
-                let pid = match settings.product {
-                    Product::Z1 => Some(USBPID_THETAZ1_UVC),
-                    Product::V => Some(USBPID_THETAV_UVC),
-                    Product::AnyProduct => None,
+                let backend: Arc<dyn Backend> = match self.backend.read().unwrap().clone() {
+                    Some(backend) => backend,
+                    None => Arc::new(UvcContext::new().map_err(|err| {
+                        gstreamer::element_imp_error!(
+                            self,
+                            gstreamer::LibraryError::Init,
+                            ("Could not create a libuvc context. Error: {:#?}", err)
+                        );
+                        gstreamer::StateChangeError
+                    })?),
+                };
+
+                let vid = match settings.vendor_id {
+                    0 => Some(USBVID_RICOH),
+                    vid => Some(vid as u16),
+                }
+                .map(|i| i as i32);
+                let pid = match settings.product_id {
+                    0 => settings.product.product_id(),
+                    pid => Some(pid as u16),
                 }
                 .map(|i| i as i32);
                 let serial_number = match settings.serial_number.as_str() {
                     "" => None,
                     sn => Some(sn),
                 };
-                let device = context
+                let device = backend
                     .find_devices(vid, pid, serial_number)
                     .map_err(|err| {
                         gstreamer::element_imp_error!(
@@ -334,7 +1139,14 @@ impl ElementImpl for ThetaUvc {
                     })?;
                 if let Some(device) = device.into_iter().nth(settings.device_index as usize) {
                     let mut state = self.state.write().unwrap();
-                    state.device.replace(Arc::new(device));
+                    // reconnecting looks for this very device, not just any that matches
+                    state.serial_number = serial_number
+                        .map(|sn| sn.to_owned())
+                        .or_else(|| device.serial_number());
+                    state.vid = vid;
+                    state.pid = pid;
+                    state.backend.replace(backend);
+                    state.device.replace(device);
                 } else {
                     gstreamer::element_imp_error!(
                         self,
@@ -347,6 +1159,8 @@ impl ElementImpl for ThetaUvc {
             gstreamer::StateChange::ReadyToNull => {
                 let mut state = self.state.write().unwrap();
                 state.device.take();
+                state.backend.take();
+                state.serial_number.take();
             }
             _ => (),
         }
@@ -356,21 +1170,12 @@ impl ElementImpl for ThetaUvc {
 }
 
 impl BaseSrcImpl for ThetaUvc {
-    fn negotiate(&self) -> Result<(), gstreamer::LoggableError> {
-        if let Some(caps) = self.caps(None) {
-            self.instance()
-                .set_caps(&caps)
-                .map_err(|_| gstreamer::loggable_error!(CAT, "Failed to negotiate caps",))
-        } else {
-            Err(gstreamer::loggable_error!(CAT, "Failed to negotiate caps",))
-        }
-    }
-
     fn caps(&self, filter: Option<&gstreamer::Caps>) -> Option<gstreamer::Caps> {
-        let caps = Self::camera_caps();
+        let caps = self.stream_caps();
         if let Some(filter) = filter {
             if filter.can_intersect(&caps) {
-                Some(caps.intersect(filter))
+                // keep our order, which puts the defaults first
+                Some(caps.intersect_with_mode(filter, gstreamer::CapsIntersectMode::First))
             } else {
                 None
             }
@@ -379,21 +1184,95 @@ impl BaseSrcImpl for ThetaUvc {
         }
     }
 
-    fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
-        let settings = {
-            // settings span
-            let settings = self.settings.read().unwrap();
-            settings.clone()
+    fn set_caps(&self, caps: &gstreamer::Caps) -> Result<(), gstreamer::LoggableError> {
+        let structure = caps
+            .structure(0)
+            .ok_or_else(|| gstreamer::loggable_error!(CAT, "Empty caps"))?;
+        let (Some(format), Ok(width), Ok(height), Ok(framerate)) = (
+            Self::format_from_structure(structure),
+            structure.get::<i32>("width"),
+            structure.get::<i32>("height"),
+            structure.get::<gstreamer::Fraction>("framerate"),
+        ) else {
+            return Err(gstreamer::loggable_error!(
+                CAT,
+                "Caps {} do not have a known format and a fixed size and framerate",
+                caps
+            ));
         };
-
-        let frame_data = {
-            // frame data span
-            let mut frame_data = self.frame_data.write().unwrap();
-            let fd = Arc::new(FrameData::default());
-            let weak = Arc::downgrade(&fd);
-            frame_data.replace(fd);
-            weak
+        let params = StreamParameters {
+            format,
+            width: width as usize,
+            height: height as usize,
+            fps: fps(framerate) as usize,
+        };
+        let mode = Mode::from_parameters(&params);
+        // only H.264 can be split into NAL units, everything else comes a frame per buffer
+        let alignment = match format {
+            FrameFormat::H264 => structure
+                .get::<&str>("alignment")
+                .ok()
+                .and_then(Alignment::from_caps_field)
+                .unwrap_or(Alignment::Au),
+            _ => Alignment::Au,
         };
+        let settings = self.settings.read().unwrap().clone();
+
+        let mut state = self.state.write().unwrap();
+        if let Some(current) = state.params {
+            if current == params && state.alignment == alignment {
+                return Ok(());
+            }
+            return Err(gstreamer::loggable_error!(
+                CAT,
+                "Cannot change the stream from {:?} to {:?} while streaming",
+                current,
+                params
+            ));
+        }
+
+        let product = Self::product(&state, &settings);
+        if mode != Mode::NoMode && !product.supported_modes().contains(&mode) {
+            gstreamer::element_imp_error!(
+                self,
+                gstreamer::LibraryError::Settings,
+                (
+                    "The {:?} preset is not supported by the {:?}, which supports {:?}",
+                    mode,
+                    product,
+                    product.supported_modes()
+                )
+            );
+            return Err(gstreamer::loggable_error!(CAT, "Unsupported preset"));
+        }
+
+        let device_handle = state
+            .handle
+            .take()
+            .ok_or_else(|| gstreamer::loggable_error!(CAT, "Device not opened yet"))?;
+        gstreamer::info!(
+            CAT,
+            imp: self,
+            "Starting {:?} camera stream with width={},height={},fps={} (preset {:?}), {:?} aligned",
+            params.format,
+            params.width,
+            params.height,
+            params.fps,
+            mode,
+            alignment
+        );
+
+        self.start_stream(&mut state, device_handle, params, alignment, &settings)
+            .map_err(|err| {
+                gstreamer::element_imp_error!(self, gstreamer::LibraryError::Init, ("{}", err));
+                gstreamer::loggable_error!(CAT, "Failed to start streaming")
+            })
+    }
+
+    fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
+        self.latency_tracker.reset();
+        self.stats.reset();
+        let on_usb = self.backend.read().unwrap().is_none();
 
         let mut state = self.state.write().unwrap();
         let device = state.device.as_ref().ok_or_else(|| {
@@ -409,77 +1288,97 @@ impl BaseSrcImpl for ThetaUvc {
             )
         })?;
 
-        gstreamer::info!(
+        // streaming starts once caps are negotiated against what the device offers
+        state.frame_descriptors = device_handle.frame_descriptors();
+        gstreamer::debug!(
             CAT,
             imp: self,
-            "Starting camera stream with width={},height={},fps={}",
-            settings.width,
-            settings.height,
-            settings.fps
+            "Device offers {:?}",
+            state.frame_descriptors
         );
+        state.handle.replace(device_handle);
 
-        let stream_handle = device_handle
-            .start_streaming(
-                StreamParameters {
-                    width: settings.width as usize,
-                    height: settings.height as usize,
-                    fps: settings.fps as usize,
-                },
-                on_frame_callback,
-                frame_data,
-            )
-            .map_err(|err| {
-                gstreamer::error_msg!(
-                    gstreamer::LibraryError::Init,
-                    ("Cannot open device to begin streaming. Error: {:#?}", err)
-                )
-            })?;
-        state.stream.replace(stream_handle);
+        // a device that drops off the bus may just stop delivering frames, so removal is watched
+        // for separately. Cameras standing in for libuvc cannot be unplugged
+        if let (true, Some(vid)) = (on_usb, state.vid) {
+            let element = self.instance().downgrade();
+            state.monitor = HotplugMonitor::start(vid as u16, move || {
+                if let Some(element) = element.upgrade() {
+                    element.imp().check_device_present();
+                }
+            });
+            if state.monitor.is_none() {
+                gstreamer::info!(
+                    CAT,
+                    imp: self,
+                    "No hotplug support, relying on the timeout to notice lost devices"
+                );
+            }
+        }
 
         Ok(())
     }
 
     fn stop(&self) -> Result<(), gstreamer::ErrorMessage> {
+        // taken out first, since its thread needs the state to finish a check
+        let monitor = self.state.write().unwrap().monitor.take();
+        if let Some(monitor) = monitor {
+            monitor.stop();
+        }
         {
             let mut state = self.state.write().unwrap();
+            // unblock the camera thread if it waits for space, so the stream can be stopped,
+            // without touching the flushing set by unlock
+            self.frames.cancel_push(&state.stream_cancelled);
             state.stream.take();
+            self.frames.clear();
+            state.handle.take();
+            state.frame_descriptors.clear();
+            state.params.take();
+            state.discont = false;
+            state.clock_mapper.reset();
+            state.latency = None;
+            state.next_offset = None;
+            state.last_end = None;
         }
+        self.latency_tracker.reset();
+        Ok(())
+    }
+
+    fn unlock(&self) -> Result<(), gstreamer::ErrorMessage> {
+        self.frames.set_flushing(true);
+        Ok(())
+    }
+
+    fn unlock_stop(&self) -> Result<(), gstreamer::ErrorMessage> {
         {
-            self.frame_data.write().unwrap().take();
+            // frames dropped by the flush are not lost by the camera, nor a gap in the stream
+            let mut state = self.state.write().unwrap();
+            state.next_offset = None;
+            state.last_end = None;
         }
+        self.frames.set_flushing(false);
         Ok(())
     }
 
     fn query(&self, query: &mut gstreamer::QueryRef) -> bool {
         match query.view_mut() {
             gstreamer::QueryViewMut::Latency(latency) => {
-                latency.set(
-                    true,
-                    self.frame_data
-                        .read()
-                        .unwrap()
-                        .as_ref()
-                        .and_then(|fd| fd.get_latency())
-                        .map_or_else(
-                            || {
-                                gstreamer::ClockTime::from_nseconds(
-                                    Duration::from_secs_f64(
-                                        1001f64 / (self.settings.read().unwrap().fps * 1000) as f64,
-                                    )
-                                    .as_nanos() as u64,
-                                )
-                            },
-                            |stream| {
-                                gstreamer::info!(
-                                    CAT,
-                                    imp: self,
-                                    "Responding to query with live latency"
-                                );
-                                gstreamer::ClockTime::from_nseconds(stream.as_nanos() as u64)
-                            },
-                        ),
-                    Some(gstreamer::ClockTime::from_mseconds(100)),
-                );
+                let (params, measured) = {
+                    let state = self.state.read().unwrap();
+                    (state.params, state.latency)
+                };
+                // one frame period until frames were measured, and always with arrival timestamps
+                let min = measured.unwrap_or_else(|| {
+                    let fps = params
+                        .map(|params| params.fps as u32)
+                        .unwrap_or(self.settings.read().unwrap().fps)
+                        .max(1);
+                    gstreamer::ClockTime::from_nseconds(
+                        Duration::from_secs_f64(1001f64 / (fps * 1000) as f64).as_nanos() as u64,
+                    )
+                });
+                latency.set(true, min, None);
                 true
             }
             gstreamer::QueryViewMut::Caps(caps) => {
@@ -496,28 +1395,135 @@ impl PushSrcImpl for ThetaUvc {
         &self,
         _buffer: Option<&mut gstreamer::BufferRef>,
     ) -> Result<CreateSuccess, gstreamer::FlowError> {
-        let mut frame_data = self.frame_data.read().unwrap();
-        while let Some(fd) = frame_data.as_ref() {
-            if let Some(buffer) = fd.pop_frame() {
-                gstreamer::debug!(
-                    CAT,
-                    imp: self,
-                    "Got frame from camera. pts={:#?}, dts={:#?}, duration={:#?}",
-                    buffer.as_ref().pts(),
-                    buffer.as_ref().dts(),
-                    buffer.as_ref().duration()
-                );
-                return Ok(CreateSuccess::NewBuffer(buffer));
+        let (timeout, reconnect, timestamp_mode) = {
+            let settings = self.settings.read().unwrap();
+            (
+                settings.timeout,
+                settings.reconnect,
+                settings.timestamp_mode,
+            )
+        };
+        let wait = (timeout > 0).then(|| Duration::from_millis(timeout as u64));
+        let frame = loop {
+            match self.frames.pop(wait) {
+                PopResult::Frame(Delivery::Frame(frame)) => break frame,
+                PopResult::Frame(Delivery::DeviceLost) if reconnect == ReconnectPolicy::Always => {
+                    self.reconnect()?;
+                }
+                PopResult::Frame(Delivery::DeviceLost) => {
+                    gstreamer::element_imp_error!(
+                        self,
+                        gstreamer::ResourceError::NotFound,
+                        ("Camera disconnected.")
+                    );
+                    return Err(gstreamer::FlowError::Error);
+                }
+                // a stalled stream is how a camera that dropped off the bus shows without hotplug
+                PopResult::Timeout if reconnect == ReconnectPolicy::Always => {
+                    gstreamer::warning!(CAT, imp: self, "No frame within {} ms", timeout);
+                    self.reconnect()?;
+                }
+                PopResult::Timeout => {
+                    gstreamer::element_imp_error!(
+                        self,
+                        gstreamer::ResourceError::Read,
+                        ("Camera stalled: no frame within {} ms.", timeout)
+                    );
+                    return Err(gstreamer::FlowError::Error);
+                }
+                PopResult::Flushing => return Err(gstreamer::FlowError::Flushing),
+            }
+        };
+        let timestamp = self.timestamp(&frame, timestamp_mode);
+        // arrival timestamps are taken on the pipeline clock, so one frame period covers them
+        if timestamp_mode == TimestampMode::Capture {
+            if let Some((now, timestamp)) = self
+                .instance()
+                .clock()
+                .and_then(|clock| clock.time())
+                .zip(timestamp)
+            {
+                self.measure_latency(Duration::from_nanos(
+                    now.nseconds().saturating_sub(timestamp.nseconds()),
+                ));
             }
-            gstreamer::debug!(CAT, imp: self, "Sleeping until next frame");
-            let fd = fd.clone();
-            std::mem::drop(frame_data); // let another person use lock
-            fd.wait();
-            gstreamer::debug!(CAT, imp: self, "Woke up");
-            frame_data = self.frame_data.read().unwrap();
         }
-        Err(gstreamer::FlowError::Eos)
+        let mut buffer = frame.buffer;
+
+        let base_time = self.instance().base_time();
+        let pts = timestamp.zip(base_time).map(|(timestamp, base_time)| {
+            gstreamer::ClockTime::from_nseconds(
+                timestamp.nseconds().saturating_sub(base_time.nseconds()),
+            )
+        });
+        buffer.make_mut().set_pts(pts);
+        let (discont, gap) = {
+            let mut state = self.state.write().unwrap();
+            let offset = buffer.offset();
+            let missing = state
+                .next_offset
+                .filter(|&next| offset > next)
+                .map(|next| offset - next);
+            state.next_offset = Some(offset + 1);
+            let last_end = std::mem::replace(
+                &mut state.last_end,
+                pts.zip(buffer.duration())
+                    .map(|(pts, duration)| pts + duration),
+            );
+            (
+                std::mem::take(&mut state.discont),
+                missing.map(|missing| (missing, last_end)),
+            )
+        };
+        if let Some((missing, last_end)) = gap {
+            self.handle_gap(&mut buffer, missing, last_end);
+        }
+        if discont {
+            buffer.make_mut().set_flags(gstreamer::BufferFlags::DISCONT);
+        }
+        gstreamer::debug!(
+            CAT,
+            imp: self,
+            "Got frame from camera. pts={:#?}, dts={:#?}, duration={:#?}",
+            buffer.as_ref().pts(),
+            buffer.as_ref().dts(),
+            buffer.as_ref().duration()
+        );
+        if self.state.read().unwrap().alignment == Alignment::Nal {
+            return Ok(CreateSuccess::NewBufferList(split_nal_units(&buffer)?));
+        }
+        Ok(CreateSuccess::NewBuffer(buffer))
+    }
+}
+
+/// Splits an access unit into one buffer per NAL unit, each keeping the timestamps and flags of
+/// the access unit.
+fn split_nal_units(
+    buffer: &gstreamer::Buffer,
+) -> Result<gstreamer::BufferList, gstreamer::FlowError> {
+    let map = buffer
+        .map_readable()
+        .map_err(|_| gstreamer::FlowError::Error)?;
+    let nals = nal_units(map.as_slice());
+    let mut list = gstreamer::BufferList::new_sized(nals.len());
+    {
+        let list = list.get_mut().unwrap();
+        for (nal, _) in nals {
+            let offset = nal.as_ptr() as usize - map.as_ptr() as usize;
+            let nal_buffer = buffer
+                .copy_region(
+                    gstreamer::BufferCopyFlags::FLAGS
+                        | gstreamer::BufferCopyFlags::TIMESTAMPS
+                        | gstreamer::BufferCopyFlags::META
+                        | gstreamer::BufferCopyFlags::MEMORY,
+                    offset,
+                    Some(nal.len()),
+                )
+                .map_err(|_| gstreamer::FlowError::Error)?;
+            list.add(nal_buffer);
+        }
     }
+    Ok(list)
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
@@ -536,6 +1542,27 @@ struct ModeSettings {
 }
 
 impl Mode {
+    const PRESETS: [Mode; 2] = [Mode::Uhd, Mode::Fhd];
+
+    /// The preset streaming with `params`, if any.
+    fn from_parameters(params: &StreamParameters) -> Self {
+        if params.format != FrameFormat::H264 {
+            return Mode::NoMode;
+        }
+        Self::PRESETS
+            .into_iter()
+            .find(|mode| {
+                mode.get_mode_settings().map_or(false, |preset| {
+                    (
+                        preset.width as usize,
+                        preset.height as usize,
+                        preset.fps as usize,
+                    ) == (params.width, params.height, params.fps)
+                })
+            })
+            .unwrap_or(Mode::NoMode)
+    }
+
     fn get_mode_settings(&self) -> Option<ModeSettings> {
         match self {
             Mode::Uhd => Some(ModeSettings {
@@ -553,47 +1580,89 @@ impl Mode {
     }
 }
 
+/// Finds the keyframes of a compressed stream, holding it back until the first of them.
+enum Parser {
+    H264(h264::AccessUnitParser),
+    H265(h265::AccessUnitParser),
+}
+
+impl Parser {
+    fn new(format: FrameFormat, alignment: Alignment) -> Option<Self> {
+        match format {
+            FrameFormat::H264 => Some(Self::H264(h264::AccessUnitParser::new(alignment))),
+            FrameFormat::H265 => Some(Self::H265(h265::AccessUnitParser::new())),
+            FrameFormat::Mjpeg | FrameFormat::Yuy2 | FrameFormat::Nv12 => None,
+        }
+    }
+
+    fn parse(&mut self, frame: &[u8]) -> Option<AccessUnit> {
+        match self {
+            Self::H264(parser) => parser.parse(frame),
+            Self::H265(parser) => parser.parse(frame),
+        }
+    }
+}
+
 fn on_frame_callback(
-    frame: UvcFrame,
-    frame_data: &mut Weak<FrameData<gstreamer::Buffer>>,
-    stream_handle: &UvcStreamHandle,
+    frame: &dyn Frame,
+    parser: Option<&mut Parser>,
+    sink: &mut FrameSink,
+    stream_handle: &dyn StreamHandle,
 ) {
-    let Some(frame_data) = frame_data.upgrade() else {
-        return;
+    let arrival = sink
+        .element
+        .upgrade()
+        .and_then(|element| element.clock())
+        .and_then(|clock| clock.time());
+    sink.track_sequence(frame.sequence());
+    let (data, keyframe) = match parser {
+        Some(parser) => match parser.parse(frame.data()) {
+            Some(access_unit) => (access_unit.data, access_unit.keyframe),
+            None => {
+                gstreamer::debug!(CAT, "Dropping frame {} until a keyframe", frame.sequence());
+                return;
+            }
+        },
+        // JPEG and raw frames stand on their own
+        None => (frame.data().to_vec(), true),
     };
     gstreamer::debug!(CAT, "Creating buffer for frame");
-    let Ok(mut buffer) = gstreamer::Buffer::with_size(frame.data().len()) else {
-        return;
-    };
+    let mut buffer = gstreamer::Buffer::from_mut_slice(data);
     {
-        let Ok(mut mapped_writable) = buffer.make_mut().map_writable() else {
-            return;
-        };
-        mapped_writable.as_mut_slice().copy_from_slice(frame.data());
-    }
-
-    let span = {
-        let start_timestamp = frame_data.start_timestamp(frame.finish_timestamp());
-        let pts = frame.finish_timestamp().saturating_sub(start_timestamp);
-        let duration = stream_handle.frame_interval();
-
-        buffer
-            .make_mut()
-            .set_pts(Some(gstreamer::ClockTime::from_nseconds(
-                pts.as_nanos().try_into().unwrap(),
-            )));
-        buffer.make_mut().set_dts(None);
-        buffer
-            .make_mut()
-            .set_duration(Some(gstreamer::ClockTime::from_nseconds(
-                duration.as_nanos().try_into().unwrap(),
-            )));
-        buffer.make_mut().set_offset(frame.sequence() as u64);
-        frame_data.add_frame(buffer);
-        duration
-    };
+        let buffer = buffer.make_mut();
+        if !keyframe {
+            buffer.set_flags(gstreamer::BufferFlags::DELTA_UNIT);
+        }
+        // the timestamp is set on the streaming thread, which knows the timestamp mode
+        buffer.set_dts(None);
+        buffer.set_duration(Some(gstreamer::ClockTime::from_nseconds(
+            stream_handle.frame_interval().as_nanos() as u64,
+        )));
+        buffer.set_offset(frame.sequence() as u64);
+    }
+    sink.push(CapturedFrame {
+        buffer,
+        finish: frame.finish_timestamp(),
+        arrival,
+    });
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstThetaUvcReconnectPolicy")]
+enum ReconnectPolicy {
+    Never,
+    Always,
+}
 
-    frame_data.update_latency(span);
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstThetaUvcTimestampMode")]
+enum TimestampMode {
+    /// The time libuvc finished receiving the frame, mapped onto the pipeline clock.
+    Capture,
+    /// Pipeline clock time at which the frame was handed to the element, like do-timestamp.
+    Arrival,
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
@@ -603,4 +1672,35 @@ pub enum Product {
     Z1,
     V,
     AnyProduct,
+    X,
+}
+
+impl Product {
+    pub(super) fn from_product_id(product_id: u16) -> Self {
+        match product_id {
+            USBPID_THETAZ1_UVC => Product::Z1,
+            USBPID_THETAV_UVC => Product::V,
+            USBPID_THETAX_UVC => Product::X,
+            _ => Product::AnyProduct,
+        }
+    }
+
+    fn product_id(&self) -> Option<u16> {
+        match self {
+            Product::Z1 => Some(USBPID_THETAZ1_UVC),
+            Product::V => Some(USBPID_THETAV_UVC),
+            Product::X => Some(USBPID_THETAX_UVC),
+            Product::AnyProduct => None,
+        }
+    }
+
+    /// The presets the product can stream over UVC. Ricoh cameras that are not known here are
+    /// only trusted with 2K, unless the product is set.
+    fn supported_modes(&self) -> &'static [Mode] {
+        match self {
+            // 4K and 2K at 29.97 fps
+            Product::Z1 | Product::V | Product::X => &[Mode::Uhd, Mode::Fhd],
+            Product::AnyProduct => &[Mode::Fhd],
+        }
+    }
 }
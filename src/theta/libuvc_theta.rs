@@ -1,5 +1,5 @@
 use std::{
-    ffi::CString,
+    ffi::{CStr, CString},
     mem::MaybeUninit,
     os::raw::c_void,
     ptr::NonNull,
@@ -9,6 +9,8 @@ use std::{
 
 use self::sys::uvc_stream_ctrl_t;
 
+pub use self::sys::uvc_error as UvcError;
+
 type PossibleStream = Mutex<(Option<Box<dyn Stream>>, Arc<UvcStreamHandleWrapper>)>;
 
 struct UvcContextWrapper {
@@ -114,6 +116,29 @@ impl UvcDeviceWrapper {
     pub unsafe fn open(self: Arc<Self>) -> Result<UvcDeviceHandle, sys::uvc_error> {
         Ok(UvcDeviceHandle::new(UvcDeviceHandleWrapper::new(self)?))
     }
+
+    unsafe fn descriptor(&self) -> Result<DeviceDescriptor, sys::uvc_error> {
+        let mut desc = std::ptr::null_mut();
+        match sys::uvc_get_device_descriptor(self.dev.as_ptr(), &mut desc as *mut _) {
+            sys::uvc_error::UVC_SUCCESS => {}
+            err => return Err(err),
+        }
+        let string = |ptr: *const std::os::raw::c_char| {
+            NonNull::new(ptr as *mut std::os::raw::c_char)
+                .map(|ptr| CStr::from_ptr(ptr.as_ptr()).to_string_lossy().into_owned())
+        };
+        let descriptor = DeviceDescriptor {
+            vendor_id: (*desc).idVendor,
+            product_id: (*desc).idProduct,
+            serial_number: string((*desc).serialNumber),
+            manufacturer: string((*desc).manufacturer),
+            product: string((*desc).product),
+            bus_number: sys::uvc_get_bus_number(self.dev.as_ptr()),
+            device_address: sys::uvc_get_device_address(self.dev.as_ptr()),
+        };
+        sys::uvc_free_device_descriptor(desc);
+        Ok(descriptor)
+    }
 }
 
 impl Drop for UvcDeviceWrapper {
@@ -140,14 +165,90 @@ impl UvcDevice {
     pub fn open(&self) -> Result<UvcDeviceHandle, sys::uvc_error> {
         unsafe { self.inner.clone().open() }
     }
+
+    pub fn descriptor(&self) -> Result<DeviceDescriptor, sys::uvc_error> {
+        unsafe { self.inner.descriptor() }
+    }
+}
+
+/// What a device reports about itself over USB, read without opening it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub bus_number: u8,
+    pub device_address: u8,
+}
+
+/// The UVC formats that can be streamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameFormat {
+    H264,
+    H265,
+    Mjpeg,
+    Yuy2,
+    Nv12,
+}
+
+impl FrameFormat {
+    pub const ALL: [FrameFormat; 5] = [
+        FrameFormat::H264,
+        FrameFormat::H265,
+        FrameFormat::Mjpeg,
+        FrameFormat::Yuy2,
+        FrameFormat::Nv12,
+    ];
+
+    unsafe fn from_format_desc(format_desc: &sys::uvc_format_desc) -> Option<Self> {
+        // frame based and uncompressed formats are told apart by a GUID starting with the fourcc
+        let fourcc = &format_desc.__bindgen_anon_1.guidFormat[..4];
+        match format_desc.bDescriptorSubtype {
+            sys::uvc_vs_desc_subtype::UVC_VS_FORMAT_MJPEG => Some(FrameFormat::Mjpeg),
+            sys::uvc_vs_desc_subtype::UVC_VS_FORMAT_FRAME_BASED
+            | sys::uvc_vs_desc_subtype::UVC_VS_FORMAT_UNCOMPRESSED => match fourcc {
+                b"H264" => Some(FrameFormat::H264),
+                b"H265" | b"HEVC" => Some(FrameFormat::H265),
+                b"YUY2" => Some(FrameFormat::Yuy2),
+                b"NV12" => Some(FrameFormat::Nv12),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// How libuvc names the format, for the formats every libuvc version knows.
+    fn uvc_frame_format(&self) -> Option<sys::uvc_frame_format> {
+        match self {
+            FrameFormat::H264 => Some(sys::uvc_frame_format::UVC_FRAME_FORMAT_H264),
+            FrameFormat::Mjpeg => Some(sys::uvc_frame_format::UVC_FRAME_FORMAT_MJPEG),
+            FrameFormat::Yuy2 => Some(sys::uvc_frame_format::UVC_FRAME_FORMAT_YUYV),
+            FrameFormat::H265 | FrameFormat::Nv12 => None,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StreamParameters {
+    pub format: FrameFormat,
     pub width: usize,
     pub height: usize,
     pub fps: usize,
 }
 
+/// A format and frame size an open device can stream, with the frame intervals it supports at
+/// that size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameDescriptor {
+    pub format: FrameFormat,
+    pub width: u32,
+    pub height: u32,
+    /// In 100 ns units, as UVC counts them.
+    pub intervals: Vec<u32>,
+}
+
 struct UvcDeviceHandleWrapper {
     handle: NonNull<sys::uvc_device_handle>,
     streams: Mutex<Vec<Box<PossibleStream>>>,
@@ -169,6 +270,7 @@ impl UvcDeviceHandleWrapper {
 
     unsafe fn start_streaming<F, T>(
         self: &Arc<Self>,
+        format: FrameFormat,
         width: i32,
         height: i32,
         fps: i32,
@@ -183,7 +285,9 @@ impl UvcDeviceHandleWrapper {
         match sys::uvc_get_stream_ctrl_format_size(
             self.handle.as_ptr(),
             &mut ctrl as *mut _,
-            sys::uvc_frame_format::UVC_FRAME_FORMAT_H264,
+            format
+                .uvc_frame_format()
+                .unwrap_or(sys::uvc_frame_format::UVC_FRAME_FORMAT_ANY),
             width,
             height,
             fps,
@@ -191,12 +295,104 @@ impl UvcDeviceHandleWrapper {
             sys::uvc_error::UVC_SUCCESS => {}
             err => return Err(err),
         }
+        if format.uvc_frame_format().is_none() {
+            // libuvc settled on whichever format first has the size, so the interface it
+            // filled in is kept and the format, frame and interval are probed again
+            self.select_format(&mut ctrl, format, width, height, fps)?;
+        }
         let (handle, state) = UvcStreamHandleWrapper::new(self.clone(), cb, init, &mut ctrl)?;
         self.streams.lock().unwrap().push(state);
         Ok(UvcStreamHandle::new(handle))
     }
+
+    unsafe fn select_format(
+        &self,
+        ctrl: &mut uvc_stream_ctrl_t,
+        format: FrameFormat,
+        width: i32,
+        height: i32,
+        fps: i32,
+    ) -> Result<(), sys::uvc_error> {
+        let mut format_ptr = sys::uvc_get_format_descs(self.handle.as_ptr());
+        while let Some(format_desc) = format_ptr.as_ref() {
+            if FrameFormat::from_format_desc(format_desc) == Some(format) {
+                let mut frame = format_desc.frame_descs as *const sys::uvc_frame_desc;
+                while let Some(frame_desc) = frame.as_ref() {
+                    if (frame_desc.wWidth as i32, frame_desc.wHeight as i32) == (width, height) {
+                        let interval = Self::frame_intervals(frame_desc)
+                            .into_iter()
+                            .find(|&interval| (10_000_000 / interval) as i32 == fps);
+                        if let Some(interval) = interval {
+                            ctrl.bmHint = 1;
+                            ctrl.bFormatIndex = format_desc.bFormatIndex;
+                            ctrl.bFrameIndex = frame_desc.bFrameIndex;
+                            ctrl.dwFrameInterval = interval;
+                            return match sys::uvc_probe_stream_ctrl(
+                                self.handle.as_ptr(),
+                                ctrl as *mut _,
+                            ) {
+                                sys::uvc_error::UVC_SUCCESS => Ok(()),
+                                err => Err(err),
+                            };
+                        }
+                    }
+                    frame = frame_desc.next;
+                }
+            }
+            format_ptr = format_desc.next;
+        }
+        Err(sys::uvc_error::UVC_ERROR_INVALID_MODE)
+    }
+
+    unsafe fn frame_descriptors(&self) -> Vec<FrameDescriptor> {
+        let mut descriptors = Vec::new();
+        let mut format_ptr = sys::uvc_get_format_descs(self.handle.as_ptr());
+        while let Some(format_desc) = format_ptr.as_ref() {
+            if let Some(format) = FrameFormat::from_format_desc(format_desc) {
+                let mut frame = format_desc.frame_descs as *const sys::uvc_frame_desc;
+                while let Some(frame_desc) = frame.as_ref() {
+                    descriptors.push(FrameDescriptor {
+                        format,
+                        width: frame_desc.wWidth as u32,
+                        height: frame_desc.wHeight as u32,
+                        intervals: Self::frame_intervals(frame_desc),
+                    });
+                    frame = frame_desc.next;
+                }
+            }
+            format_ptr = format_desc.next;
+        }
+        descriptors
+    }
+
+    unsafe fn frame_intervals(frame_desc: &sys::uvc_frame_desc) -> Vec<u32> {
+        if frame_desc.intervals.is_null() {
+            // a continuous range, which is summarized rather than enumerated step by step
+            let mut intervals = vec![
+                frame_desc.dwMinFrameInterval,
+                frame_desc.dwDefaultFrameInterval,
+                frame_desc.dwMaxFrameInterval,
+            ];
+            intervals.sort_unstable();
+            intervals.dedup();
+            intervals.retain(|&interval| interval != 0);
+            return intervals;
+        }
+        let mut intervals = Vec::new();
+        let mut interval = frame_desc.intervals as *const u32;
+        // zero terminated
+        while *interval != 0 {
+            intervals.push(*interval);
+            interval = interval.add(1);
+        }
+        intervals
+    }
 }
 
+unsafe impl Send for UvcDeviceHandleWrapper {}
+
+unsafe impl Sync for UvcDeviceHandleWrapper {}
+
 impl Drop for UvcDeviceHandleWrapper {
     fn drop(&mut self) {
         unsafe {
@@ -217,6 +413,10 @@ impl UvcDeviceHandle {
         }
     }
 
+    pub fn frame_descriptors(&self) -> Vec<FrameDescriptor> {
+        unsafe { self.inner.frame_descriptors() }
+    }
+
     pub fn start_streaming<F, T>(
         self,
         params: StreamParameters,
@@ -229,6 +429,7 @@ impl UvcDeviceHandle {
     {
         unsafe {
             self.inner.start_streaming(
+                params.format,
                 params
                     .width
                     .try_into()
@@ -298,7 +499,6 @@ impl UvcStreamHandle {
         Self { _inner: inner }
     }
 
-    #[allow(unused)]
     pub fn frame_interval(&self) -> Duration {
         Duration::from_nanos(self._inner.ctrl.dwFrameInterval as u64 * 100)
     }
@@ -333,7 +533,6 @@ impl UvcFrame {
         }
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn data(&self) -> &[u8] {
         unsafe {
@@ -344,31 +543,26 @@ impl UvcFrame {
         }
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn width(&self) -> usize {
         unsafe { self.frame.as_ref().width as usize }
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn height(&self) -> usize {
         unsafe { self.frame.as_ref().height as usize }
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn step(&self) -> usize {
         unsafe { self.frame.as_ref().step as usize }
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn sequence(&self) -> usize {
         unsafe { self.frame.as_ref().sequence as usize }
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn start_timestamp(&self) -> Duration {
         unsafe {
@@ -379,7 +573,6 @@ impl UvcFrame {
         }
     }
 
-    #[allow(unused)]
     pub fn finish_timestamp(&self) -> Duration {
         unsafe {
             Duration::new(
@@ -1,5 +1,13 @@
+mod backend;
+mod h264;
+mod h265;
 mod imp;
 mod libuvc_theta;
+mod provider;
+#[cfg(test)]
+mod simulator;
+#[cfg(test)]
+mod tests;
 use std::error::Error;
 
 use gstreamer::{glib, prelude::StaticType};
@@ -8,11 +16,37 @@ glib::wrapper! {
     pub struct ThetaUvc(ObjectSubclass<imp::ThetaUvc>) @extends gstreamer_base::PushSrc, gstreamer_base::BaseSrc, gstreamer::Element, gstreamer::Object;
 }
 
+glib::wrapper! {
+    pub struct ThetaDeviceProvider(ObjectSubclass<provider::ThetaDeviceProvider>) @extends gstreamer::DeviceProvider, gstreamer::Object;
+}
+
+glib::wrapper! {
+    pub struct ThetaDevice(ObjectSubclass<provider::ThetaDevice>) @extends gstreamer::Device, gstreamer::Object;
+}
+
+#[cfg(test)]
+impl ThetaUvc {
+    /// A thetauvcsrc that finds its camera through `backend` instead of libuvc.
+    fn with_backend(backend: std::sync::Arc<dyn backend::Backend>) -> Self {
+        use gstreamer::subclass::prelude::*;
+
+        let element = glib::Object::new::<Self>(&[]);
+        element.imp().set_backend(backend);
+        element
+    }
+}
+
 pub fn register(plugin: &gstreamer::Plugin) -> Result<(), impl Error> {
     gstreamer::Element::register(
         Some(plugin),
         "thetauvcsrc",
         gstreamer::Rank::None,
         ThetaUvc::static_type(),
+    )?;
+    gstreamer::DeviceProvider::register(
+        Some(plugin),
+        "thetadeviceprovider",
+        gstreamer::Rank::Primary,
+        ThetaDeviceProvider::static_type(),
     )
 }
@@ -0,0 +1,275 @@
+use std::sync::Mutex;
+
+use gstreamer::{glib, prelude::*, subclass::prelude::*};
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::libusb_hotplug::HotplugMonitor;
+
+use super::{
+    imp::{Product, ThetaUvc, USBVID_RICOH},
+    libuvc_theta::{UvcContext, UvcDevice},
+};
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "thetadeviceprovider",
+        gstreamer::DebugColorFlags::empty(),
+        Some("Ricoh Theta Device Provider"),
+    )
+});
+
+/// Bus number and address, which identify a device for as long as it stays plugged in.
+type DeviceKey = (u8, u8);
+
+#[derive(Default)]
+struct State {
+    devices: Vec<(DeviceKey, gstreamer::Device)>,
+    monitor: Option<HotplugMonitor>,
+}
+
+/// Lists Ricoh Theta cameras, reporting them as they are plugged in and out where libusb
+/// supports hotplug.
+#[derive(Default)]
+pub struct ThetaDeviceProvider {
+    state: Mutex<State>,
+}
+
+impl ThetaDeviceProvider {
+    fn scan(&self) -> Vec<(DeviceKey, gstreamer::Device)> {
+        let context = match UvcContext::new() {
+            Ok(context) => context,
+            Err(err) => {
+                gstreamer::warning!(
+                    CAT,
+                    imp: self,
+                    "Could not create a libuvc context. Error: {:#?}",
+                    err
+                );
+                return Vec::new();
+            }
+        };
+        let devices = match context.find_devices(Some(USBVID_RICOH as i32), None, None) {
+            Ok(devices) => devices,
+            Err(err) => {
+                // libuvc reports finding nothing as an error too
+                gstreamer::debug!(CAT, imp: self, "No devices found. Error: {:#?}", err);
+                return Vec::new();
+            }
+        };
+        devices
+            .into_iter()
+            .filter_map(|device| match device.descriptor() {
+                Ok(descriptor) => Some((
+                    self.caps(&device, Product::from_product_id(descriptor.product_id)),
+                    descriptor,
+                )),
+                Err(err) => {
+                    gstreamer::debug!(
+                        CAT,
+                        imp: self,
+                        "Could not read device descriptor. Error: {:#?}",
+                        err
+                    );
+                    None
+                }
+            })
+            .map(|(caps, descriptor)| {
+                let product = Product::from_product_id(descriptor.product_id);
+                let product_name = descriptor
+                    .product
+                    .clone()
+                    .unwrap_or_else(|| "Ricoh Theta".to_owned());
+                let display_name = match descriptor.serial_number.as_ref() {
+                    Some(serial_number) => format!("{} {}", product_name, serial_number),
+                    None => product_name.clone(),
+                };
+                let properties = gstreamer::Structure::builder("theta-device")
+                    .field("device.api", "uvc")
+                    .field("device.bus", "usb")
+                    .field("device.bus-number", descriptor.bus_number as u32)
+                    .field("device.address", descriptor.device_address as u32)
+                    .field("device.vendor.id", descriptor.vendor_id as u32)
+                    .field("device.product.id", descriptor.product_id as u32)
+                    .field("device.product.name", product_name)
+                    .field(
+                        "device.serial",
+                        descriptor.serial_number.clone().unwrap_or_default(),
+                    )
+                    .build();
+                let device: super::ThetaDevice = glib::Object::builder()
+                    .property("display-name", display_name)
+                    .property("device-class", "Video/Source")
+                    .property("caps", &caps)
+                    .property("properties", &properties)
+                    .build();
+                device
+                    .imp()
+                    .info
+                    .set(DeviceInfo {
+                        serial_number: descriptor.serial_number,
+                        product,
+                    })
+                    .unwrap();
+                (
+                    (descriptor.bus_number, descriptor.device_address),
+                    device.upcast(),
+                )
+            })
+            .collect()
+    }
+
+    /// What `device` says it can stream. A device that cannot be opened, usually because it is
+    /// streaming elsewhere, is offered the presets of `product` instead.
+    fn caps(&self, device: &UvcDevice, product: Product) -> gstreamer::Caps {
+        let descriptors = device
+            .open()
+            .map(|handle| handle.frame_descriptors())
+            .unwrap_or_else(|err| {
+                gstreamer::debug!(
+                    CAT,
+                    imp: self,
+                    "Could not open device. Error: {:#?}",
+                    err
+                );
+                Vec::new()
+            });
+        if descriptors.is_empty() {
+            ThetaUvc::preset_caps(product)
+        } else {
+            ThetaUvc::descriptor_caps(&descriptors)
+        }
+    }
+
+    /// Reports the devices that came and went since the last scan.
+    fn rescan(&self) {
+        let found = self.scan();
+        let (removed, added) = {
+            let mut state = self.state.lock().unwrap();
+            let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut state.devices)
+                .into_iter()
+                .partition(|(key, _)| found.iter().any(|(found, _)| found == key));
+            let added: Vec<_> = found
+                .into_iter()
+                .filter(|(key, _)| !kept.iter().any(|(kept, _)| kept == key))
+                .collect();
+            state.devices = kept;
+            state.devices.extend(added.iter().cloned());
+            (removed, added)
+        };
+
+        let obj = self.instance();
+        for (_, device) in removed {
+            gstreamer::info!(CAT, imp: self, "Removed {}", device.display_name());
+            obj.device_remove(&device);
+        }
+        for (_, device) in added {
+            gstreamer::info!(CAT, imp: self, "Added {}", device.display_name());
+            obj.device_add(&device);
+        }
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for ThetaDeviceProvider {
+    const NAME: &'static str = "thetadeviceprovider";
+
+    type Type = super::ThetaDeviceProvider;
+
+    type ParentType = gstreamer::DeviceProvider;
+}
+
+impl ObjectImpl for ThetaDeviceProvider {}
+
+impl GstObjectImpl for ThetaDeviceProvider {}
+
+impl DeviceProviderImpl for ThetaDeviceProvider {
+    fn metadata() -> Option<&'static gstreamer::subclass::DeviceProviderMetadata> {
+        static METADATA: Lazy<gstreamer::subclass::DeviceProviderMetadata> = Lazy::new(|| {
+            gstreamer::subclass::DeviceProviderMetadata::new(
+                "Ricoh Theta Device Provider",
+                "Source/Video",
+                "Lists Ricoh Theta cameras",
+                "William Zhang <wtzhang23@gmail.com>",
+            )
+        });
+
+        Some(&*METADATA)
+    }
+
+    fn probe(&self) -> Vec<gstreamer::Device> {
+        self.scan().into_iter().map(|(_, device)| device).collect()
+    }
+
+    fn start(&self) -> Result<(), gstreamer::LoggableError> {
+        self.rescan();
+
+        let provider = self.instance().downgrade();
+        let monitor = HotplugMonitor::start(USBVID_RICOH, move || {
+            if let Some(provider) = provider.upgrade() {
+                provider.imp().rescan();
+            }
+        });
+        if monitor.is_none() {
+            gstreamer::info!(
+                CAT,
+                imp: self,
+                "Hotplug is not supported, only listing devices present at start"
+            );
+        }
+        self.state.lock().unwrap().monitor = monitor;
+        Ok(())
+    }
+
+    fn stop(&self) {
+        // the monitor thread rescans under the state lock, so it is joined without holding it
+        let monitor = self.state.lock().unwrap().monitor.take();
+        if let Some(monitor) = monitor {
+            monitor.stop();
+        }
+        self.state.lock().unwrap().devices.clear();
+    }
+}
+
+struct DeviceInfo {
+    serial_number: Option<String>,
+    product: Product,
+}
+
+/// A Ricoh Theta camera, which creates a thetauvcsrc opening it by serial number.
+#[derive(Default)]
+pub struct ThetaDevice {
+    info: OnceCell<DeviceInfo>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for ThetaDevice {
+    const NAME: &'static str = "thetadevice";
+
+    type Type = super::ThetaDevice;
+
+    type ParentType = gstreamer::Device;
+}
+
+impl ObjectImpl for ThetaDevice {}
+
+impl GstObjectImpl for ThetaDevice {}
+
+impl DeviceImpl for ThetaDevice {
+    fn create_element(
+        &self,
+        name: Option<&str>,
+    ) -> Result<gstreamer::Element, gstreamer::LoggableError> {
+        let info = self.info.get().unwrap();
+        let mut builder =
+            gstreamer::ElementFactory::make("thetauvcsrc").property("product", info.product);
+        if let Some(serial_number) = info.serial_number.as_ref() {
+            builder = builder.property("serial-number", serial_number);
+        }
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        builder
+            .build()
+            .map_err(|err| gstreamer::loggable_error!(CAT, "Could not create thetauvcsrc: {}", err))
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use super::{
+    backend::{monotonic_time, Backend, Device, DeviceHandle, Frame, FrameCallback, StreamHandle},
+    h264::access_units,
+    imp::{USBPID_THETAV_UVC, USBVID_RICOH},
+    libuvc_theta::{FrameDescriptor, FrameFormat, StreamParameters, UvcError},
+};
+
+/// The serial number the simulated camera reports.
+pub const SIMULATED_SERIAL_NUMBER: &str = "SIMULATED";
+
+/// Stands in for libuvc with a single camera that replays an H.264 elementary stream, one access
+/// unit per frame, in a loop. Clones share the camera.
+#[derive(Clone)]
+pub struct Simulator {
+    access_units: Arc<Vec<Vec<u8>>>,
+    stall_after: Option<usize>,
+    /// Sequence numbers of the frames that never make it.
+    lost: Range<usize>,
+    product_id: u16,
+    frame_descriptors: Vec<FrameDescriptor>,
+    usb: Arc<Mutex<Usb>>,
+}
+
+/// Whether the simulated camera is on the bus, and who looked for it.
+#[derive(Default)]
+struct Usb {
+    /// Drops the camera off the bus once this many frames were streamed, for that long.
+    unplug: Option<(usize, Duration)>,
+    unplugged_until: Option<Instant>,
+    lookups: Vec<Option<String>>,
+}
+
+impl Usb {
+    fn present(&self) -> bool {
+        self.unplugged_until
+            .map_or(true, |until| Instant::now() >= until)
+    }
+}
+
+impl Simulator {
+    pub fn new(stream: &[u8]) -> Self {
+        Self {
+            access_units: Arc::new(access_units(stream)),
+            stall_after: None,
+            lost: 0..0,
+            product_id: USBPID_THETAV_UVC,
+            frame_descriptors: Vec::new(),
+            usb: Default::default(),
+        }
+    }
+
+    /// Replays the H.264 elementary stream in the file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::new(&std::fs::read(path)?))
+    }
+
+    /// Stops delivering frames after `frames` of them while keeping the stream open, like a
+    /// camera that stalled.
+    pub fn stall_after(mut self, frames: usize) -> Self {
+        self.stall_after = Some(frames);
+        self
+    }
+
+    /// Loses `frames` frames from the one with sequence number `from` on, like libuvc on a busy
+    /// bus. The time they would have taken still passes.
+    pub fn lose(mut self, from: usize, frames: usize) -> Self {
+        self.lost = from..from + frames;
+        self
+    }
+
+    /// Passes for the product with `product_id` instead of a Theta V.
+    pub fn product_id(mut self, product_id: u16) -> Self {
+        self.product_id = product_id;
+        self
+    }
+
+    /// Offers `frame_descriptors` once opened instead of streaming whatever is asked for.
+    pub fn describing(mut self, frame_descriptors: Vec<FrameDescriptor>) -> Self {
+        self.frame_descriptors = frame_descriptors;
+        self
+    }
+
+    /// Stops delivering frames after `frames` of them and drops off the bus for `duration`, like a
+    /// camera that was unplugged and plugged back in. Only the first stream is cut off.
+    pub fn unplug_after(self, frames: usize, duration: Duration) -> Self {
+        self.usb.lock().unwrap().unplug = Some((frames, duration));
+        self
+    }
+
+    /// The serial numbers the camera was looked for by, `None` for any camera.
+    pub fn lookups(&self) -> Vec<Option<String>> {
+        self.usb.lock().unwrap().lookups.clone()
+    }
+}
+
+impl Backend for Simulator {
+    fn find_devices(
+        &self,
+        vid: Option<i32>,
+        pid: Option<i32>,
+        serial_number: Option<&str>,
+    ) -> Result<Vec<Arc<dyn Device>>, UvcError> {
+        let mut usb = self.usb.lock().unwrap();
+        usb.lookups.push(serial_number.map(|sn| sn.to_owned()));
+        if !usb.present()
+            || vid.map_or(false, |vid| vid != USBVID_RICOH as i32)
+            || pid.map_or(false, |pid| pid != self.product_id as i32)
+            || serial_number.map_or(false, |serial_number| {
+                serial_number != SIMULATED_SERIAL_NUMBER
+            })
+        {
+            // libuvc reports finding nothing the same way
+            return Err(UvcError::UVC_ERROR_NO_DEVICE);
+        }
+        Ok(vec![Arc::new(SimulatedDevice(self.clone()))])
+    }
+}
+
+struct SimulatedDevice(Simulator);
+
+impl Device for SimulatedDevice {
+    fn product_id(&self) -> Option<u16> {
+        Some(self.0.product_id)
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        Some(SIMULATED_SERIAL_NUMBER.to_owned())
+    }
+
+    fn open(&self) -> Result<Box<dyn DeviceHandle>, UvcError> {
+        if !self.0.usb.lock().unwrap().present() {
+            return Err(UvcError::UVC_ERROR_NO_DEVICE);
+        }
+        if self.0.access_units.is_empty() {
+            return Err(UvcError::UVC_ERROR_NOT_SUPPORTED);
+        }
+        Ok(Box::new(SimulatedDeviceHandle(self.0.clone())))
+    }
+}
+
+struct SimulatedDeviceHandle(Simulator);
+
+impl DeviceHandle for SimulatedDeviceHandle {
+    fn frame_descriptors(&self) -> Vec<FrameDescriptor> {
+        // unless told otherwise, the elementary stream does not say, so whatever is asked for is
+        // streamed
+        self.0.frame_descriptors.clone()
+    }
+
+    fn start_streaming(
+        self: Box<Self>,
+        params: StreamParameters,
+        mut callback: FrameCallback,
+    ) -> Result<Box<dyn StreamHandle>, UvcError> {
+        if params.format != FrameFormat::H264 || params.fps == 0 {
+            return Err(UvcError::UVC_ERROR_INVALID_MODE);
+        }
+        let simulator = self.0;
+        let unplug = simulator.usb.lock().unwrap().unplug.take();
+        let frame_interval = Duration::from_secs(1) / params.fps as u32;
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = std::thread::Builder::new()
+            .name("theta-simulator".to_owned())
+            .spawn({
+                let running = running.clone();
+                move || {
+                    let interval = FrameInterval(frame_interval);
+                    let mut next = Instant::now();
+                    let frames = unplug
+                        .map(|(frames, _)| frames)
+                        .or(simulator.stall_after)
+                        .unwrap_or(usize::MAX);
+                    let access_units = simulator
+                        .access_units
+                        .iter()
+                        .cycle()
+                        .take(frames)
+                        .enumerate();
+                    for (sequence, access_unit) in access_units {
+                        if !running.load(Ordering::Acquire) {
+                            break;
+                        }
+                        next += frame_interval;
+                        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+                        if simulator.lost.contains(&sequence) {
+                            continue;
+                        }
+                        let frame = SimulatedFrame {
+                            data: access_unit,
+                            width: params.width,
+                            height: params.height,
+                            sequence,
+                            finish: monotonic_time(),
+                        };
+                        callback(&frame, &interval);
+                    }
+                    if let (Some((_, duration)), true) = (unplug, running.load(Ordering::Acquire)) {
+                        simulator.usb.lock().unwrap().unplugged_until =
+                            Some(Instant::now() + duration);
+                    }
+                    while running.load(Ordering::Acquire) {
+                        std::thread::sleep(frame_interval);
+                    }
+                }
+            })
+            .map_err(|_| UvcError::UVC_ERROR_OTHER)?;
+        Ok(Box::new(SimulatedStream {
+            running,
+            handle: Some(handle),
+            frame_interval,
+        }))
+    }
+}
+
+struct SimulatedStream {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+    frame_interval: Duration,
+}
+
+impl StreamHandle for SimulatedStream {
+    fn frame_interval(&self) -> Duration {
+        self.frame_interval
+    }
+}
+
+impl Drop for SimulatedStream {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// What the streaming thread hands to callbacks in place of the stream it does not own.
+struct FrameInterval(Duration);
+
+impl StreamHandle for FrameInterval {
+    fn frame_interval(&self) -> Duration {
+        self.0
+    }
+}
+
+struct SimulatedFrame<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    sequence: usize,
+    finish: Duration,
+}
+
+impl Frame for SimulatedFrame<'_> {
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn sequence(&self) -> usize {
+        self.sequence
+    }
+
+    fn finish_timestamp(&self) -> Duration {
+        self.finish
+    }
+}
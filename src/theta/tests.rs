@@ -0,0 +1,341 @@
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+use gstreamer::prelude::*;
+use gstreamer_check::Harness;
+
+use super::{
+    h265,
+    libuvc_theta::{FrameDescriptor, FrameFormat},
+    simulator::{Simulator, SIMULATED_SERIAL_NUMBER},
+    ThetaUvc,
+};
+
+const SPS: &[u8] = &[0, 0, 0, 1, 0x67, 0x42, 0xc0, 0x1e, 0xda];
+const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80];
+const IDR_SLICE: &[u8] = &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00];
+const SLICE: &[u8] = &[0, 0, 0, 1, 0x41, 0x9a, 0x02, 0x00];
+
+/// A gray 1920x960 Baseline stream laid out like the H.264 a Theta streams: parameter sets ahead
+/// of each IDR frame and two groups of [`FIXTURE_GOP`] frames. Made by `generate.py` next to it.
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/theta/gray-1920x960.h264"
+);
+/// Frames per group of pictures in [`FIXTURE`].
+const FIXTURE_GOP: u64 = 10;
+
+/// A USB product ID no Theta uses.
+const UNKNOWN_PRODUCT_ID: u16 = 0x1234;
+
+/// Frames per group of pictures in [`stream`].
+const GOP: u64 = 5;
+/// Sequence number of the first IDR frame in [`stream`].
+const FIRST_IDR: u64 = 2;
+
+fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| gstreamer::init().unwrap());
+}
+
+/// An H.264 stream that joins a group of pictures halfway: two delta frames, then an IDR frame
+/// with its parameter sets followed by two more delta frames.
+fn stream() -> Vec<u8> {
+    [SLICE, SLICE, SPS, PPS, IDR_SLICE, SLICE, SLICE].concat()
+}
+
+fn simulated(simulator: Simulator, properties: &[(&str, &str)]) -> (ThetaUvc, Harness) {
+    init();
+    let element = ThetaUvc::with_backend(Arc::new(simulator));
+    for (name, value) in properties {
+        element.set_property_from_str(name, value);
+    }
+    let harness = Harness::with_element(&element, None, Some("src"));
+    (element, harness)
+}
+
+/// The H.264 presets a Theta describes, at 29.97 fps.
+fn preset_descriptors() -> Vec<FrameDescriptor> {
+    [(3840, 1920), (1920, 960)]
+        .into_iter()
+        .map(|(width, height)| FrameDescriptor {
+            format: FrameFormat::H264,
+            width,
+            height,
+            intervals: vec![333_667],
+        })
+        .collect()
+}
+
+fn is_keyframe(buffer: &gstreamer::Buffer) -> bool {
+    !buffer.flags().contains(gstreamer::BufferFlags::DELTA_UNIT)
+}
+
+#[test]
+fn negotiates_default_preset() {
+    let (_element, mut harness) = simulated(Simulator::from_file(FIXTURE).unwrap(), &[]);
+    harness.play();
+    harness.pull().unwrap();
+
+    let caps = harness.sinkpad().unwrap().current_caps().unwrap();
+    let structure = caps.structure(0).unwrap();
+    assert_eq!(structure.name(), "video/x-h264");
+    assert_eq!(structure.get::<i32>("width").unwrap(), 1920);
+    assert_eq!(structure.get::<i32>("height").unwrap(), 960);
+    assert_eq!(structure.get::<&str>("alignment").unwrap(), "au");
+}
+
+#[test]
+fn negotiates_nal_alignment() {
+    let (_element, mut harness) = simulated(Simulator::from_file(FIXTURE).unwrap(), &[]);
+    harness.set_sink_caps_str("video/x-h264,alignment=nal");
+    harness.play();
+
+    // the IDR frame comes one NAL unit per buffer, parameter sets first
+    let nal_types = (0..3)
+        .map(|_| {
+            let buffer = harness.pull().unwrap();
+            let map = buffer.map_readable().unwrap();
+            assert_eq!(&map[..4], &[0, 0, 0, 1]);
+            map[4] & 0x1f
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(nal_types, [7, 8, 5]);
+    let caps = harness.sinkpad().unwrap().current_caps().unwrap();
+    let structure = caps.structure(0).unwrap();
+    assert_eq!(structure.get::<&str>("alignment").unwrap(), "nal");
+}
+
+#[test]
+fn rejects_preset_unsupported_by_product() {
+    let (element, mut harness) = simulated(
+        Simulator::new(&stream())
+            .product_id(UNKNOWN_PRODUCT_ID)
+            .describing(preset_descriptors()),
+        &[("product-id", &UNKNOWN_PRODUCT_ID.to_string())],
+    );
+    let bus = gstreamer::Bus::new();
+    element.set_bus(Some(&bus));
+    // the camera offers 4K, but an unknown product is only trusted with 2K
+    harness.set_sink_caps_str("video/x-h264,width=3840,height=1920");
+    harness.play();
+
+    let message = bus
+        .timed_pop_filtered(
+            gstreamer::ClockTime::from_seconds(5),
+            &[gstreamer::MessageType::Error],
+        )
+        .unwrap();
+    let gstreamer::MessageView::Error(err) = message.view() else {
+        unreachable!();
+    };
+    assert!(err.error().to_string().contains("not supported"));
+}
+
+#[test]
+fn finds_unknown_product_by_product_id() {
+    let (element, mut harness) = simulated(
+        Simulator::new(&stream())
+            .product_id(UNKNOWN_PRODUCT_ID)
+            .describing(preset_descriptors()),
+        &[("product", "v")],
+    );
+    element.set_bus(Some(&gstreamer::Bus::new()));
+    // looked for by the product ID of the Theta V
+    assert!(element.set_state(gstreamer::State::Ready).is_err());
+
+    element.set_property("product-id", UNKNOWN_PRODUCT_ID as u32);
+    element.set_state(gstreamer::State::Ready).unwrap();
+    // the product set decides the presets of a camera that does not identify as one
+    harness.set_sink_caps_str("video/x-h264,width=3840,height=1920");
+    harness.play();
+    harness.pull().unwrap();
+
+    let caps = harness.sinkpad().unwrap().current_caps().unwrap();
+    let structure = caps.structure(0).unwrap();
+    assert_eq!(structure.get::<i32>("width").unwrap(), 3840);
+}
+
+#[test]
+fn starts_at_idr_frame() {
+    let (_element, mut harness) = simulated(Simulator::new(&stream()), &[]);
+    harness.play();
+
+    let buffer = harness.pull().unwrap();
+    assert_eq!(buffer.offset(), FIRST_IDR);
+    assert!(is_keyframe(&buffer));
+    let buffer = harness.pull().unwrap();
+    assert_eq!(buffer.offset(), FIRST_IDR + 1);
+    assert!(!is_keyframe(&buffer));
+}
+
+#[test]
+fn flags_keyframes_of_fixture_stream() {
+    let (_element, mut harness) =
+        simulated(Simulator::from_file(FIXTURE).unwrap(), &[("leaky", "no")]);
+    harness.play();
+
+    // across the loop back to the start of the file as well
+    for offset in 0..3 * FIXTURE_GOP {
+        let buffer = harness.pull().unwrap();
+        assert_eq!(buffer.offset(), offset);
+        assert_eq!(is_keyframe(&buffer), offset % FIXTURE_GOP == 0);
+    }
+}
+
+#[test]
+fn hands_off_every_frame_without_leaking() {
+    let (element, mut harness) = simulated(
+        Simulator::new(&stream()),
+        &[("leaky", "no"), ("max-buffers", "1")],
+    );
+    harness.play();
+
+    for offset in FIRST_IDR..FIRST_IDR + 2 * GOP {
+        let buffer = harness.pull().unwrap();
+        assert_eq!(buffer.offset(), offset);
+        assert!(!buffer.flags().contains(gstreamer::BufferFlags::DISCONT));
+    }
+    let stats = element.property::<gstreamer::Structure>("stats");
+    assert_eq!(stats.get::<u64>("dropped").unwrap(), 0);
+}
+
+#[test]
+fn drops_to_next_keyframe_when_full() {
+    let (element, mut harness) = simulated(
+        Simulator::new(&stream()),
+        &[("leaky", "downstream"), ("max-buffers", "1")],
+    );
+    harness.play();
+    harness.pull().unwrap();
+
+    // fall behind by more than a group of pictures
+    std::thread::sleep(Duration::from_millis(500));
+    let buffer = harness.pull().unwrap();
+    assert!(is_keyframe(&buffer));
+    assert_eq!(buffer.offset() % GOP, FIRST_IDR);
+    assert!(buffer.flags().contains(gstreamer::BufferFlags::DISCONT));
+    let stats = element.property::<gstreamer::Structure>("stats");
+    assert!(stats.get::<u64>("dropped").unwrap() > 0);
+}
+
+#[test]
+fn errors_when_camera_stalls() {
+    let (element, mut harness) = simulated(
+        Simulator::new(&stream()).stall_after(FIRST_IDR as usize + 1),
+        &[("timeout", "200"), ("reconnect", "never")],
+    );
+    let bus = gstreamer::Bus::new();
+    element.set_bus(Some(&bus));
+    harness.play();
+    harness.pull().unwrap();
+
+    let message = bus
+        .timed_pop_filtered(
+            gstreamer::ClockTime::from_seconds(5),
+            &[gstreamer::MessageType::Error],
+        )
+        .unwrap();
+    let gstreamer::MessageView::Error(err) = message.view() else {
+        unreachable!();
+    };
+    assert!(err.error().to_string().contains("stalled"));
+}
+
+#[test]
+fn reopens_replugged_camera_by_serial_number() {
+    let simulator =
+        Simulator::new(&stream()).unplug_after(FIRST_IDR as usize + 2, Duration::from_millis(300));
+    let (element, mut harness) = simulated(
+        simulator.clone(),
+        &[("timeout", "200"), ("reconnect", "always")],
+    );
+    let lost = Arc::new(Mutex::new(Vec::new()));
+    element.connect("device-lost", false, {
+        let lost = lost.clone();
+        move |args| {
+            lost.lock().unwrap().push(args[1].get::<String>().unwrap());
+            None
+        }
+    });
+    harness.play();
+
+    for offset in FIRST_IDR..FIRST_IDR + 2 {
+        assert_eq!(harness.pull().unwrap().offset(), offset);
+    }
+    // the stream starts over, so it resumes at its first IDR frame
+    let buffer = harness.pull().unwrap();
+    assert_eq!(buffer.offset(), FIRST_IDR);
+    assert!(is_keyframe(&buffer));
+    assert!(buffer.flags().contains(gstreamer::BufferFlags::DISCONT));
+
+    assert_eq!(*lost.lock().unwrap(), [SIMULATED_SERIAL_NUMBER]);
+    // any camera is looked for on start, the lost one after that
+    let lookups = simulator.lookups();
+    assert!(lookups.len() > 1);
+    assert_eq!(lookups[0], None);
+    assert!(lookups[1..]
+        .iter()
+        .all(|lookup| lookup.as_deref() == Some(SIMULATED_SERIAL_NUMBER)));
+}
+
+#[test]
+fn reports_frames_lost_by_camera() {
+    const LOST_FROM: u64 = FIRST_IDR + 2;
+    const LOST: u64 = 2;
+
+    let (element, mut harness) = simulated(
+        Simulator::new(&stream()).lose(LOST_FROM as usize, LOST as usize),
+        &[("leaky", "no")],
+    );
+    let bus = gstreamer::Bus::new();
+    element.set_bus(Some(&bus));
+    harness.play();
+
+    harness.pull().unwrap();
+    let before = harness.pull().unwrap();
+    assert_eq!(before.offset(), LOST_FROM - 1);
+    let after = harness.pull().unwrap();
+    assert_eq!(after.offset(), LOST_FROM + LOST);
+    assert!(after.flags().contains(gstreamer::BufferFlags::DISCONT));
+
+    // the gap event covers the time from the end of the frame before up to the frame after
+    let (start, duration) = loop {
+        let event = harness.pull_event().unwrap();
+        if let gstreamer::EventView::Gap(gap) = event.view() {
+            break gap.get();
+        }
+    };
+    assert_eq!(start, before.pts().unwrap() + before.duration().unwrap());
+    assert_eq!(start + duration.unwrap(), after.pts().unwrap());
+
+    let message = bus
+        .timed_pop_filtered(
+            gstreamer::ClockTime::from_seconds(5),
+            &[gstreamer::MessageType::Qos],
+        )
+        .unwrap();
+    let gstreamer::MessageView::Qos(qos) = message.view() else {
+        unreachable!();
+    };
+    let (live, _, _, timestamp, _) = qos.get();
+    assert!(live);
+    assert_eq!(timestamp, after.pts());
+    let (_, dropped) = qos.stats();
+    assert_eq!(dropped.value(), LOST as i64);
+
+    let stats = element.property::<gstreamer::Structure>("stats");
+    assert_eq!(stats.get::<u64>("lost").unwrap(), LOST);
+    assert_eq!(stats.get::<u64>("dropped").unwrap(), 0);
+}
+
+#[test]
+fn flags_h265_irap_pictures() {
+    const TRAIL_R: &[u8] = &[0, 0, 0, 1, 0x02, 0x01, 0xd0];
+    const IDR_W_RADL: &[u8] = &[0, 0, 0, 1, 0x26, 0x01, 0xaf];
+
+    let mut parser = h265::AccessUnitParser::new();
+    assert!(parser.parse(TRAIL_R).is_none());
+    assert!(parser.parse(IDR_W_RADL).unwrap().keyframe);
+    assert!(!parser.parse(TRAIL_R).unwrap().keyframe);
+}
@@ -0,0 +1,130 @@
+#!/usr/bin/env python3
+"""Writes gray-1920x960.h264, a Baseline H.264 elementary stream laid out like what a Theta
+streams over UVC: 1920x960, parameter sets ahead of every IDR frame, two groups of 10 frames.
+
+Every picture is plain gray so the stream stays small: IDR frames are DC predicted 16x16 intra
+macroblocks without residual and P frames skip every macroblock.
+"""
+
+import pathlib
+
+WIDTH_MBS = 1920 // 16
+HEIGHT_MBS = 960 // 16
+GOP = 10
+GOPS = 2
+
+
+class Bits:
+    def __init__(self):
+        self.bits = []
+
+    def u(self, count, value):
+        self.bits += [(value >> shift) & 1 for shift in reversed(range(count))]
+
+    def ue(self, value):
+        value += 1
+        self.u(value.bit_length() * 2 - 1, value)
+
+    def se(self, value):
+        self.ue(2 * value - 1 if value > 0 else -2 * value)
+
+    def rbsp(self):
+        self.bits.append(1)
+        while len(self.bits) % 8:
+            self.bits.append(0)
+        return bytes(
+            int("".join(map(str, self.bits[index : index + 8])), 2)
+            for index in range(0, len(self.bits), 8)
+        )
+
+
+def nal_unit(header, rbsp):
+    payload = bytearray()
+    zeros = 0
+    for byte in rbsp:
+        if zeros >= 2 and byte <= 3:
+            payload.append(3)
+            zeros = 0
+        payload.append(byte)
+        zeros = zeros + 1 if byte == 0 else 0
+    return b"\x00\x00\x00\x01" + bytes([header]) + bytes(payload)
+
+
+def sps():
+    bits = Bits()
+    bits.u(8, 66)  # profile_idc: Baseline
+    bits.u(8, 0xC0)  # constraint_set0_flag, constraint_set1_flag
+    bits.u(8, 40)  # level_idc
+    bits.ue(0)  # seq_parameter_set_id
+    bits.ue(0)  # log2_max_frame_num_minus4
+    bits.ue(2)  # pic_order_cnt_type
+    bits.ue(1)  # max_num_ref_frames
+    bits.u(1, 0)  # gaps_in_frame_num_value_allowed_flag
+    bits.ue(WIDTH_MBS - 1)
+    bits.ue(HEIGHT_MBS - 1)
+    bits.u(1, 1)  # frame_mbs_only_flag
+    bits.u(1, 1)  # direct_8x8_inference_flag
+    bits.u(1, 0)  # frame_cropping_flag
+    bits.u(1, 0)  # vui_parameters_present_flag
+    return nal_unit(0x67, bits.rbsp())
+
+
+def pps():
+    bits = Bits()
+    bits.ue(0)  # pic_parameter_set_id
+    bits.ue(0)  # seq_parameter_set_id
+    bits.u(1, 0)  # entropy_coding_mode_flag
+    bits.u(1, 0)  # bottom_field_pic_order_in_frame_present_flag
+    bits.ue(0)  # num_slice_groups_minus1
+    bits.ue(0)  # num_ref_idx_l0_default_active_minus1
+    bits.ue(0)  # num_ref_idx_l1_default_active_minus1
+    bits.u(1, 0)  # weighted_pred_flag
+    bits.u(2, 0)  # weighted_bipred_idc
+    bits.se(0)  # pic_init_qp_minus26
+    bits.se(0)  # pic_init_qs_minus26
+    bits.se(0)  # chroma_qp_index_offset
+    bits.u(1, 1)  # deblocking_filter_control_present_flag
+    bits.u(1, 0)  # constrained_intra_pred_flag
+    bits.u(1, 0)  # redundant_pic_cnt_present_flag
+    return nal_unit(0x68, bits.rbsp())
+
+
+def idr(idr_pic_id):
+    bits = Bits()
+    bits.ue(0)  # first_mb_in_slice
+    bits.ue(7)  # slice_type: I
+    bits.ue(0)  # pic_parameter_set_id
+    bits.u(4, 0)  # frame_num
+    bits.ue(idr_pic_id)
+    bits.u(1, 0)  # no_output_of_prior_pics_flag
+    bits.u(1, 0)  # long_term_reference_flag
+    bits.se(0)  # slice_qp_delta
+    bits.ue(1)  # disable_deblocking_filter_idc
+    for _ in range(WIDTH_MBS * HEIGHT_MBS):
+        bits.ue(3)  # mb_type: I_16x16_2_0_0, DC prediction without residual
+        bits.ue(0)  # intra_chroma_pred_mode: DC
+        bits.se(0)  # mb_qp_delta
+        bits.u(1, 1)  # coeff_token of the empty Intra16x16DCLevel block
+    return nal_unit(0x65, bits.rbsp())
+
+
+def p(frame_num):
+    bits = Bits()
+    bits.ue(0)  # first_mb_in_slice
+    bits.ue(5)  # slice_type: P
+    bits.ue(0)  # pic_parameter_set_id
+    bits.u(4, frame_num)
+    bits.u(1, 0)  # num_ref_idx_active_override_flag
+    bits.u(1, 0)  # ref_pic_list_modification_flag_l0
+    bits.u(1, 0)  # adaptive_ref_pic_marking_mode_flag
+    bits.se(0)  # slice_qp_delta
+    bits.ue(1)  # disable_deblocking_filter_idc
+    bits.ue(WIDTH_MBS * HEIGHT_MBS)  # mb_skip_run
+    return nal_unit(0x41, bits.rbsp())
+
+
+stream = b"".join(
+    sps() + pps() + idr(gop % 2) + b"".join(p(frame) for frame in range(1, GOP))
+    for gop in range(GOPS)
+)
+pathlib.Path(__file__).with_name("gray-1920x960.h264").write_bytes(stream)